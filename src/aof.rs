@@ -0,0 +1,89 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+/// When appended commands are forced out to the disk, from `appendfsync`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every command, so nothing acknowledged is ever lost.
+    Always,
+    /// Once a second, losing at most the last second on a crash.
+    EverySec,
+    /// Whenever the OS gets round to it.
+    No,
+}
+
+impl FsyncPolicy {
+    pub fn from_config(value: &str) -> FsyncPolicy {
+        match value {
+            "always" => FsyncPolicy::Always,
+            "no" => FsyncPolicy::No,
+            _ => FsyncPolicy::EverySec,
+        }
+    }
+}
+
+/// The append-only file: every command that changed the dataset, in the
+/// order it was applied, as the RESP array a client would have sent.
+pub struct Aof {
+    file: File,
+    fsync: FsyncPolicy,
+}
+
+impl Aof {
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if fsync == FsyncPolicy::EverySec {
+            // syncing through a second handle keeps the lock free meanwhile
+            let sync_file = file.try_clone()?;
+            thread::spawn(move || run_fsync(sync_file));
+        }
+        Ok(Aof { file, fsync })
+    }
+
+    /// Appends a command, already RESP encoded.
+    pub fn append(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.file.write_all(frame)?;
+        if self.fsync == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+fn run_fsync(file: File) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        if let Err(error) = file.sync_data() {
+            eprintln!("can't fsync the AOF: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_append() {
+        let path = std::env::temp_dir().join(format!("aof-test-{}.aof", std::process::id()));
+        let mut aof = Aof::open(&path, FsyncPolicy::Always).unwrap();
+        aof.append(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n")
+            .unwrap();
+        aof.append(b"*2\r\n$3\r\nDEL\r\n$1\r\na\r\n").unwrap();
+        drop(aof);
+
+        // reopening appends rather than truncating
+        let mut aof = Aof::open(&path, FsyncPolicy::No).unwrap();
+        aof.append(b"*2\r\n$4\r\nINCR\r\n$1\r\nn\r\n").unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*2\r\n$3\r\nDEL\r\n$1\r\na\r\n*2\r\n$4\r\nINCR\r\n$1\r\nn\r\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Instant,
+};
+
+use tokio::{sync::Notify, time};
+
+/// Connections blocked in BLPOP or BRPOP, queued per key in the order they
+/// blocked. Only the connection at the front of a key's queue may pop from
+/// it, so the one that has waited longest is served first.
+#[derive(Default)]
+pub struct BlockedClients {
+    waiters: Mutex<Waiters>,
+    /// Signalled when a key someone waits on is pushed to, or a waiter
+    /// leaves and the next in line may get its turn.
+    wakeup: Notify,
+}
+
+#[derive(Default)]
+struct Waiters {
+    /// Connection ids waiting on each key of each database, longest first.
+    queues: HashMap<(usize, String), VecDeque<u64>>,
+    /// Moves on with every signal, so a waiter that checked the lists can
+    /// tell whether something happened before it went to sleep.
+    generation: u64,
+}
+
+impl BlockedClients {
+    /// Queues connection `id` behind anyone already waiting on `keys`, until
+    /// the returned guard is dropped.
+    pub fn block(&self, id: u64, db: usize, keys: &[String]) -> Blocked<'_> {
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            waiters
+                .queues
+                .entry((db, key.clone()))
+                .or_default()
+                .push_back(id);
+        }
+        Blocked {
+            clients: self,
+            id,
+            db,
+            keys: keys.to_vec(),
+        }
+    }
+
+    /// Takes connection `id` out of the queues for `keys`, letting whoever
+    /// was behind it go next.
+    fn unblock(&self, id: u64, db: usize, keys: &[String]) {
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            let queue_key = (db, key.clone());
+            if let Some(queue) = waiters.queues.get_mut(&queue_key) {
+                queue.retain(|waiter| *waiter != id);
+                if queue.is_empty() {
+                    waiters.queues.remove(&queue_key);
+                }
+            }
+        }
+        waiters.generation += 1;
+        self.wakeup.notify_waiters();
+    }
+
+    /// The keys connection `id` is at the front of the queue for, in the
+    /// order given, and the generation to pass to `wait` after trying them.
+    pub fn turn(&self, id: u64, db: usize, keys: &[String]) -> (u64, Vec<String>) {
+        let waiters = self.waiters.lock().unwrap();
+        let keys = keys
+            .iter()
+            .filter(|key| {
+                waiters
+                    .queues
+                    .get(&(db, key.to_string()))
+                    .is_some_and(|queue| queue.front() == Some(&id))
+            })
+            .cloned()
+            .collect();
+        (waiters.generation, keys)
+    }
+
+    /// Wakes whoever waits on `key` after something was pushed to it.
+    pub fn key_pushed(&self, db: usize, key: &str) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if waiters.queues.contains_key(&(db, key.to_string())) {
+            waiters.generation += 1;
+            self.wakeup.notify_waiters();
+        }
+    }
+
+    /// Waits until the generation moves on from `generation`, returning
+    /// false if `deadline` passes first.
+    pub async fn wait(&self, generation: u64, deadline: Option<Instant>) -> bool {
+        loop {
+            // listening before looking, so a signal in between isn't missed
+            let notified = self.wakeup.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.waiters.lock().unwrap().generation != generation {
+                return true;
+            }
+            match deadline {
+                None => notified.await,
+                Some(deadline) => {
+                    if time::timeout_at(deadline.into(), notified).await.is_err() {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A connection's place in the queues, given up when this is dropped. A
+/// wait that's abandoned part way, as when the client hangs up, so doesn't
+/// leave it in front of everyone behind it.
+pub struct Blocked<'a> {
+    clients: &'a BlockedClients,
+    id: u64,
+    db: usize,
+    keys: Vec<String>,
+}
+
+impl Drop for Blocked<'_> {
+    fn drop(&mut self) {
+        self.clients.unblock(self.id, self.db, &self.keys);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn keys(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_turns_go_in_order() {
+        let blocked = BlockedClients::default();
+        let first = blocked.block(1, 0, &keys(&["a"]));
+        let second = blocked.block(2, 0, &keys(&["a", "b"]));
+        let third = blocked.block(3, 1, &keys(&["a"]));
+        assert_eq!(blocked.turn(1, 0, &keys(&["a"])).1, keys(&["a"]));
+        assert_eq!(blocked.turn(2, 0, &keys(&["a", "b"])).1, keys(&["b"]));
+        // databases have queues of their own
+        assert_eq!(blocked.turn(3, 1, &keys(&["a"])).1, keys(&["a"]));
+
+        drop(first);
+        assert_eq!(blocked.turn(2, 0, &keys(&["a", "b"])).1, keys(&["a", "b"]));
+        drop(second);
+        drop(third);
+        assert!(blocked.waiters.lock().unwrap().queues.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let blocked = BlockedClients::default();
+        let _blocked = blocked.block(1, 0, &keys(&["a"]));
+        let (generation, _turn) = blocked.turn(1, 0, &keys(&["a"]));
+        let soon = Some(Instant::now() + Duration::from_millis(20));
+        // pushes to keys nobody waits on don't wake anyone
+        blocked.key_pushed(0, "b");
+        assert!(!blocked.wait(generation, soon).await);
+
+        blocked.key_pushed(0, "a");
+        assert!(blocked.wait(generation, None).await);
+    }
+}
@@ -0,0 +1,156 @@
+/// Every command the server implements, with its arity and group as Redis
+/// reports them in COMMAND and COMMAND DOCS. Calls are checked against the
+/// arity before they are parsed. It counts the command name itself, and a
+/// negative one means at least that many arguments.
+pub const COMMANDS: &[(&str, i64, &str)] = &[
+    ("append", 3, "string"),
+    ("bgsave", -1, "server"),
+    ("bitcount", -2, "bitmap"),
+    ("blpop", -3, "list"),
+    ("brpop", -3, "list"),
+    ("client", -2, "connection"),
+    ("command", -1, "server"),
+    ("config", -2, "server"),
+    ("copy", -3, "generic"),
+    ("dbsize", 1, "server"),
+    ("debug", -2, "server"),
+    ("decr", 2, "string"),
+    ("decrby", 3, "string"),
+    ("del", -2, "generic"),
+    ("discard", 1, "transactions"),
+    ("echo", 2, "connection"),
+    ("exec", 1, "transactions"),
+    ("exists", -2, "generic"),
+    ("expire", -3, "generic"),
+    ("expireat", -3, "generic"),
+    ("expiretime", 2, "generic"),
+    ("flushall", -1, "server"),
+    ("flushdb", -1, "server"),
+    ("get", 2, "string"),
+    ("getbit", 3, "bitmap"),
+    ("getdel", 2, "string"),
+    ("getrange", 4, "string"),
+    ("getset", 3, "string"),
+    ("hdel", -3, "hash"),
+    ("hello", -1, "connection"),
+    ("hexists", 3, "hash"),
+    ("hget", 3, "hash"),
+    ("hgetall", 2, "hash"),
+    ("hincrby", 4, "hash"),
+    ("hlen", 2, "hash"),
+    ("hscan", -3, "hash"),
+    ("hset", -4, "hash"),
+    ("incr", 2, "string"),
+    ("incrby", 3, "string"),
+    ("info", -1, "server"),
+    ("keys", 2, "generic"),
+    ("lindex", 3, "list"),
+    ("llen", 2, "list"),
+    ("lmove", 5, "list"),
+    ("lpop", -2, "list"),
+    ("lpos", -3, "list"),
+    ("lpush", -3, "list"),
+    ("lrange", 4, "list"),
+    ("lrem", 4, "list"),
+    ("lset", 4, "list"),
+    ("ltrim", 4, "list"),
+    ("memory", -2, "server"),
+    ("mget", -2, "string"),
+    ("move", 3, "generic"),
+    ("mset", -3, "string"),
+    ("multi", 1, "transactions"),
+    ("object", -2, "generic"),
+    ("persist", 2, "generic"),
+    ("pexpire", -3, "generic"),
+    ("pexpireat", -3, "generic"),
+    ("pexpiretime", 2, "generic"),
+    ("ping", -1, "connection"),
+    ("psubscribe", -2, "pubsub"),
+    ("psync", -3, "server"),
+    ("pttl", 2, "generic"),
+    ("publish", 3, "pubsub"),
+    ("punsubscribe", -1, "pubsub"),
+    ("randomkey", 1, "generic"),
+    ("rename", 3, "generic"),
+    ("renamenx", 3, "generic"),
+    ("replconf", -1, "server"),
+    ("rpop", -2, "list"),
+    ("rpoplpush", 3, "list"),
+    ("rpush", -3, "list"),
+    ("sadd", -3, "set"),
+    ("save", 1, "server"),
+    ("scan", -2, "generic"),
+    ("scard", 2, "set"),
+    ("sdiff", -2, "set"),
+    ("select", 2, "connection"),
+    ("set", -3, "string"),
+    ("setbit", 4, "bitmap"),
+    ("setnx", 3, "string"),
+    ("setrange", 4, "string"),
+    ("sinter", -2, "set"),
+    ("sismember", 3, "set"),
+    ("smembers", 2, "set"),
+    ("srem", -3, "set"),
+    ("sscan", -3, "set"),
+    ("strlen", 2, "string"),
+    ("subscribe", -2, "pubsub"),
+    ("sunion", -2, "set"),
+    ("touch", -2, "generic"),
+    ("ttl", 2, "generic"),
+    ("type", 2, "generic"),
+    ("unsubscribe", -1, "pubsub"),
+    ("unwatch", 1, "transactions"),
+    ("wait", 3, "generic"),
+    ("watch", -2, "transactions"),
+    ("zadd", -4, "sorted-set"),
+    ("zincrby", 4, "sorted-set"),
+    ("zrange", -4, "sorted-set"),
+    ("zrank", 3, "sorted-set"),
+    ("zrevrank", 3, "sorted-set"),
+    ("zscan", -3, "sorted-set"),
+    ("zscore", 3, "sorted-set"),
+];
+
+/// The arity and group of the command called `name`, in lowercase.
+pub fn lookup(name: &str) -> Option<(i64, &'static str)> {
+    COMMANDS
+        .binary_search_by(|(command, _arity, _group)| command.cmp(&name))
+        .ok()
+        .map(|index| (COMMANDS[index].1, COMMANDS[index].2))
+}
+
+/// Whether a call with `argc` arguments, counting the command name, fits
+/// `arity`.
+pub fn arity_allows(arity: i64, argc: usize) -> bool {
+    let argc = argc as i64;
+    if arity < 0 {
+        argc >= -arity
+    } else {
+        argc == arity
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        // lookups search the table, so it has to stay sorted
+        assert!(COMMANDS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(lookup("get"), Some((2, "string")));
+        assert_eq!(lookup("zscore"), Some((3, "sorted-set")));
+        assert_eq!(lookup("GET"), None);
+        assert_eq!(lookup("nope"), None);
+    }
+
+    #[test]
+    fn test_arity_allows() {
+        assert!(arity_allows(2, 2));
+        assert!(!arity_allows(2, 1));
+        assert!(!arity_allows(2, 3));
+        assert!(arity_allows(-3, 3));
+        assert!(arity_allows(-3, 7));
+        assert!(!arity_allows(-3, 2));
+    }
+}
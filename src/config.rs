@@ -0,0 +1,228 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use crate::{glob::glob_match, pubsub::KEYSPACE_EVENT_FLAGS};
+
+/// Parameters and their defaults. Everything here can be read with CONFIG GET
+/// and changed with CONFIG SET, though `bind`, `port`, `replicaof` and the AOF
+/// settings only matter at startup.
+const DEFAULTS: &[(&str, &str)] = &[
+    ("appendfilename", "appendonly.aof"),
+    ("appendfsync", "everysec"),
+    ("appendonly", "no"),
+    ("bind", "127.0.0.1"),
+    ("dbfilename", "dump.rdb"),
+    ("dir", "."),
+    ("maxmemory", "0"),
+    ("maxmemory-policy", "noeviction"),
+    ("notify-keyspace-events", ""),
+    ("port", "6379"),
+    ("replicaof", ""),
+    ("save", "3600 1 300 100 60 10000"),
+    ("timeout", "0"),
+];
+
+/// Parameters that have to hold a non-negative integer.
+const NUMERIC: &[&str] = &["maxmemory", "port", "timeout"];
+
+/// Parameters that have to hold one of a fixed set of values.
+const CHOICES: &[(&str, &[&str])] = &[
+    ("appendfsync", &["always", "everysec", "no"]),
+    ("appendonly", &["yes", "no"]),
+    (
+        "maxmemory-policy",
+        &["noeviction", "allkeys-lru", "volatile-lru"],
+    ),
+];
+
+/// Parameters that hold any mix of the given single letter flags.
+const FLAGS: &[(&str, &str)] = &[("notify-keyspace-events", KEYSPACE_EVENT_FLAGS)];
+
+/// Runtime server parameters, shared by every connection.
+pub struct Config {
+    params: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            params: DEFAULTS
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Config {
+    /// Builds the startup config from command line flags, which follow the
+    /// `redis-server` form of `--<parameter> <value>`, e.g. `--port 6380`.
+    /// Values with several words can be given quoted or as separate
+    /// arguments, so `--replicaof localhost 6379` works too.
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.iter().peekable();
+        while let Some(flag) = args.next() {
+            let Some(name) = flag.strip_prefix("--") else {
+                return Err(format!("unexpected argument '{}'", flag));
+            };
+            let mut words = vec![];
+            while let Some(word) = args.next_if(|arg| !arg.starts_with("--")) {
+                words.push(word.as_str());
+            }
+            if words.is_empty() {
+                return Err(format!("missing value for '{}'", flag));
+            }
+            config.set(name, &words.join(" "))?;
+        }
+        Ok(config)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// The address to listen on, from `bind` and `port`.
+    pub fn address(&self) -> String {
+        format!(
+            "{}:{}",
+            self.get("bind").unwrap_or_default(),
+            self.get("port").unwrap_or_default()
+        )
+    }
+
+    /// Where SAVE and BGSAVE write the snapshot, from `dir` and `dbfilename`.
+    pub fn dump_path(&self) -> PathBuf {
+        PathBuf::from(self.get("dir").unwrap_or_default())
+            .join(self.get("dbfilename").unwrap_or_default())
+    }
+
+    /// Where commands are appended while `appendonly` is on.
+    pub fn aof_path(&self) -> PathBuf {
+        PathBuf::from(self.get("dir").unwrap_or_default())
+            .join(self.get("appendfilename").unwrap_or_default())
+    }
+
+    /// How many bytes the keyspace may take up, from `maxmemory`. Zero means
+    /// there's no limit.
+    pub fn maxmemory(&self) -> usize {
+        self.get("maxmemory")
+            .unwrap_or_default()
+            .parse()
+            .unwrap_or_default()
+    }
+
+    /// How long a client may go without sending anything (or take to accept
+    /// a reply) before it's disconnected, from `timeout`. Zero means forever.
+    pub fn timeout(&self) -> Option<Duration> {
+        match self.get("timeout").unwrap_or_default().parse::<u64>() {
+            Ok(0) | Err(_) => None,
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    /// Every parameter whose name matches the glob `pattern`, as CONFIG GET
+    /// takes it. Names are case insensitive.
+    pub fn matching(&self, pattern: &str) -> Vec<(&str, &str)> {
+        let pattern = pattern.to_lowercase();
+        self.params
+            .iter()
+            .filter(|(name, _)| glob_match(pattern.as_bytes(), name.as_bytes()))
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let name = name.to_lowercase();
+        let Some(current) = self.params.get_mut(&name) else {
+            return Err(format!(
+                "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            ));
+        };
+        if NUMERIC.contains(&name.as_str()) && value.parse::<u64>().is_err() {
+            return Err(format!(
+                "ERR CONFIG SET failed (possibly related to argument '{}') - argument must be a non-negative integer",
+                name
+            ));
+        }
+        if let Some((_, choices)) = CHOICES.iter().find(|(choice, _)| *choice == name) {
+            if !choices.contains(&value) {
+                return Err(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - argument(s) must be one of the following: {}",
+                    name,
+                    choices.join(", ")
+                ));
+            }
+        }
+        if let Some((_, flags)) = FLAGS.iter().find(|(flag, _)| *flag == name) {
+            if !value.chars().all(|flag| flags.contains(flag)) {
+                return Err(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - argument may only use the flags '{}'",
+                    name, flags
+                ));
+            }
+        }
+        *current = value.to_string();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matching() {
+        let config = Config::default();
+        assert_eq!(config.matching("maxmemory"), vec![("maxmemory", "0")]);
+        assert_eq!(
+            config.matching("MAXMEMORY*"),
+            vec![("maxmemory", "0"), ("maxmemory-policy", "noeviction")]
+        );
+        assert!(config.matching("no-such-param").is_empty());
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        assert_eq!(Config::from_args(&[]).unwrap().address(), "127.0.0.1:6379");
+
+        let config = Config::from_args(&args(&["--port", "6380", "--bind", "0.0.0.0"])).unwrap();
+        assert_eq!(config.address(), "0.0.0.0:6380");
+
+        assert!(Config::from_args(&args(&["--port"])).is_err());
+        assert!(Config::from_args(&args(&["--port", "high"])).is_err());
+        assert!(Config::from_args(&args(&["6380"])).is_err());
+
+        let config = Config::from_args(&args(&["--replicaof", "localhost", "6379"])).unwrap();
+        assert_eq!(config.get("replicaof"), Some("localhost 6379"));
+        let config = Config::from_args(&args(&["--replicaof", "localhost 6379"])).unwrap();
+        assert_eq!(config.get("replicaof"), Some("localhost 6379"));
+
+        assert_eq!(Config::from_args(&[]).unwrap().timeout(), None);
+        let config = Config::from_args(&args(&["--timeout", "30"])).unwrap();
+        assert_eq!(config.timeout(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_set() {
+        let mut config = Config::default();
+        assert_eq!(config.set("MaxMemory", "1024"), Ok(()));
+        assert_eq!(config.matching("maxmemory"), vec![("maxmemory", "1024")]);
+        assert!(config.set("maxmemory", "lots").is_err());
+        assert!(config.set("no-such-param", "1").is_err());
+        assert_eq!(config.set("appendfsync", "always"), Ok(()));
+        assert!(config.set("appendfsync", "sometimes").is_err());
+        assert_eq!(config.set("maxmemory-policy", "allkeys-lru"), Ok(()));
+        assert!(config.set("maxmemory-policy", "allkeys-lfu").is_err());
+        assert_eq!(config.set("notify-keyspace-events", "KEA"), Ok(()));
+        assert!(config.set("notify-keyspace-events", "KEQ").is_err());
+        // list events aren't published, so they can't be asked for
+        assert!(config.set("notify-keyspace-events", "KEl").is_err());
+        assert_eq!(config.maxmemory(), 1024);
+        assert_eq!(config.matching("maxmemory"), vec![("maxmemory", "1024")]);
+    }
+}
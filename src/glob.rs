@@ -0,0 +1,156 @@
+/// Redis-style glob matching: `*` matches any run of bytes, `?` matches a
+/// single byte, `[abc]`, `[a-z]` and `[^abc]` match character classes, and
+/// `\` escapes the next byte.
+pub fn glob_match(mut pattern: &[u8], string: &[u8]) -> bool {
+    // Only the most recent star ever needs revisiting: it can swallow one
+    // more byte and the match resumes from the pattern after it. That keeps
+    // matching at O(pattern * string) however many stars there are.
+    let mut backtrack: Option<(&[u8], usize)> = None;
+    let mut position = 0;
+
+    while position < string.len() {
+        if let Some((b'*', rest)) = pattern.split_first() {
+            pattern = rest;
+            backtrack = Some((rest, position));
+            continue;
+        }
+
+        if let Some(rest) = match_byte(pattern, string[position]) {
+            pattern = rest;
+            position += 1;
+            continue;
+        }
+
+        let Some((after_star, start)) = backtrack else {
+            return false;
+        };
+        pattern = after_star;
+        position = start + 1;
+        backtrack = Some((after_star, position));
+    }
+
+    trim_leading_stars(pattern).is_empty()
+}
+
+/// Matches the first non-star element of `pattern` against `c`, returning
+/// the pattern that follows it.
+fn match_byte(pattern: &[u8], c: u8) -> Option<&[u8]> {
+    let (&first, rest) = pattern.split_first()?;
+
+    match first {
+        b'?' => Some(rest),
+
+        b'[' => match parse_class(rest) {
+            Some((matches, after_class)) => matches(c).then_some(after_class),
+            // an unterminated class is matched literally
+            None => (c == b'[').then_some(rest),
+        },
+
+        b'\\' if !rest.is_empty() => (c == rest[0]).then_some(&rest[1..]),
+
+        expected => (c == expected).then_some(rest),
+    }
+}
+
+fn trim_leading_stars(mut pattern: &[u8]) -> &[u8] {
+    while let Some((b'*', rest)) = pattern.split_first() {
+        pattern = rest;
+    }
+    pattern
+}
+
+/// Parses the body of a `[...]` class (after the opening bracket), returning
+/// a predicate for it and the pattern that follows the closing bracket.
+fn parse_class(pattern: &[u8]) -> Option<(impl Fn(u8) -> bool, &[u8])> {
+    let (negated, mut body) = match pattern.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+
+    let mut ranges: Vec<(u8, u8)> = vec![];
+    loop {
+        match body {
+            [] => return None,
+            [b']', rest @ ..] => {
+                body = rest;
+                break;
+            }
+            [b'\\', c, rest @ ..] => {
+                ranges.push((*c, *c));
+                body = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                ranges.push(((*start).min(*end), (*start).max(*end)));
+                body = rest;
+            }
+            [c, rest @ ..] => {
+                ranges.push((*c, *c));
+                body = rest;
+            }
+        }
+    }
+
+    let matches = move |c: u8| {
+        let in_class = ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&c));
+        in_class != negated
+    };
+    Some((matches, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_star() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:42"));
+        assert!(matches("user:*", "user:"));
+        assert!(!matches("user:*", "users"));
+        assert!(matches("*:name", "user:1:name"));
+        assert!(matches("a**b", "a-long-b"));
+        assert!(matches("*a*b", "xaxab"));
+        assert!(!matches("*a*b", "xaxa"));
+    }
+
+    #[test]
+    fn test_many_stars() {
+        // would take exponential time with naive backtracking
+        let pattern = "a*".repeat(30) + "b";
+        assert!(!matches(&pattern, &"a".repeat(100)));
+        assert!(matches(&pattern, &("a".repeat(100) + "b")));
+    }
+
+    #[test]
+    fn test_question_mark() {
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn test_character_class() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(!matches("h[a-c]llo", "hdllo"));
+        assert!(matches("[", "["));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+    }
+}
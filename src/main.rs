@@ -1,11 +1,48 @@
+mod aof;
+mod blocking;
+mod commands;
+mod config;
+mod glob;
+mod memory;
+mod pubsub;
+mod rdb;
+mod replication;
+mod resp;
+mod storage;
+mod zset;
+
+use aof::{Aof, FsyncPolicy};
+use blocking::BlockedClients;
+use commands::COMMANDS;
+use config::Config;
+use glob::glob_match;
+use pubsub::{keyspace_event_bits, PubSub, Subscriber, SubscriptionKind};
+use replication::{is_subcommand, run_replica, Replica, Replication};
+use resp::{Protocol, RespValue, SEPARATOR};
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    io::{self, ErrorKind},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     thread,
     time::{Duration, Instant},
 };
+use storage::{
+    changes_made, evict_expired_sample, evict_if_expired, get_hash_mut, get_list_mut, get_live,
+    get_or_create_hash, get_or_create_list, get_or_create_set, get_or_create_zset, get_set_mut,
+    get_string, live_version, peek_live, read_hash, read_list, read_set, read_string, read_zset,
+    remove_if_empty, resolve_index, resolve_range, scan_elements, stable_hash, unix_millis_now,
+    Databases, SetValue, ShardedStorage, Storage, StorageEntry, DATABASE_COUNT, SHARD_COUNT,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    sync::mpsc::{self, UnboundedSender},
+    task, time,
+};
 
 #[derive(PartialEq)]
 enum MessageType {
@@ -17,317 +54,7265 @@ enum MessageType {
 }
 
 enum Command {
-    Echo(String),
-    Command(String),
+    Echo(Vec<u8>),
+    ListCommands,
+    CountCommands,
+    DescribeCommands(Vec<String>),
+    ConfigGet(String),
+    ConfigSet(String, String),
+    Info(Option<String>),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<String>),
+    Unwatch,
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    PSubscribe(Vec<String>),
+    PUnsubscribe(Vec<String>),
+    Publish(String, Vec<u8>),
+    /// `HELLO [protover]`, switching the connection to RESP2 or RESP3.
+    Hello(Option<u8>),
+    /// `CLIENT SETNAME`, an empty name clearing it.
+    ClientSetName(String),
+    /// `CLIENT GETNAME`, an empty string when no name is set.
+    ClientGetName,
+    ClientList,
+    ClientId,
+    /// `DEBUG SLEEP seconds`, holding up this connection alone.
+    DebugSleep(f64),
+    DebugObject(String),
+    /// Any other `DEBUG` subcommand, with its arguments.
+    Debug(Vec<String>),
+    ReplConf(Vec<String>),
+    /// `WAIT numreplicas timeout`, the timeout in milliseconds with 0 for
+    /// no limit.
+    Wait(usize, u64),
+    /// Always answered with a full resync, whatever offset the replica asks
+    /// to continue from.
+    Psync,
+    /// `SELECT index`, which switches the connection to another database.
+    Select(i64),
+    DbSize,
+    RandomKey,
+    Save,
+    BgSave,
+    FlushAll,
+    FlushDb,
     Get(String),
-    Set(String, String, Option<u64>),
+    Set(String, Vec<u8>, SetOptions),
+    GetSet(String, Vec<u8>),
+    GetDel(String),
+    Append(String, Vec<u8>),
+    Strlen(String),
+    SetRange(String, usize, Vec<u8>),
+    GetRange(String, i64, i64),
+    SetBit(String, usize, bool),
+    GetBit(String, usize),
+    /// Counts set bits, in the whole value or between two offsets. The
+    /// offsets count bits when `bits` is set and bytes otherwise.
+    BitCount {
+        key: String,
+        range: Option<(i64, i64)>,
+        bits: bool,
+    },
+    MGet(Vec<String>),
+    MSet(Vec<(String, Vec<u8>)>),
+    SetNx(String, Vec<u8>),
+    Del(Vec<String>),
+    /// Never sent by a client: maxmemory dropping a key, which goes to the AOF
+    /// and replicas as a DEL but is announced as `evicted`.
+    Evict(String),
+    Rename(String, String),
+    RenameNx(String, String),
+    /// `COPY source destination [DB db] [REPLACE]`, the database being the
+    /// selected one unless `DB` says otherwise.
+    Copy {
+        src: String,
+        dst: String,
+        db: Option<i64>,
+        replace: bool,
+    },
+    /// `MOVE key db`, from the selected database to another.
+    Move(String, i64),
+    Exists(Vec<String>),
+    /// Counts the keys that exist, like EXISTS, but also counts as a use of
+    /// them, resetting their idle time.
+    Touch(Vec<String>),
+    Keys(String),
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`, see `ShardedStorage::scan`.
+    Scan {
+        cursor: u64,
+        pattern: Option<String>,
+        count: usize,
+    },
+    /// `HSCAN`, `SSCAN` and `ZSCAN`, which walk the elements of one key the
+    /// way SCAN walks the keyspace.
+    HScan {
+        key: String,
+        cursor: u64,
+        pattern: Option<String>,
+        count: usize,
+    },
+    SScan {
+        key: String,
+        cursor: u64,
+        pattern: Option<String>,
+        count: usize,
+    },
+    ZScan {
+        key: String,
+        cursor: u64,
+        pattern: Option<String>,
+        count: usize,
+    },
+    Type(String),
+    /// `OBJECT ENCODING key`.
+    ObjectEncoding(String),
+    ObjectRefcount(String),
+    /// Seconds since a command last read or wrote the key.
+    ObjectIdletime(String),
+    /// About how many bytes the key and its value take up.
+    MemoryUsage(String),
+    LPush(String, Vec<Vec<u8>>),
+    RPush(String, Vec<Vec<u8>>),
+    LPop(String, Option<usize>),
+    RPop(String, Option<usize>),
+    /// Pops from the first of the lists that has anything, waiting up to the
+    /// timeout (forever without one) for a push when none do.
+    BLPop(Vec<String>, Option<Duration>),
+    BRPop(Vec<String>, Option<Duration>),
+    /// Pops from one end of the first list and pushes onto an end of the
+    /// second, which may be the same list.
+    LMove(String, String, ListEnd, ListEnd),
+    LRange(String, i64, i64),
+    LLen(String),
+    LIndex(String, i64),
+    LSet(String, i64, Vec<u8>),
+    LRem(String, i64, Vec<u8>),
+    LTrim(String, i64, i64),
+    /// Finds where `element` is in a list. `rank` picks which match to start
+    /// from, negative ones counting from the tail, and with a count (0 for
+    /// all of them) the reply is an array of positions. A `max_len` other
+    /// than 0 stops the search after that many elements.
+    LPos {
+        key: String,
+        element: Vec<u8>,
+        rank: i64,
+        count: Option<usize>,
+        max_len: usize,
+    },
+    HSet(String, Vec<(Vec<u8>, Vec<u8>)>),
+    HGet(String, Vec<u8>),
+    HGetAll(String),
+    HDel(String, Vec<Vec<u8>>),
+    HExists(String, Vec<u8>),
+    HLen(String),
+    HIncrBy(String, Vec<u8>, i64),
+    SAdd(String, Vec<Vec<u8>>),
+    SRem(String, Vec<Vec<u8>>),
+    SIsMember(String, Vec<u8>),
+    SMembers(String),
+    SCard(String),
+    SInter(Vec<String>),
+    SUnion(Vec<String>),
+    SDiff(Vec<String>),
+    /// `ZADD key [NX|XX] [GT|LT] [CH] score member [score member ...]`.
+    ZAdd(String, ZAddOptions, ScoredMembers),
+    ZScore(String, Vec<u8>),
+    ZIncrBy(String, f64, Vec<u8>),
+    ZRank(String, Vec<u8>),
+    ZRevRank(String, Vec<u8>),
+    /// `ZRANGE key start stop [WITHSCORES] [REV]`, by rank. `REV` counts
+    /// the ranks from the highest score down.
+    ZRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+        rev: bool,
+    },
+    Incr(String),
+    Decr(String),
+    IncrBy(String, i64),
+    DecrBy(String, i64),
+    Expire(String, u64, ExpireCondition),
+    Pexpire(String, u64, ExpireCondition),
+    ExpireAt(String, u64, ExpireCondition),
+    PexpireAt(String, u64, ExpireCondition),
+    Persist(String),
+    Ttl(String),
+    Pttl(String),
+    ExpireTime(String),
+    PexpireTime(String),
+    Error(String),
+    /// A command name we don't implement, kept so the client gets an error
+    /// back instead of waiting on a reply that never comes.
+    Unknown(String),
     Ping,
 }
 
-struct StorageEntry {
-    expire_timestamp: Option<Instant>,
-    value: String,
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Expiry {
+    /// `EX` / `PX`: a timeout counted from now.
+    After(Duration),
+    /// `EXAT` / `PXAT`: a unix timestamp in milliseconds.
+    AtUnixMillis(u64),
+}
+
+impl Expiry {
+    /// The deadline in Unix milliseconds, or `None` when it's too far off
+    /// to keep, past where Redis's signed milliseconds end.
+    fn to_unix_millis(self) -> Option<u64> {
+        let millis = match self {
+            Expiry::After(timeout) => u64::try_from(timeout.as_millis())
+                .ok()?
+                .checked_add(unix_millis_now())?,
+            Expiry::AtUnixMillis(millis) => millis,
+        };
+        (millis <= i64::MAX as u64).then_some(millis)
+    }
+}
+
+/// `LEFT` or `RIGHT`, which end of a list an element goes to or from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ListEnd {
+    Left,
+    Right,
 }
 
-impl StorageEntry {
-    fn new(value: String, expire_timestamp: Option<Instant>) -> StorageEntry {
-        StorageEntry {
-            expire_timestamp,
-            value,
+impl ListEnd {
+    fn parse(arg: &[u8]) -> Option<ListEnd> {
+        match arg_string(arg).to_lowercase().as_str() {
+            "left" => Some(ListEnd::Left),
+            "right" => Some(ListEnd::Right),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static [u8] {
+        match self {
+            ListEnd::Left => b"LEFT",
+            ListEnd::Right => b"RIGHT",
+        }
+    }
+
+    fn pop(self, list: &mut VecDeque<Vec<u8>>) -> Option<Vec<u8>> {
+        match self {
+            ListEnd::Left => list.pop_front(),
+            ListEnd::Right => list.pop_back(),
+        }
+    }
+
+    fn push(self, list: &mut VecDeque<Vec<u8>>, element: Vec<u8>) {
+        match self {
+            ListEnd::Left => list.push_front(element),
+            ListEnd::Right => list.push_back(element),
         }
     }
 }
 
-const SEPARATOR: &str = "\r\n";
-const NULL_BULK_STRING: &str = "$-1\r\n";
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SetCondition {
+    /// `NX`: only set keys that don't exist yet.
+    IfAbsent,
+    /// `XX`: only set keys that already exist.
+    IfPresent,
+}
 
-fn format_message(kind: MessageType, body: String) -> String {
-    let message = match kind {
-        MessageType::SimpleString => format!("+{}", body),
-        MessageType::Error => format!("-{}", body),
-        MessageType::Integer => ":".to_string(),
-        MessageType::BulkString => format!("${}{}{}", body.len(), SEPARATOR, body),
-        MessageType::Array => "*".to_string(),
-    };
-    format!("{}{}", message, SEPARATOR)
+#[derive(Debug, Default, PartialEq)]
+struct SetOptions {
+    expiry: Option<Expiry>,
+    condition: Option<SetCondition>,
+    keep_ttl: bool,
 }
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:6379").unwrap();
-    let storage = Arc::new(Mutex::new(HashMap::<String, StorageEntry>::new()));
+const SYNTAX_ERROR: &str = "ERR syntax error";
+
+/// Parses everything after `SET key value`.
+fn parse_set_options(args: &[Vec<u8>]) -> Result<SetOptions, String> {
+    let mut options = SetOptions::default();
+    let mut args = args.iter();
 
-    for stream in listener.incoming() {
-        let mut storage_for_thread = storage.clone();
-        match stream {
-            Ok(incoming_stream) => {
-                thread::spawn(move || {
-                    handle_stream(incoming_stream, &mut storage_for_thread);
+    while let Some(arg) = args.next() {
+        let option = arg_string(arg).to_lowercase();
+        match option.as_str() {
+            "nx" | "xx" => {
+                if options.condition.is_some() {
+                    return Err(SYNTAX_ERROR.to_string());
+                }
+                options.condition = Some(if option == "nx" {
+                    SetCondition::IfAbsent
+                } else {
+                    SetCondition::IfPresent
                 });
             }
-            Err(e) => {
-                println!("error: {}", e);
+
+            "keepttl" => {
+                if options.expiry.is_some() {
+                    return Err(SYNTAX_ERROR.to_string());
+                }
+                options.keep_ttl = true;
+            }
+
+            "ex" | "px" | "exat" | "pxat" => {
+                if options.expiry.is_some() || options.keep_ttl {
+                    return Err(SYNTAX_ERROR.to_string());
+                }
+                let amount = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+                let amount = arg_string(amount)
+                    .parse::<i64>()
+                    .map_err(|_e| NOT_AN_INTEGER_ERROR.to_string())?;
+                if amount <= 0 {
+                    return Err("ERR invalid expire time in 'set' command".to_string());
+                }
+                let amount = amount as u64;
+                let expiry = match option.as_str() {
+                    "ex" => Expiry::After(Duration::from_secs(amount)),
+                    "px" => Expiry::After(Duration::from_millis(amount)),
+                    "exat" => Expiry::AtUnixMillis(amount.saturating_mul(1000)),
+                    _ => Expiry::AtUnixMillis(amount),
+                };
+                if expiry.to_unix_millis().is_none() {
+                    return Err("ERR invalid expire time in 'set' command".to_string());
+                }
+                options.expiry = Some(expiry);
             }
+
+            _ => return Err(SYNTAX_ERROR.to_string()),
+        }
+    }
+
+    Ok(options)
+}
+
+/// The `NX`, `XX`, `GT` and `LT` flags of the EXPIRE family.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct ExpireCondition {
+    /// `NX` only sets an expiry on keys without one, `XX` only on keys that
+    /// already have one.
+    existing: Option<SetCondition>,
+    /// `GT` or `LT`: the new expiry has to compare this way against the
+    /// current one, a key without an expiry counting as never expiring.
+    comparison: Option<std::cmp::Ordering>,
+}
+
+impl ExpireCondition {
+    /// Whether a key expiring at `current` may be given `expiry` instead.
+    fn allows(self, current: Option<u64>, expiry: u64) -> bool {
+        let existing = match self.existing {
+            Some(SetCondition::IfAbsent) => current.is_none(),
+            Some(SetCondition::IfPresent) => current.is_some(),
+            None => true,
+        };
+        let comparison = match (self.comparison, current) {
+            (Some(ordering), Some(current)) => expiry.cmp(&current) == ordering,
+            (Some(ordering), None) => ordering == std::cmp::Ordering::Less,
+            (None, _current) => true,
+        };
+        existing && comparison
+    }
+
+    /// The flags that give this condition, to pass it on as it came.
+    fn flags(self) -> Vec<Vec<u8>> {
+        let mut flags = vec![];
+        match self.existing {
+            Some(SetCondition::IfAbsent) => flags.push(b"NX".to_vec()),
+            Some(SetCondition::IfPresent) => flags.push(b"XX".to_vec()),
+            None => {}
         }
+        match self.comparison {
+            Some(std::cmp::Ordering::Greater) => flags.push(b"GT".to_vec()),
+            Some(std::cmp::Ordering::Less) => flags.push(b"LT".to_vec()),
+            _ => {}
+        }
+        flags
     }
 }
 
-fn handle_stream(
-    mut stream: TcpStream,
-    storage_ref: &mut Arc<Mutex<HashMap<String, StorageEntry>>>,
-) {
-    loop {
-        let mut buffer = [0 as u8; 1024];
-        match stream.read(&mut buffer) {
-            Ok(read_bytes) => {
-                let message = String::from_utf8(buffer.to_vec())
-                    .unwrap()
-                    // removes null bytes https://stackoverflow.com/a/49406848
-                    .trim_end_matches(char::from(0))
-                    .to_string();
-                if read_bytes == 0 {
-                    break;
-                }
+fn parse_expire_condition(args: &[Vec<u8>]) -> Result<ExpireCondition, String> {
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for arg in args {
+        match arg_string(arg).to_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            _ => return Err(format!("ERR Unsupported option {}", arg_string(arg))),
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Err(
+            "ERR NX and XX, GT or LT options at the same time are not compatible".to_string(),
+        );
+    }
+    if gt && lt {
+        return Err("ERR GT and LT options at the same time are not compatible".to_string());
+    }
+    Ok(ExpireCondition {
+        existing: match (nx, xx) {
+            (true, _) => Some(SetCondition::IfAbsent),
+            (_, true) => Some(SetCondition::IfPresent),
+            _ => None,
+        },
+        comparison: match (gt, lt) {
+            (true, _) => Some(std::cmp::Ordering::Greater),
+            (_, true) => Some(std::cmp::Ordering::Less),
+            _ => None,
+        },
+    })
+}
 
-                // println!("message: {:?}", message.clone().chars().collect::<Vec<_>>());
-                let instructions = handle_client_message(message);
+/// Sorted set members with their scores, in the order ZADD gives them.
+type ScoredMembers = Vec<(f64, Vec<u8>)>;
 
-                if instructions.len() == 0 {
-                    stream
-                        .write(
-                            format_message(
-                                MessageType::Error,
-                                "Error processing message".to_string(),
-                            )
-                            .as_bytes(),
-                        )
-                        .unwrap();
-                }
-
-                for instruction in instructions {
-                    let message_to_send = match instruction {
-                        Command::Echo(message) => format_message(MessageType::BulkString, message),
-
-                        Command::Command(command) => match command.as_str() {
-                            _ => format_message(
-                                MessageType::SimpleString,
-                                "not supported yet".to_string(),
-                            ),
-                        },
-
-                        Command::Get(key) => {
-                            let mut storage = storage_ref.lock().unwrap();
-                            match storage.get(&key) {
-                                Some(entry) => {
-                                    let now = Instant::now();
-                                    let expiry = entry
-                                        .expire_timestamp
-                                        .unwrap_or(now + Duration::from_secs(1));
-                                    if entry.expire_timestamp.is_some() && now > expiry {
-                                        storage.remove(&key);
-                                        NULL_BULK_STRING.to_string()
-                                    } else {
-                                        format_message(
-                                            MessageType::BulkString,
-                                            entry.value.to_string(),
-                                        )
-                                    }
-                                }
-                                None => NULL_BULK_STRING.to_string(),
-                            }
-                        }
+#[derive(Debug, Default, PartialEq)]
+struct ZAddOptions {
+    /// `NX` only adds new members, `XX` only updates existing ones.
+    condition: Option<SetCondition>,
+    /// `GT` or `LT`: an existing member's score only changes when the new
+    /// one compares this way against it. New members are added regardless.
+    comparison: Option<std::cmp::Ordering>,
+    /// `CH`: the reply counts members whose score changed, not just new ones.
+    changed: bool,
+}
 
-                        Command::Set(key, value, expiry) => {
-                            let mut storage = storage_ref.lock().unwrap();
-                            let entry = StorageEntry::new(
-                                value,
-                                match expiry {
-                                    Some(ms) => Some(Instant::now() + Duration::from_millis(ms)),
-                                    None => None,
-                                },
-                            );
-                            storage.insert(key, entry);
-                            format_message(MessageType::SimpleString, "OK".to_string())
-                        }
+const NOT_A_FLOAT_ERROR: &str = "ERR value is not a valid float";
 
-                        Command::Ping => {
-                            format_message(MessageType::SimpleString, "PONG".to_string())
-                        }
-                    };
-                    // println!(
-                    //     "sending-----> {:?}",
-                    //     message_to_send.chars().collect::<Vec<_>>()
-                    // );
-                    stream.write(message_to_send.as_bytes()).unwrap();
+/// Parses a sorted set score. Infinities are fine, NaN isn't.
+fn parse_score(arg: &[u8]) -> Result<f64, String> {
+    match arg_string(arg).parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(score),
+        _ => Err(NOT_A_FLOAT_ERROR.to_string()),
+    }
+}
+
+/// Parses everything after `ZADD key`: the flags, then score and member pairs.
+fn parse_zadd(args: &[Vec<u8>]) -> Result<(ZAddOptions, ScoredMembers), String> {
+    let mut options = ZAddOptions::default();
+    let mut rest = args;
+    while let Some((arg, remaining)) = rest.split_first() {
+        match arg_string(arg).to_lowercase().as_str() {
+            "nx" => options.condition = Some(SetCondition::IfAbsent),
+            "xx" => options.condition = Some(SetCondition::IfPresent),
+            "gt" => options.comparison = Some(std::cmp::Ordering::Greater),
+            "lt" => options.comparison = Some(std::cmp::Ordering::Less),
+            "ch" => options.changed = true,
+            _ => break,
+        }
+        rest = remaining;
+    }
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err(SYNTAX_ERROR.to_string());
+    }
+    if options.condition == Some(SetCondition::IfAbsent) && options.comparison.is_some() {
+        return Err(
+            "ERR GT, LT, and/or NX options at the same time are not compatible".to_string(),
+        );
+    }
+    let members = rest
+        .chunks(2)
+        .map(|pair| Ok((parse_score(&pair[0])?, pair[1].to_vec())))
+        .collect::<Result<_, String>>()?;
+    Ok((options, members))
+}
+
+/// How many elements a SCAN step looks at when COUNT isn't given.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Parses the cursor and the `MATCH pattern` and `COUNT count` options of
+/// the SCAN family.
+fn parse_scan(args: &[Vec<u8>]) -> Result<(u64, Option<String>, usize), String> {
+    let cursor = arg_string(&args[0])
+        .parse::<u64>()
+        .map_err(|_e| "ERR invalid cursor".to_string())?;
+    let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| SYNTAX_ERROR.to_string())?;
+        match arg_string(arg).to_lowercase().as_str() {
+            "match" => pattern = Some(arg_string(value)),
+            "count" => {
+                count = match arg_string(value).parse::<i64>() {
+                    Ok(count) if count >= 1 => count as usize,
+                    Ok(_count) => return Err(SYNTAX_ERROR.to_string()),
+                    Err(_e) => return Err(NOT_AN_INTEGER_ERROR.to_string()),
                 }
-                println!();
-            }
-            Err(e) => {
-                println!("error: {}", e);
-                break;
             }
+            _ => return Err(SYNTAX_ERROR.to_string()),
         }
     }
+    Ok((cursor, pattern, count))
 }
 
-enum State {
-    ReadingArray,
-    ReadingBulkStringLength,
-    ReadingBulkStringContent,
+/// Whether a SCAN family element passes the `MATCH` option, if there is one.
+fn scan_match(pattern: &Option<String>, element: &[u8]) -> bool {
+    match pattern {
+        Some(pattern) => glob_match(pattern.as_bytes(), element),
+        None => true,
+    }
 }
 
-fn handle_client_message(message: String) -> Vec<Command> {
-    let mut state = State::ReadingArray;
-    let mut roller = CharRoller::from_string(message);
-    let mut command_name = "".to_string();
-    let mut args: Vec<String> = vec![];
-    let mut instructions: Vec<Command> = vec![];
-    let mut items_left_count = 0;
+impl Command {
+    /// How this command goes into the AOF if it changes the dataset, or
+    /// `None` if it only reads. Relative expiries become absolute ones, so
+    /// replaying the file later doesn't push them back.
+    fn propagated_args(&self) -> Option<Vec<Vec<u8>>> {
+        fn args(parts: &[&[u8]]) -> Vec<Vec<u8>> {
+            parts.iter().map(|part| part.to_vec()).collect()
+        }
+        fn with_key(name: &[u8], key: &str, rest: &[Vec<u8>]) -> Vec<Vec<u8>> {
+            let mut parts = args(&[name, key.as_bytes()]);
+            parts.extend(rest.iter().cloned());
+            parts
+        }
+        fn absolute_expiry(
+            key: &str,
+            expiry: Expiry,
+            condition: &ExpireCondition,
+        ) -> Option<Vec<Vec<u8>>> {
+            // one that's out of range is refused, so there's nothing to send
+            let millis = expiry.to_unix_millis()?.to_string();
+            let mut parts = args(&[b"PEXPIREAT", key.as_bytes(), millis.as_bytes()]);
+            parts.extend(condition.flags());
+            Some(parts)
+        }
 
-    while let Some(raw_word) = roller.next_word() {
-        let word = raw_word.trim();
-        match state {
-            State::ReadingArray => {
-                let instruction_type = get_instruction_type(word.chars().nth(0).unwrap());
-                if instruction_type != MessageType::Array {
-                    panic!("expected array");
+        let propagated = match self {
+            Command::Set(key, value, options) => {
+                let mut parts = args(&[b"SET", key.as_bytes(), value]);
+                match options.condition {
+                    Some(SetCondition::IfAbsent) => parts.push(b"NX".to_vec()),
+                    Some(SetCondition::IfPresent) => parts.push(b"XX".to_vec()),
+                    None => {}
                 }
-                let array_length = word[1..].parse::<usize>().unwrap();
-                items_left_count = array_length;
-                state = State::ReadingBulkStringLength;
+                if let Some(expiry) = options.expiry {
+                    parts.push(b"PXAT".to_vec());
+                    parts.push(expiry.to_unix_millis()?.to_string().into_bytes());
+                }
+                if options.keep_ttl {
+                    parts.push(b"KEEPTTL".to_vec());
+                }
+                parts
             }
-
-            State::ReadingBulkStringLength => {
-                let instruction_type = get_instruction_type(word.chars().nth(0).unwrap());
-                if instruction_type != MessageType::BulkString {
-                    panic!("expected bulk string");
+            Command::GetSet(key, value) => args(&[b"GETSET", key.as_bytes(), value]),
+            Command::GetDel(key) => args(&[b"GETDEL", key.as_bytes()]),
+            Command::Append(key, value) => args(&[b"APPEND", key.as_bytes(), value]),
+            Command::SetBit(key, offset, bit) => args(&[
+                b"SETBIT",
+                key.as_bytes(),
+                offset.to_string().as_bytes(),
+                if *bit { b"1" } else { b"0" },
+            ]),
+            Command::SetRange(key, offset, value) => args(&[
+                b"SETRANGE",
+                key.as_bytes(),
+                offset.to_string().as_bytes(),
+                value,
+            ]),
+            Command::MSet(pairs) => {
+                let mut parts = args(&[b"MSET"]);
+                for (key, value) in pairs {
+                    parts.push(key.as_bytes().to_vec());
+                    parts.push(value.clone());
                 }
-                state = State::ReadingBulkStringContent;
+                parts
             }
-
-            State::ReadingBulkStringContent => {
-                if command_name.is_empty() {
-                    command_name = word.to_string();
-                } else {
-                    args.push(word.to_string());
+            Command::SetNx(key, value) => args(&[b"SETNX", key.as_bytes(), value]),
+            Command::Del(keys) => {
+                let mut parts = args(&[b"DEL"]);
+                parts.extend(keys.iter().map(|key| key.as_bytes().to_vec()));
+                parts
+            }
+            Command::Evict(key) => args(&[b"DEL", key.as_bytes()]),
+            Command::Rename(key, new_key) => args(&[b"RENAME", key.as_bytes(), new_key.as_bytes()]),
+            Command::RenameNx(key, new_key) => {
+                args(&[b"RENAMENX", key.as_bytes(), new_key.as_bytes()])
+            }
+            Command::Copy {
+                src,
+                dst,
+                db,
+                replace,
+            } => {
+                let mut parts = args(&[b"COPY", src.as_bytes(), dst.as_bytes()]);
+                if let Some(db) = db {
+                    parts.push(b"DB".to_vec());
+                    parts.push(db.to_string().into_bytes());
                 }
-
-                if items_left_count != 1 {
-                    state = State::ReadingBulkStringLength;
-                    items_left_count = items_left_count - 1;
-                    continue;
+                if *replace {
+                    parts.push(b"REPLACE".to_vec());
+                }
+                parts
+            }
+            Command::Move(key, db) => args(&[b"MOVE", key.as_bytes(), db.to_string().as_bytes()]),
+            // what a blocked pop finally did is applied without blocking
+            // elsewhere, so the timeout has no use there
+            Command::BLPop(keys, _timeout) | Command::BRPop(keys, _timeout) => {
+                let mut parts = args(&[match self {
+                    Command::BLPop(..) => b"BLPOP",
+                    _ => b"BRPOP",
+                }]);
+                parts.extend(keys.iter().map(|key| key.as_bytes().to_vec()));
+                parts.push(b"0".to_vec());
+                parts
+            }
+            Command::LMove(source, destination, from, to) => args(&[
+                b"LMOVE",
+                source.as_bytes(),
+                destination.as_bytes(),
+                from.name(),
+                to.name(),
+            ]),
+            Command::LPush(key, elements) => with_key(b"LPUSH", key, elements),
+            Command::RPush(key, elements) => with_key(b"RPUSH", key, elements),
+            Command::LPop(key, count) | Command::RPop(key, count) => {
+                let name: &[u8] = match self {
+                    Command::LPop(..) => b"LPOP",
+                    _ => b"RPOP",
+                };
+                let mut parts = args(&[name, key.as_bytes()]);
+                if let Some(count) = count {
+                    parts.push(count.to_string().into_bytes());
+                }
+                parts
+            }
+            Command::LSet(key, index, element) => args(&[
+                b"LSET",
+                key.as_bytes(),
+                index.to_string().as_bytes(),
+                element,
+            ]),
+            Command::LRem(key, count, element) => args(&[
+                b"LREM",
+                key.as_bytes(),
+                count.to_string().as_bytes(),
+                element,
+            ]),
+            Command::LTrim(key, start, stop) => args(&[
+                b"LTRIM",
+                key.as_bytes(),
+                start.to_string().as_bytes(),
+                stop.to_string().as_bytes(),
+            ]),
+            Command::HSet(key, pairs) => {
+                let mut parts = args(&[b"HSET", key.as_bytes()]);
+                for (field, value) in pairs {
+                    parts.push(field.clone());
+                    parts.push(value.clone());
+                }
+                parts
+            }
+            Command::HDel(key, fields) => with_key(b"HDEL", key, fields),
+            Command::HIncrBy(key, field, delta) => args(&[
+                b"HINCRBY",
+                key.as_bytes(),
+                field,
+                delta.to_string().as_bytes(),
+            ]),
+            Command::SAdd(key, members) => with_key(b"SADD", key, members),
+            Command::SRem(key, members) => with_key(b"SREM", key, members),
+            Command::ZAdd(key, options, members) => {
+                let mut parts = args(&[b"ZADD", key.as_bytes()]);
+                match options.condition {
+                    Some(SetCondition::IfAbsent) => parts.push(b"NX".to_vec()),
+                    Some(SetCondition::IfPresent) => parts.push(b"XX".to_vec()),
+                    None => {}
+                }
+                match options.comparison {
+                    Some(std::cmp::Ordering::Greater) => parts.push(b"GT".to_vec()),
+                    Some(std::cmp::Ordering::Less) => parts.push(b"LT".to_vec()),
+                    _ => {}
+                }
+                if options.changed {
+                    parts.push(b"CH".to_vec());
                 }
+                for (score, member) in members {
+                    // printed in full, so the score read back is the same
+                    parts.push(score.to_string().into_bytes());
+                    parts.push(member.clone());
+                }
+                parts
+            }
+            Command::ZIncrBy(key, delta, member) => args(&[
+                b"ZINCRBY",
+                key.as_bytes(),
+                delta.to_string().as_bytes(),
+                member,
+            ]),
+            Command::Incr(key) => args(&[b"INCR", key.as_bytes()]),
+            Command::Decr(key) => args(&[b"DECR", key.as_bytes()]),
+            Command::IncrBy(key, delta) => {
+                args(&[b"INCRBY", key.as_bytes(), delta.to_string().as_bytes()])
+            }
+            Command::DecrBy(key, delta) => {
+                args(&[b"DECRBY", key.as_bytes(), delta.to_string().as_bytes()])
+            }
+            Command::Expire(key, seconds, condition) => {
+                absolute_expiry(key, Expiry::After(Duration::from_secs(*seconds)), condition)?
+            }
+            Command::Pexpire(key, millis, condition) => absolute_expiry(
+                key,
+                Expiry::After(Duration::from_millis(*millis)),
+                condition,
+            )?,
+            Command::ExpireAt(key, seconds, condition) => absolute_expiry(
+                key,
+                Expiry::AtUnixMillis(seconds.saturating_mul(1000)),
+                condition,
+            )?,
+            Command::PexpireAt(key, millis, condition) => {
+                absolute_expiry(key, Expiry::AtUnixMillis(*millis), condition)?
+            }
+            Command::Persist(key) => args(&[b"PERSIST", key.as_bytes()]),
+            Command::FlushAll => args(&[b"FLUSHALL"]),
+            Command::FlushDb => args(&[b"FLUSHDB"]),
+            _ => return None,
+        };
+        Some(propagated)
+    }
 
-                match command_name.to_lowercase().as_str() {
-                    "ping" => {
-                        instructions.push(Command::Ping);
-                        command_name = "".to_string();
-                    }
+    /// Whether the command can make the dataset bigger, which isn't allowed
+    /// while it's over maxmemory and nothing can be evicted.
+    fn may_grow(&self) -> bool {
+        matches!(
+            self,
+            Command::Set(..)
+                | Command::GetSet(..)
+                | Command::Append(..)
+                | Command::SetRange(..)
+                | Command::SetBit(..)
+                | Command::MSet(..)
+                | Command::SetNx(..)
+                | Command::Copy { .. }
+                | Command::LPush(..)
+                | Command::RPush(..)
+                | Command::LMove(..)
+                | Command::LSet(..)
+                | Command::HSet(..)
+                | Command::HIncrBy(..)
+                | Command::SAdd(..)
+                | Command::ZAdd(..)
+                | Command::ZIncrBy(..)
+                | Command::Incr(..)
+                | Command::Decr(..)
+                | Command::IncrBy(..)
+                | Command::DecrBy(..)
+        )
+    }
+}
 
-                    "echo" => {
-                        instructions.push(Command::Echo(args.join(" ").to_string()));
-                        command_name = "".to_string();
-                    }
+const NOT_AN_INTEGER_ERROR: &str = "ERR value is not an integer or out of range";
+const OVERFLOW_ERROR: &str = "ERR increment or decrement would overflow";
+const NO_SUCH_KEY_ERROR: &str = "ERR no such key";
+const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+/// DEBUG subcommands that only tune internals this server doesn't have, so
+/// they're accepted and ignored. Test suites written against Redis send them
+/// while setting up.
+const IGNORED_DEBUG_SUBCOMMANDS: &[&str] = &[
+    "dict-resizing",
+    "quicklist-packed-threshold",
+    "set-skip-checksum-validation",
+    "stringmatch-len",
+];
+/// The longest string a command may build, 512MB as in Redis.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+/// The longest argument a client may send, Redis's `proto-max-bulk-len`.
+const MAX_BULK_LEN: usize = MAX_STRING_LEN;
 
-                    "get" => {
-                        instructions.push(Command::Get(args[0].to_string()));
-                        command_name = "".to_string();
-                    }
+/// How many bits are set from bit `start` to bit `stop` of `value`, both
+/// included, counting from the most significant bit of the first byte.
+fn count_bits(value: &[u8], start: usize, stop: usize) -> u32 {
+    let (first, last) = (start / 8, stop / 8);
+    let total: u32 = value[first..=last]
+        .iter()
+        .map(|byte| byte.count_ones())
+        .sum();
+    // take back the bits of the end bytes that fall outside the range
+    let before = value[first] & !(0xff >> (start % 8));
+    let after = value[last] & ((1 << (7 - stop % 8)) - 1);
+    total - before.count_ones() - after.count_ones()
+}
 
-                    "set" => {
-                        let mut expiry: Option<u64> = None;
-                        if args.len() == 4 {
-                            expiry = match args[3].parse::<u64>() {
-                                Ok(expiry) => {
-                                    if expiry > 0 {
-                                        Some(expiry)
-                                    } else {
-                                        None
-                                    }
-                                }
-                                Err(_e) => None,
-                            }
-                        }
-                        instructions.push(Command::Set(
-                            args[0].to_string(),
-                            args[1].to_string(),
-                            expiry,
-                        ));
-                        command_name = "".to_string();
-                    }
+/// Parses a SETBIT or GETBIT offset, which has to land inside a string of
+/// at most `MAX_STRING_LEN` bytes.
+fn parse_bit_offset(arg: &[u8]) -> Result<usize, String> {
+    match std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<usize>().ok())
+    {
+        Some(offset) if offset < MAX_STRING_LEN * 8 => Ok(offset),
+        _ => Err("ERR bit offset is not an integer or out of range".to_string()),
+    }
+}
 
-                    "command" => {
-                        instructions.push(Command::Command(args.join(" ").to_string()));
-                        command_name = "".to_string();
-                    }
+/// Adds `delta` to the integer stored at `key`, keeping any expiry the
+/// entry already had. Missing (or expired) keys start from zero.
+fn apply_delta(storage: &mut Storage, key: &str, delta: i64) -> Result<i64, String> {
+    evict_if_expired(storage, key);
+    let entry = storage
+        .entry(key.to_string())
+        .or_insert_with(|| StorageEntry::string(b"0".to_vec(), None));
+    let current = std::str::from_utf8(entry.as_string()?)
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .ok_or_else(|| NOT_AN_INTEGER_ERROR.to_string())?;
+    let next = current
+        .checked_add(delta)
+        .ok_or_else(|| OVERFLOW_ERROR.to_string())?;
+    *entry.as_string_mut()? = next.to_string().into_bytes();
+    Ok(next)
+}
 
-                    other => {
-                        println!("unknown command: {}", other);
-                    }
-                }
-                state = State::ReadingBulkStringLength;
+/// Everything connections share. Cloning it just clones the handles.
+#[derive(Clone, Default)]
+struct SharedState {
+    storage: Arc<Databases>,
+    config: Arc<Mutex<Config>>,
+    connected_clients: Arc<AtomicUsize>,
+    /// The id the newest connection got. IDs start from 1, as in Redis.
+    last_client_id: Arc<AtomicU64>,
+    pubsub: Arc<Mutex<PubSub>>,
+    /// The `notify-keyspace-events` setting as `keyspace_event_bits` packs
+    /// it, so writes don't take the config lock to find it's off.
+    keyspace_events: Arc<AtomicU32>,
+    /// Set while a BGSAVE is writing, so a second one doesn't race it.
+    saving: Arc<AtomicBool>,
+    /// The append-only file, while `appendonly` is on.
+    aof: Option<Arc<Mutex<Aof>>>,
+    blocked: Arc<BlockedClients>,
+    /// Every open connection by id, for CLIENT LIST.
+    clients: Arc<Mutex<HashMap<u64, ClientInfo>>>,
+    replication: Arc<Replication>,
+}
+
+/// What CLIENT LIST tells about a connection.
+struct ClientInfo {
+    addr: String,
+    name: Option<String>,
+    connected_at: Instant,
+}
+
+/// How often the active expiry cycle runs, and how many keys it may check
+/// per run. Keys are checked a small batch at a time so connections never
+/// wait on the lock for long.
+const ACTIVE_EXPIRY_INTERVAL: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRY_KEYS_PER_CYCLE: usize = 1000;
+const ACTIVE_EXPIRY_BATCH: usize = 20;
+
+/// Reclaims keys that expired without ever being read again, which lazy
+/// expiry alone would keep around forever. Like Redis, a cycle keeps going
+/// past its budget while more than a quarter of a batch turns out expired.
+fn run_active_expiry(state: SharedState) {
+    let mut cursors = [[0; SHARD_COUNT]; DATABASE_COUNT];
+    loop {
+        for (db, cursors) in cursors.iter_mut().enumerate() {
+            expire_cycle(&state, db, cursors);
+        }
+        thread::sleep(ACTIVE_EXPIRY_INTERVAL);
+    }
+}
+
+/// One cycle over the shards of a single database.
+fn expire_cycle(state: &SharedState, db: usize, cursors: &mut [usize; SHARD_COUNT]) {
+    let storage_ref = state.storage.db(db);
+    for (shard, cursor) in cursors.iter_mut().enumerate() {
+        let mut checked_in_shard = 0;
+        loop {
+            // the shard is only locked for one batch at a time
+            let (checked, evicted) = evict_expired_sample(
+                &mut storage_ref.write_shard(shard),
+                cursor,
+                ACTIVE_EXPIRY_BATCH,
+            );
+            for key in &evicted {
+                notify(state, db, 'x', "expired", key);
+            }
+            checked_in_shard += checked;
+            let mostly_live = evicted.len() * 4 <= checked;
+            let budget_spent = checked_in_shard >= ACTIVE_EXPIRY_KEYS_PER_CYCLE / SHARD_COUNT;
+            if checked == 0 || (mostly_live && budget_spent) {
+                break;
             }
         }
     }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = Config::from_args(&args).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    });
+    let listener = TcpListener::bind(config.address()).await.unwrap();
+    let appendonly = config.get("appendonly") == Some("yes");
+    let fsync = FsyncPolicy::from_config(config.get("appendfsync").unwrap_or_default());
+    let (aof_path, dump_path) = (config.aof_path(), config.dump_path());
+    let replication = Replication::from_config(config.get("replicaof").unwrap_or_default())
+        .unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        });
+    let keyspace_events =
+        keyspace_event_bits(config.get("notify-keyspace-events").unwrap_or_default());
+    let mut state = SharedState {
+        keyspace_events: Arc::new(AtomicU32::new(keyspace_events)),
+        config: Arc::new(Mutex::new(config)),
+        replication: Arc::new(replication),
+        ..SharedState::default()
+    };
+
+    // the AOF has everything the snapshot has and more, so with it on the
+    // snapshot isn't read at all
+    let (path, loaded) = if appendonly {
+        (&aof_path, replay_aof(&state, &aof_path))
+    } else {
+        (&dump_path, rdb::load_from_disk(&state.storage, &dump_path))
+    };
+    match loaded {
+        Ok(count) => println!("loaded {} entries from {}", count, path.display()),
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => {
+            eprintln!("can't load {}: {}", path.display(), error);
+            std::process::exit(1);
+        }
+    }
+    if appendonly {
+        let aof = Aof::open(&aof_path, fsync).unwrap_or_else(|error| {
+            eprintln!("can't open {}: {}", aof_path.display(), error);
+            std::process::exit(1);
+        });
+        state.aof = Some(Arc::new(Mutex::new(aof)));
+    }
+
+    let state_for_expiry = state.clone();
+    thread::spawn(move || run_active_expiry(state_for_expiry));
+    if state.replication.is_replica() {
+        let state_for_replica = state.clone();
+        thread::spawn(move || run_replica(state_for_replica));
+    }
 
-    instructions
+    serve(listener, state).await;
 }
 
-fn get_instruction_type(c: char) -> MessageType {
-    match c {
-        '+' => MessageType::SimpleString,
-        '-' => MessageType::Error,
-        ':' => MessageType::Integer,
-        '$' => MessageType::BulkString,
-        '*' => MessageType::Array,
-        _ => panic!("unknown type: {}", c),
+/// Runs the commands in the AOF at `path` through the same dispatch as a
+/// connection's, returning how many there were. A command cut off at the end,
+/// by a crash part way through writing it, is left out.
+fn replay_aof(state: &SharedState, path: &Path) -> io::Result<usize> {
+    let contents = std::fs::read(path)?;
+    let complete = &contents[..complete_frames_len(&contents)];
+    let instructions = handle_client_message(complete)
+        .map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?;
+    let count = instructions.len();
+    let mut db = 0;
+    for instruction in instructions {
+        execute_command(instruction, &mut db, state);
     }
+    Ok(count)
 }
 
-pub struct CharRoller {
-    chars: Vec<char>,
-    index: usize,
+fn delta_reply(storage_ref: &ShardedStorage, key: &str, delta: Result<i64, String>) -> RespValue {
+    let mut storage = storage_ref.write(key);
+    match delta.and_then(|delta| apply_delta(&mut storage, key, delta)) {
+        Ok(value) => RespValue::Integer(value),
+        Err(error) => RespValue::Error(error),
+    }
 }
 
-impl CharRoller {
-    pub fn from_string(phrase: String) -> CharRoller {
-        let chars: Vec<_> = phrase.chars().collect();
-        CharRoller { chars, index: 0 }
+/// RENAME, or RENAMENX when `if_absent` is set. The entry moves over whole,
+/// so the expiry goes with the value.
+fn rename_key(
+    storage_ref: &ShardedStorage,
+    key: String,
+    new_key: String,
+    if_absent: bool,
+) -> RespValue {
+    let mut shards = storage_ref.write_keys([&key, &new_key]);
+    evict_if_expired(shards.shard(&key), &key);
+    evict_if_expired(shards.shard(&new_key), &new_key);
+    if !shards.shard(&key).contains_key(&key) {
+        return RespValue::error(NO_SUCH_KEY_ERROR);
     }
+    if if_absent && shards.shard(&new_key).contains_key(&new_key) {
+        return RespValue::Integer(0);
+    }
+    if key != new_key {
+        let entry = shards.shard(&key).remove(&key).unwrap();
+        shards.shard(&new_key).insert(new_key, entry);
+    }
+    if if_absent {
+        RespValue::Integer(1)
+    } else {
+        RespValue::ok()
+    }
+}
 
-    pub fn next_word(&mut self) -> Option<String> {
-        let mut word = String::new();
-        if self.index == self.chars.len() {
+/// Stores what COPY took from its source, unless there was nothing to take
+/// or `key` is taken and mustn't be replaced.
+fn copy_into(
+    storage: &mut Storage,
+    key: String,
+    copy: Option<StorageEntry>,
+    replace: bool,
+) -> RespValue {
+    let Some(copy) = copy else {
+        return RespValue::Integer(0);
+    };
+    evict_if_expired(storage, &key);
+    if storage.contains_key(&key) && !replace {
+        return RespValue::Integer(0);
+    }
+    storage.insert(key, copy);
+    RespValue::Integer(1)
+}
+
+/// The reply to ZRANK, or to ZREVRANK when `rev` is set: the member's
+/// position counting from the lowest score, or from the highest.
+fn rank_reply(storage_ref: &ShardedStorage, key: &str, member: &[u8], rev: bool) -> RespValue {
+    let storage = storage_ref.read(key);
+    match read_zset(&storage, key) {
+        Ok(Some(zset)) => match zset.rank(member) {
+            Some(rank) if rev => RespValue::Integer((zset.len() - 1 - rank) as i64),
+            Some(rank) => RespValue::Integer(rank as i64),
+            None => RespValue::null_bulk(),
+        },
+        Ok(None) => RespValue::null_bulk(),
+        Err(error) => RespValue::Error(error),
+    }
+}
+
+/// The reply to each SCAN step: the cursor to continue from, as a bulk
+/// string, and the elements found.
+fn scan_reply(next_cursor: u64, elements: Vec<RespValue>) -> RespValue {
+    RespValue::array(vec![
+        RespValue::bulk(next_cursor.to_string().as_bytes()),
+        RespValue::array(elements),
+    ])
+}
+
+/// Builds the INFO text, either every section or just the one asked for.
+fn info(state: &SharedState, section: Option<&str>) -> String {
+    let port = state
+        .config
+        .lock()
+        .unwrap()
+        .get("port")
+        .unwrap_or_default()
+        .to_string();
+    let server = format!(
+        "redis_version:7.0.0\r\nredis_mode:standalone\r\nprocess_id:{}\r\ntcp_port:{}\r\n",
+        std::process::id(),
+        port
+    );
+    let clients = format!(
+        "connected_clients:{}\r\n",
+        state.connected_clients.load(Ordering::SeqCst)
+    );
+
+    let memory = {
+        let config = state.config.lock().unwrap();
+        format!(
+            "used_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\n",
+            state.storage.used_memory(),
+            config.maxmemory(),
+            config.get("maxmemory-policy").unwrap_or_default()
+        )
+    };
+
+    let mut keyspace = String::new();
+    for (index, storage_ref) in state.storage.iter().enumerate() {
+        let (mut keys, mut expires) = (0, 0);
+        for storage in storage_ref.read_all().iter() {
+            for entry in storage.values().filter(|entry| !entry.is_expired()) {
+                keys += 1;
+                expires += entry.expire_timestamp.is_some() as usize;
+            }
+        }
+        if keys > 0 {
+            keyspace += &format!(
+                "db{}:keys={},expires={},avg_ttl=0\r\n",
+                index, keys, expires
+            );
+        }
+    }
+
+    let sections = [
+        ("Server", server),
+        ("Clients", clients),
+        ("Memory", memory),
+        ("Replication", state.replication.info()),
+        ("Keyspace", keyspace),
+    ];
+    let wanted = section.map(str::to_lowercase);
+    sections
+        .iter()
+        .filter(|(name, _)| match wanted.as_deref() {
+            None | Some("all") | Some("default") | Some("everything") => true,
+            Some(wanted) => wanted == name.to_lowercase(),
+        })
+        .map(|(name, body)| format!("# {}\r\n{}", name, body))
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+/// EXPIRE and the like, `name` being the command for the error a deadline
+/// out of range gets.
+fn set_expiry(
+    state: &SharedState,
+    db: usize,
+    name: &str,
+    key: &str,
+    expiry: Expiry,
+    condition: ExpireCondition,
+) -> RespValue {
+    let Some(deadline) = expiry.to_unix_millis() else {
+        return RespValue::Error(format!("ERR invalid expire time in '{}' command", name));
+    };
+    let mut storage = state.storage.db(db).write(key);
+    evict_if_expired(&mut storage, key);
+    let updated = match storage.get_mut(key) {
+        Some(entry) if !condition.allows(entry.expire_timestamp, deadline) => false,
+        // a deadline that has already passed deletes the key there and then
+        Some(_entry) if deadline <= unix_millis_now() => {
+            storage.remove(key);
+            notify(state, db, 'g', "del", key);
+            true
+        }
+        Some(entry) => {
+            entry.expire_timestamp = Some(deadline);
+            entry.mark_changed();
+            notify(state, db, 'g', "expire", key);
+            true
+        }
+        None => false,
+    };
+    RespValue::Integer(updated as i64)
+}
+
+/// Publishes a keyspace notification for `event` on `key`, if the
+/// `notify-keyspace-events` setting asks for events of `class`. Writes call
+/// it while they still hold the key's shard, so notifications for a key go
+/// out in the order its writes were made. Keys that writes found expired
+/// and dropped are announced first, so an `expired` goes out before whatever
+/// the write then did to the key.
+fn notify(state: &SharedState, db: usize, class: char, event: &str, key: &str) {
+    let flags = state.keyspace_events.load(Ordering::Relaxed);
+    if flags == 0 {
+        return;
+    }
+    let mut pubsub = state.pubsub.lock().unwrap();
+    for expired in state.storage.db(db).take_expired() {
+        pubsub.notify_keyspace_event(flags, 'x', "expired", db, &expired);
+    }
+    pubsub.notify_keyspace_event(flags, class, event, db, key);
+}
+
+/// Announces the keys that writes found expired and dropped, for commands
+/// that don't announce anything themselves. The queue is emptied either way.
+fn notify_expired(state: &SharedState, db: usize) {
+    let expired = state.storage.db(db).take_expired();
+    let flags = state.keyspace_events.load(Ordering::Relaxed);
+    if expired.is_empty() || flags == 0 {
+        return;
+    }
+    let mut pubsub = state.pubsub.lock().unwrap();
+    for key in expired {
+        pubsub.notify_keyspace_event(flags, 'x', "expired", db, &key);
+    }
+}
+
+/// Remaining lifetime of `key` in milliseconds, using the Redis conventions
+/// of `-2` for a missing key and `-1` for a key without an expiry.
+fn remaining_ttl_millis(storage_ref: &ShardedStorage, key: &str) -> i128 {
+    let storage = storage_ref.read(key);
+    match get_live(&storage, key) {
+        Some(entry) => match entry.expire_timestamp {
+            Some(expiry) => expiry.saturating_sub(unix_millis_now()) as i128,
+            None => -1,
+        },
+        None => -2,
+    }
+}
+
+/// When `key` expires, in milliseconds since the Unix epoch, with the same
+/// `-2` and `-1` as `remaining_ttl_millis`.
+fn unix_expiry_millis(storage_ref: &ShardedStorage, key: &str) -> i128 {
+    let storage = storage_ref.read(key);
+    match get_live(&storage, key) {
+        Some(entry) => entry.expire_timestamp.map_or(-1, |expiry| expiry as i128),
+        None => -2,
+    }
+}
+
+/// Pops from one end of a list. Without a count the reply is a single bulk
+/// string, with one it's an array of up to `count` elements.
+fn pop_list(
+    storage_ref: &ShardedStorage,
+    key: &str,
+    count: Option<usize>,
+    pop: fn(&mut VecDeque<Vec<u8>>) -> Option<Vec<u8>>,
+) -> RespValue {
+    let mut storage = storage_ref.write(key);
+    let list = match get_list_mut(&mut storage, key) {
+        Ok(Some(list)) => list,
+        Ok(None) if count.is_some() => return RespValue::Array(None),
+        Ok(None) => return RespValue::null_bulk(),
+        Err(error) => return RespValue::Error(error),
+    };
+
+    let reply = match count {
+        Some(count) => RespValue::array(
+            (0..count)
+                .map_while(|_| pop(list))
+                .map(|element| RespValue::bulk(&element))
+                .collect(),
+        ),
+        None => match pop(list) {
+            Some(element) => RespValue::bulk(&element),
+            None => RespValue::null_bulk(),
+        },
+    };
+    remove_if_empty(&mut storage, key);
+    reply
+}
+
+/// Moves an element between lists, with both of their shards locked so it's
+/// never missing from both or in both.
+fn move_element(
+    state: &SharedState,
+    db: usize,
+    source: &str,
+    destination: &str,
+    from: ListEnd,
+    to: ListEnd,
+) -> RespValue {
+    let storage_ref = state.storage.db(db);
+    let mut shards = storage_ref.write_keys([&source, &destination]);
+    // nothing is popped if it couldn't be pushed
+    if let Err(error) = read_list(shards.shard_ref(destination), destination) {
+        return RespValue::Error(error);
+    }
+    let element = match get_list_mut(shards.shard(source), source) {
+        Ok(Some(list)) => from.pop(list),
+        Ok(None) => None,
+        Err(error) => return RespValue::Error(error),
+    };
+    let Some(element) = element else {
+        return RespValue::null_bulk();
+    };
+    // a list moving onto itself might have been emptied and removed, in
+    // which case it's made afresh
+    remove_if_empty(shards.shard(source), source);
+    match get_or_create_list(shards.shard(destination), destination) {
+        Ok(list) => to.push(list, element.clone()),
+        Err(error) => return RespValue::Error(error),
+    }
+    state.blocked.key_pushed(db, destination);
+    RespValue::bulk(&element)
+}
+
+/// Pops one element from the first of `keys` holding a list, replying with
+/// the key and the element, or a null array if they're all empty.
+fn pop_first(
+    storage_ref: &ShardedStorage,
+    keys: &[String],
+    pop: fn(&mut VecDeque<Vec<u8>>) -> Option<Vec<u8>>,
+) -> RespValue {
+    for key in keys {
+        match pop_list(storage_ref, key, None, pop) {
+            RespValue::Bulk(None) => {}
+            RespValue::Bulk(Some(element)) => {
+                return RespValue::array(vec![
+                    RespValue::bulk(key.as_bytes()),
+                    RespValue::Bulk(Some(element)),
+                ])
+            }
+            error => return error,
+        }
+    }
+    RespValue::Array(None)
+}
+
+#[derive(Clone, Copy)]
+enum SetOperation {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// Combines the sets at `keys`, treating missing keys as empty sets. For
+/// `Diff` the first set is the one the others are taken away from.
+fn combine_sets(
+    storage_ref: &ShardedStorage,
+    keys: &[String],
+    operation: SetOperation,
+) -> RespValue {
+    let shards = storage_ref.read_keys(keys);
+    let sets: Result<Vec<Option<&SetValue>>, String> = keys
+        .iter()
+        .map(|key| read_set(shards.shard_ref(key), key))
+        .collect();
+    let sets = match sets {
+        Ok(sets) => sets,
+        Err(error) => return RespValue::Error(error),
+    };
+    let empty = SetValue::new();
+    let sets: Vec<&SetValue> = sets.into_iter().map(|set| set.unwrap_or(&empty)).collect();
+    let Some((first, rest)) = sets.split_first() else {
+        return RespValue::array(vec![]);
+    };
+
+    let members: Vec<&Vec<u8>> = match operation {
+        SetOperation::Inter => first
+            .iter()
+            .filter(|member| rest.iter().all(|set| set.contains(*member)))
+            .collect(),
+        SetOperation::Union => {
+            let mut union: HashSet<&Vec<u8>> = first.iter().collect();
+            for set in rest {
+                union.extend(set.iter());
+            }
+            union.into_iter().collect()
+        }
+        SetOperation::Diff => first
+            .iter()
+            .filter(|member| !rest.iter().any(|set| set.contains(*member)))
+            .collect(),
+    };
+    RespValue::array(
+        members
+            .into_iter()
+            .map(|member| RespValue::bulk(member))
+            .collect(),
+    )
+}
+
+/// Per-connection state, living for as long as the socket does.
+struct Session {
+    id: u64,
+    /// Everything written back to the client goes through here, replies and
+    /// pushed pub/sub messages alike, so they can't interleave mid-frame.
+    outgoing: UnboundedSender<Vec<u8>>,
+    /// Commands queued since MULTI, `None` outside a transaction.
+    queued: Option<Vec<Command>>,
+    /// Set when a command failed to parse while queuing, so EXEC refuses to
+    /// run a transaction with a piece missing.
+    queue_failed: bool,
+    /// The database SELECT last picked.
+    db: usize,
+    /// Keys from WATCH, with their database and the version each had at the
+    /// time. A missing key has no version, so one created and deleted again
+    /// in between slips by.
+    watched: Vec<(usize, String, Option<u64>)>,
+    /// Subscribed channels and patterns. While there are any a RESP2
+    /// connection is in subscriber mode, where only the pub/sub commands and
+    /// PING work.
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    /// Set by HELLO, and used to encode every reply after it.
+    protocol: Protocol,
+    /// What CLIENT SETNAME called the connection.
+    name: Option<String>,
+    /// Set once PSYNC has made this connection the link to a replica.
+    replica: bool,
+}
+
+impl Session {
+    fn new(state: &SharedState, outgoing: UnboundedSender<Vec<u8>>) -> Session {
+        Session {
+            id: state.last_client_id.fetch_add(1, Ordering::SeqCst) + 1,
+            outgoing,
+            queued: None,
+            queue_failed: false,
+            db: 0,
+            watched: vec![],
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            protocol: Protocol::Resp2,
+            name: None,
+            replica: false,
+        }
+    }
+
+    /// How long the connection may sit idle before it's closed. Subscribers
+    /// and replicas only wait for what gets pushed to them, so they're left be.
+    fn idle_timeout(&self, state: &SharedState) -> Option<Duration> {
+        if self.replica || !self.channels.is_empty() || !self.patterns.is_empty() {
             return None;
         }
-        while self.index < self.chars.len() {
-            let c = self.chars[self.index];
-            if c == '\r' {
-                self.index += 1;
-                continue;
+        state.config.lock().unwrap().timeout()
+    }
+
+    fn subscriber(&self) -> Subscriber {
+        Subscriber {
+            id: self.id,
+            outgoing: self.outgoing.clone(),
+            protocol: self.protocol,
+        }
+    }
+
+    /// Handles a command from this connection. Most produce one reply, but
+    /// the (un)subscribe commands confirm each channel or pattern separately.
+    /// Nothing here waits for something to happen: the commands that would
+    /// go through `handle_waiting` instead, see `waits`.
+    fn handle(&mut self, instruction: Command, state: &SharedState) -> Vec<RespValue> {
+        match instruction {
+            Command::Psync => self.start_replica(state),
+
+            Command::Hello(version) => vec![self.hello(version, state)],
+
+            Command::ClientSetName(name) => {
+                self.name = (!name.is_empty()).then_some(name);
+                if let Some(client) = state.clients.lock().unwrap().get_mut(&self.id) {
+                    client.name = self.name.clone();
+                }
+                vec![RespValue::ok()]
             }
-            if c == '\n' {
-                self.index += 1;
-                break;
+
+            Command::ClientId => vec![RespValue::Integer(self.id as i64)],
+
+            Command::ClientGetName => vec![match &self.name {
+                Some(name) => RespValue::bulk(name.as_bytes()),
+                None => RespValue::bulk(b""),
+            }],
+
+            // a replica confirming how far it's got, which wants no reply
+            Command::ReplConf(args) if is_subcommand(&args, "ack") => {
+                if let Some(offset) = args.get(1).and_then(|offset| offset.parse().ok()) {
+                    state.replication.record_ack(self.id, offset);
+                }
+                vec![]
             }
-            word.push(c);
-            self.index += 1;
+
+            Command::Subscribe(names) => self.subscribe(SubscriptionKind::Channel, names, state),
+
+            Command::PSubscribe(names) => self.subscribe(SubscriptionKind::Pattern, names, state),
+
+            Command::Unsubscribe(names) => {
+                self.unsubscribe(SubscriptionKind::Channel, names, state)
+            }
+
+            Command::PUnsubscribe(names) => {
+                self.unsubscribe(SubscriptionKind::Pattern, names, state)
+            }
+
+            // RESP3 tells pushed messages apart from replies, so there any
+            // command works while subscribed
+            Command::Ping if self.in_subscriber_mode() => vec![RespValue::array(vec![
+                RespValue::bulk(b"pong"),
+                RespValue::bulk(b""),
+            ])],
+
+            _ if self.in_subscriber_mode() => vec![RespValue::error(
+                "ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+            )],
+
+            instruction => vec![self.handle_command(instruction, state)],
         }
-        return if word.len() == 0 { None } else { Some(word) };
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Whether `instruction` waits for something to happen, so it goes to
+    /// `handle_waiting`. None do when queued in a transaction, and only WAIT
+    /// does in subscriber mode. A replica refuses pops like any other write.
+    fn waits(&self, instruction: &Command, state: &SharedState) -> bool {
+        match instruction {
+            Command::Wait(..) => self.queued.is_none(),
+            Command::BLPop(..) | Command::BRPop(..) => {
+                self.queued.is_none()
+                    && !self.in_subscriber_mode()
+                    && !state.replication.is_replica()
+            }
+            Command::DebugSleep(_) => self.queued.is_none() && !self.in_subscriber_mode(),
+            _ => false,
+        }
+    }
+
+    /// Handles a command `waits` picked out, waiting on the runtime rather
+    /// than holding up one of its threads.
+    async fn handle_waiting(
+        &mut self,
+        instruction: Command,
+        state: &SharedState,
+    ) -> Vec<RespValue> {
+        match instruction {
+            Command::BLPop(keys, timeout) => {
+                vec![
+                    self.blocking_pop(keys, timeout, Command::BLPop, state)
+                        .await,
+                ]
+            }
+            Command::BRPop(keys, timeout) => {
+                vec![
+                    self.blocking_pop(keys, timeout, Command::BRPop, state)
+                        .await,
+                ]
+            }
+            Command::Wait(wanted, timeout) => {
+                let timeout = (timeout > 0).then(|| Duration::from_millis(timeout));
+                let acked = state.replication.wait_for_acks(wanted, timeout).await;
+                vec![RespValue::Integer(acked as i64)]
+            }
+            // other connections carry on meanwhile
+            Command::DebugSleep(seconds) => {
+                time::sleep(Duration::from_secs_f64(seconds)).await;
+                vec![RespValue::ok()]
+            }
+            instruction => self.handle(instruction, state),
+        }
+    }
+
+    fn subscriptions(&mut self, kind: SubscriptionKind) -> &mut BTreeSet<String> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+        }
+    }
+
+    fn subscription_count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn in_subscriber_mode(&self) -> bool {
+        self.protocol == Protocol::Resp2 && self.subscription_count() > 0
+    }
+
+    fn subscribe(
+        &mut self,
+        kind: SubscriptionKind,
+        names: Vec<String>,
+        state: &SharedState,
+    ) -> Vec<RespValue> {
+        names
+            .into_iter()
+            .map(|name| {
+                state
+                    .pubsub
+                    .lock()
+                    .unwrap()
+                    .subscribe(kind, &name, &self.subscriber());
+                self.subscriptions(kind).insert(name.clone());
+                self.subscription_reply(kind, "subscribe", Some(&name))
+            })
+            .collect()
+    }
+
+    fn unsubscribe(
+        &mut self,
+        kind: SubscriptionKind,
+        names: Vec<String>,
+        state: &SharedState,
+    ) -> Vec<RespValue> {
+        // no names means all of them
+        let names = if names.is_empty() {
+            self.subscriptions(kind).iter().cloned().collect()
+        } else {
+            names
+        };
+        if names.is_empty() {
+            return vec![self.subscription_reply(kind, "unsubscribe", None)];
+        }
+        names
+            .into_iter()
+            .map(|name| {
+                state
+                    .pubsub
+                    .lock()
+                    .unwrap()
+                    .unsubscribe(kind, &name, self.id);
+                self.subscriptions(kind).remove(&name);
+                self.subscription_reply(kind, "unsubscribe", Some(&name))
+            })
+            .collect()
+    }
+
+    /// The confirmation for a (un)subscribe, with the number of channels and
+    /// patterns the connection is left subscribed to.
+    fn subscription_reply(
+        &self,
+        kind: SubscriptionKind,
+        action: &str,
+        name: Option<&str>,
+    ) -> RespValue {
+        let action = match kind {
+            SubscriptionKind::Channel => action.to_string(),
+            SubscriptionKind::Pattern => format!("p{}", action),
+        };
+        RespValue::Push(vec![
+            RespValue::bulk(action.as_bytes()),
+            match name {
+                Some(name) => RespValue::bulk(name.as_bytes()),
+                None => RespValue::null_bulk(),
+            },
+            RespValue::Integer(self.subscription_count() as i64),
+        ])
+    }
+
+    /// Switches protocols if asked to, and replies with a map describing the
+    /// server either way.
+    fn hello(&mut self, version: Option<u8>, state: &SharedState) -> RespValue {
+        match version {
+            None => {}
+            Some(2) => self.protocol = Protocol::Resp2,
+            Some(3) => self.protocol = Protocol::Resp3,
+            Some(_) => return RespValue::error("NOPROTO unsupported protocol version"),
+        }
+        // messages already subscribed to go out in the new protocol too
+        let mut pubsub = state.pubsub.lock().unwrap();
+        for channel in &self.channels {
+            pubsub.subscribe(SubscriptionKind::Channel, channel, &self.subscriber());
+        }
+        for pattern in &self.patterns {
+            pubsub.subscribe(SubscriptionKind::Pattern, pattern, &self.subscriber());
+        }
+        drop(pubsub);
+        let role: &[u8] = if state.replication.is_replica() {
+            b"replica"
+        } else {
+            b"master"
+        };
+        let proto = match self.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        RespValue::Map(vec![
+            (RespValue::bulk(b"server"), RespValue::bulk(b"redis")),
+            (RespValue::bulk(b"version"), RespValue::bulk(b"7.0.0")),
+            (RespValue::bulk(b"proto"), RespValue::Integer(proto)),
+            (RespValue::bulk(b"id"), RespValue::Integer(self.id as i64)),
+            (RespValue::bulk(b"mode"), RespValue::bulk(b"standalone")),
+            (RespValue::bulk(b"role"), RespValue::bulk(role)),
+            (RespValue::bulk(b"modules"), RespValue::array(vec![])),
+        ])
+    }
+
+    /// Turns this connection into a replica's link: it gets a snapshot, then
+    /// every write from there on. Commands are held off meanwhile, so the
+    /// stream picks up exactly where the snapshot ends.
+    fn start_replica(&mut self, state: &SharedState) -> Vec<RespValue> {
+        let _exclusive = state.storage.exclusive_access();
+        let snapshot = rdb::encode(&rdb::snapshot(&state.storage));
+        let replication = &state.replication;
+        let mut reply = format!(
+            "+FULLRESYNC {} {}\r\n${}\r\n",
+            replication.replid,
+            replication.offset.load(Ordering::SeqCst),
+            snapshot.len()
+        )
+        .into_bytes();
+        // the snapshot goes out like a bulk string minus the trailing CRLF,
+        // which a `RespValue` can't express, so it's written out directly
+        reply.extend(snapshot);
+        let _ = self.outgoing.send(reply);
+        // the replica starts out in database 0, whatever the others are in
+        *replication.selected_db.lock().unwrap() = None;
+        replication.replicas.lock().unwrap().push(Replica {
+            link: self.subscriber(),
+            acked_offset: replication.offset.load(Ordering::SeqCst),
+        });
+        self.replica = true;
+        vec![]
+    }
+
+    /// Drops the connection's subscriptions once it's gone, and takes it off
+    /// the list of clients.
+    fn close(&mut self, state: &SharedState) {
+        state.clients.lock().unwrap().remove(&self.id);
+        state
+            .replication
+            .replicas
+            .lock()
+            .unwrap()
+            .retain(|replica| replica.link.id != self.id);
+        let mut pubsub = state.pubsub.lock().unwrap();
+        for channel in std::mem::take(&mut self.channels) {
+            pubsub.unsubscribe(SubscriptionKind::Channel, &channel, self.id);
+        }
+        for pattern in std::mem::take(&mut self.patterns) {
+            pubsub.unsubscribe(SubscriptionKind::Pattern, &pattern, self.id);
+        }
+    }
+
+    /// Pops from the first of `keys` with anything in it, or waits for a push
+    /// to one of them. Each try runs `pop` on the keys this connection is
+    /// first in line for, so a push goes to whoever blocked earliest.
+    async fn blocking_pop(
+        &mut self,
+        keys: Vec<String>,
+        timeout: Option<Duration>,
+        pop: fn(Vec<String>, Option<Duration>) -> Command,
+        state: &SharedState,
+    ) -> RespValue {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let blocked = &state.blocked;
+        // leaves the queues however this ends, the client hanging up included
+        let _queued = blocked.block(self.id, self.db, &keys);
+        loop {
+            let (generation, turn) = blocked.turn(self.id, self.db, &keys);
+            if !turn.is_empty() {
+                let reply = task::block_in_place(|| {
+                    execute_command(pop(turn, timeout), &mut self.db, state)
+                });
+                if reply != RespValue::Array(None) {
+                    return reply;
+                }
+            }
+            if !blocked.wait(generation, deadline).await {
+                return RespValue::Array(None);
+            }
+        }
+    }
+
+    /// Runs a command, or queues it while a transaction is open.
+    fn handle_command(&mut self, instruction: Command, state: &SharedState) -> RespValue {
+        // a replica takes writes from its master alone, which skips sessions
+        let instruction =
+            if state.replication.is_replica() && instruction.propagated_args().is_some() {
+                Command::Error("READONLY You can't write against a read only replica.".to_string())
+            } else {
+                instruction
+            };
+        let instruction = if instruction.may_grow() && !make_room(&instruction, state) {
+            Command::Error(OOM_ERROR.to_string())
+        } else {
+            instruction
+        };
+        let Some(queued) = &mut self.queued else {
+            return match instruction {
+                Command::Multi => {
+                    self.queued = Some(vec![]);
+                    RespValue::ok()
+                }
+                Command::Exec => RespValue::error("ERR EXEC without MULTI"),
+                Command::Discard => RespValue::error("ERR DISCARD without MULTI"),
+                Command::Watch(keys) => {
+                    let _shared = state.storage.shared_access();
+                    let shards = state.storage.db(self.db).read_keys(&keys);
+                    for key in keys {
+                        let version = live_version(shards.shard_ref(&key), &key);
+                        self.watched.push((self.db, key, version));
+                    }
+                    RespValue::ok()
+                }
+                Command::Unwatch => {
+                    self.watched.clear();
+                    RespValue::ok()
+                }
+                instruction => execute_command(instruction, &mut self.db, state),
+            };
+        };
+
+        match instruction {
+            Command::Multi => RespValue::error("ERR MULTI calls can not be nested"),
+            Command::Exec => {
+                let queued = self.queued.take().unwrap_or_default();
+                let watched = std::mem::take(&mut self.watched);
+                if std::mem::take(&mut self.queue_failed) {
+                    return RespValue::error(
+                        "EXECABORT Transaction discarded because of previous errors.",
+                    );
+                }
+                let _exclusive = state.storage.exclusive_access();
+                let untouched = watched.iter().all(|(db, key, version)| {
+                    live_version(&state.storage.db(*db).read(key), key) == *version
+                });
+                if !untouched {
+                    return RespValue::Array(None);
+                }
+                RespValue::array(
+                    queued
+                        .into_iter()
+                        .map(|instruction| apply_command(instruction, &mut self.db, state))
+                        .collect(),
+                )
+            }
+            Command::Discard => {
+                self.queued = None;
+                self.queue_failed = false;
+                self.watched.clear();
+                RespValue::ok()
+            }
+            Command::Watch(_keys) => RespValue::error("ERR WATCH inside MULTI is not allowed"),
+            Command::Error(error) => {
+                self.queue_failed = true;
+                RespValue::Error(error)
+            }
+            Command::Unknown(name) => {
+                self.queue_failed = true;
+                apply_command(Command::Unknown(name), &mut self.db, state)
+            }
+            instruction => {
+                queued.push(instruction);
+                RespValue::Simple("QUEUED".to_string())
+            }
+        }
+    }
+}
+
+/// Evicts keys by the maxmemory policy until the keyspace would fit in
+/// maxmemory even with the most `instruction` could add. False when it's over
+/// and the policy doesn't allow evicting anything more. What the command
+/// really adds is only counted once it has run.
+fn make_room(instruction: &Command, state: &SharedState) -> bool {
+    let (limit, policy) = {
+        let config = state.config.lock().unwrap();
+        let policy = config.get("maxmemory-policy").unwrap_or_default();
+        (config.maxmemory(), policy.to_string())
+    };
+    if limit == 0 {
+        return true;
+    }
+    let written = instruction
+        .propagated_args()
+        .map_or(0, |args| memory::write_usage(&args));
+    while state.storage.used_memory() + written > limit {
+        let volatile = match policy.as_str() {
+            "allkeys-lru" => false,
+            "volatile-lru" => true,
+            _ => return false,
+        };
+        let Some((mut db, key)) = memory::pick_lru(&state.storage, volatile) else {
+            return false;
+        };
+        // propagated as a DEL, so the AOF and replicas follow
+        execute_command(Command::Evict(key), &mut db, state);
+    }
+    true
+}
+
+/// Runs a single command against database `db`, which SELECT changes. A
+/// transaction holds the storage exclusively while it applies its queued
+/// commands, and this waits for it to finish.
+fn execute_command(instruction: Command, db: &mut usize, state: &SharedState) -> RespValue {
+    let _shared = state.storage.shared_access();
+    apply_command(instruction, db, state)
+}
+
+/// Applies a command, then announces any keys it found expired.
+fn apply_command(instruction: Command, db: &mut usize, state: &SharedState) -> RespValue {
+    if let Command::Select(index) = instruction {
+        if !(0..DATABASE_COUNT as i64).contains(&index) {
+            return RespValue::error("ERR DB index is out of range");
+        }
+        *db = index as usize;
+        return RespValue::ok();
+    }
+    let db = *db;
+    let reply = propagate_command(instruction, db, state);
+    notify_expired(state, db);
+    reply
+}
+
+/// Runs a command. One that changed something is then appended to the AOF,
+/// if that's on, and sent on to any replicas.
+fn propagate_command(instruction: Command, db: usize, state: &SharedState) -> RespValue {
+    let Some(args) = instruction.propagated_args() else {
+        return dispatch_command(instruction, db, state);
+    };
+    // writes run one at a time while they're being propagated, so the AOF and
+    // the replicas get them in the same order they were applied. That costs
+    // the shards their concurrency for writes, but only while there's
+    // somewhere to send them, which is checked first.
+    let mut replicas = state.replication.replicas.lock().unwrap();
+    if state.aof.is_none() && replicas.is_empty() {
+        drop(replicas);
+        return dispatch_command(instruction, db, state);
+    }
+    // one that turned out a no-op, like DEL of a missing key, isn't sent
+    let changes = changes_made();
+    let reply = dispatch_command(instruction, db, state);
+    if changes_made() != changes {
+        let mut frame = vec![];
+        let mut selected_db = state.replication.selected_db.lock().unwrap();
+        if *selected_db != Some(db) {
+            let select = [
+                RespValue::bulk(b"SELECT"),
+                RespValue::bulk(db.to_string().as_bytes()),
+            ];
+            frame = RespValue::array(select.to_vec()).to_bytes();
+            *selected_db = Some(db);
+        }
+        let command = RespValue::array(args.iter().map(|arg| RespValue::bulk(arg)).collect());
+        frame.extend(command.to_bytes());
+        if let Some(aof) = &state.aof {
+            if let Err(error) = aof.lock().unwrap().append(&frame) {
+                eprintln!("can't write to the AOF: {}", error);
+            }
+        }
+        state.replication.propagate(&mut replicas, &frame);
+    }
+    reply
+}
+
+fn dispatch_command(instruction: Command, db: usize, state: &SharedState) -> RespValue {
+    let storage_ref = state.storage.db(db);
+    match instruction {
+        Command::Echo(message) => RespValue::bulk(&message),
+
+        Command::ListCommands => RespValue::array(
+            COMMANDS
+                .iter()
+                .map(|(name, arity, _group)| {
+                    RespValue::array(vec![
+                        RespValue::bulk(name.as_bytes()),
+                        RespValue::Integer(*arity),
+                    ])
+                })
+                .collect(),
+        ),
+
+        Command::CountCommands => RespValue::Integer(COMMANDS.len() as i64),
+
+        Command::DescribeCommands(names) => {
+            // with no names given, every command is described
+            let names: Vec<String> = if names.is_empty() {
+                COMMANDS.iter().map(|(name, ..)| name.to_string()).collect()
+            } else {
+                names.iter().map(|name| name.to_lowercase()).collect()
+            };
+            RespValue::Map(
+                names
+                    .into_iter()
+                    .filter_map(|name| {
+                        let (arity, group) = commands::lookup(&name)?;
+                        let doc = RespValue::Map(vec![
+                            (RespValue::bulk(b"group"), RespValue::bulk(group.as_bytes())),
+                            (RespValue::bulk(b"arity"), RespValue::Integer(arity)),
+                        ]);
+                        Some((RespValue::bulk(name.as_bytes()), doc))
+                    })
+                    .collect(),
+            )
+        }
+
+        Command::Info(section) => RespValue::bulk(info(state, section.as_deref()).as_bytes()),
+
+        Command::RandomKey => match storage_ref.random_key() {
+            Some(key) => RespValue::bulk(key.as_bytes()),
+            None => RespValue::null_bulk(),
+        },
+
+        Command::DbSize => {
+            let live_keys: usize = storage_ref
+                .read_all()
+                .iter()
+                .map(|storage| storage.values().filter(|entry| !entry.is_expired()).count())
+                .sum();
+            RespValue::Integer(live_keys as i64)
+        }
+
+        Command::Save => {
+            let path = state.config.lock().unwrap().dump_path();
+            match rdb::save_to_disk(&state.storage, &path) {
+                Ok(()) => RespValue::ok(),
+                Err(error) => RespValue::Error(format!("ERR {}", error)),
+            }
+        }
+
+        Command::BgSave => {
+            if state.saving.swap(true, Ordering::SeqCst) {
+                return RespValue::error("ERR Background save already in progress");
+            }
+            // copy the data now, under the locks, and write it without them
+            let snapshot = rdb::snapshot(&state.storage);
+            let path = state.config.lock().unwrap().dump_path();
+            let saving = state.saving.clone();
+            thread::spawn(move || {
+                if let Err(error) = rdb::write_snapshot(&snapshot, &path) {
+                    eprintln!("background save failed: {}", error);
+                }
+                saving.store(false, Ordering::SeqCst);
+            });
+            RespValue::Simple("Background saving started".to_string())
+        }
+
+        Command::FlushAll => {
+            for storage_ref in state.storage.iter() {
+                for storage in storage_ref.write_all().iter_mut() {
+                    storage.clear();
+                }
+            }
+            RespValue::ok()
+        }
+
+        Command::FlushDb => {
+            for storage in storage_ref.write_all().iter_mut() {
+                storage.clear();
+            }
+            RespValue::ok()
+        }
+
+        Command::ConfigGet(pattern) => {
+            let config = state.config.lock().unwrap();
+            let pairs = config
+                .matching(&pattern)
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        RespValue::bulk(name.as_bytes()),
+                        RespValue::bulk(value.as_bytes()),
+                    )
+                })
+                .collect();
+            RespValue::Map(pairs)
+        }
+
+        Command::ConfigSet(name, value) => {
+            let mut config = state.config.lock().unwrap();
+            match config.set(&name, &value) {
+                Ok(()) => {
+                    let setting = config.get("notify-keyspace-events").unwrap_or_default();
+                    state
+                        .keyspace_events
+                        .store(keyspace_event_bits(setting), Ordering::Relaxed);
+                    RespValue::ok()
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::Get(key) => {
+            let storage = storage_ref.read(&key);
+            match storage.get(&key) {
+                Some(entry) if entry.is_expired() => {
+                    // the read lock can't remove it, so come back with the
+                    // write lock, which checks again in case it was rewritten
+                    drop(storage);
+                    evict_if_expired(&mut storage_ref.write(&key), &key);
+                    RespValue::null_bulk()
+                }
+                Some(entry) => {
+                    entry.touch();
+                    match entry.as_string() {
+                        Ok(value) => RespValue::bulk(value),
+                        Err(error) => RespValue::Error(error),
+                    }
+                }
+                None => RespValue::null_bulk(),
+            }
+        }
+
+        Command::Set(key, value, options) => {
+            let mut storage = storage_ref.write(&key);
+            evict_if_expired(&mut storage, &key);
+            let existing = storage.get(&key);
+            let allowed = match options.condition {
+                Some(SetCondition::IfAbsent) => existing.is_none(),
+                Some(SetCondition::IfPresent) => existing.is_some(),
+                None => true,
+            };
+            if !allowed {
+                return RespValue::null_bulk();
+            }
+
+            let expire_timestamp = if options.keep_ttl {
+                existing.and_then(|entry| entry.expire_timestamp)
+            } else {
+                // parsing made sure it's in range
+                options.expiry.and_then(Expiry::to_unix_millis)
+            };
+            notify(state, db, '$', "set", &key);
+            storage.insert(key, StorageEntry::string(value, expire_timestamp));
+            RespValue::ok()
+        }
+
+        Command::GetSet(key, value) => {
+            let mut storage = storage_ref.write(&key);
+            let previous = match get_string(&mut storage, &key) {
+                Ok(Some(previous)) => RespValue::bulk(previous),
+                Ok(None) => RespValue::null_bulk(),
+                Err(error) => return RespValue::Error(error),
+            };
+            // the new entry has no expiry, whatever the old one had
+            notify(state, db, '$', "set", &key);
+            storage.insert(key, StorageEntry::string(value, None));
+            previous
+        }
+
+        Command::GetDel(key) => {
+            let mut storage = storage_ref.write(&key);
+            let value = match get_string(&mut storage, &key) {
+                Ok(Some(value)) => RespValue::bulk(value),
+                Ok(None) => return RespValue::null_bulk(),
+                Err(error) => return RespValue::Error(error),
+            };
+            storage.remove(&key);
+            notify(state, db, 'g', "del", &key);
+            value
+        }
+
+        Command::Append(key, suffix) => {
+            let mut storage = storage_ref.write(&key);
+            evict_if_expired(&mut storage, &key);
+            // extend in place so an existing expiry survives
+            let entry = storage
+                .entry(key)
+                .or_insert_with(|| StorageEntry::string(vec![], None));
+            match entry.as_string_mut() {
+                Ok(value) => {
+                    value.extend_from_slice(&suffix);
+                    RespValue::Integer(value.len() as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::SetRange(key, offset, patch) => {
+            if offset + patch.len() > MAX_STRING_LEN {
+                return RespValue::error(
+                    "ERR string exceeds maximum allowed size (proto-max-bulk-len)",
+                );
+            }
+            let mut storage = storage_ref.write(&key);
+            evict_if_expired(&mut storage, &key);
+            if patch.is_empty() {
+                // nothing to write, so a missing key isn't created
+                return match read_string(&storage, &key) {
+                    Ok(value) => RespValue::Integer(value.map_or(0, |value| value.len()) as i64),
+                    Err(error) => RespValue::Error(error),
+                };
+            }
+            let entry = storage
+                .entry(key)
+                .or_insert_with(|| StorageEntry::string(vec![], None));
+            match entry.as_string_mut() {
+                Ok(value) => {
+                    let end = offset + patch.len();
+                    if value.len() < end {
+                        // any gap before the offset is filled with zero bytes
+                        value.resize(end, 0);
+                    }
+                    value[offset..end].copy_from_slice(&patch);
+                    RespValue::Integer(value.len() as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::GetRange(key, start, stop) => {
+            let storage = storage_ref.read(&key);
+            match read_string(&storage, &key) {
+                Ok(Some(value)) => match resolve_range(start, stop, value.len()) {
+                    Some((start, stop)) => RespValue::bulk(&value[start..=stop]),
+                    None => RespValue::bulk(b""),
+                },
+                Ok(None) => RespValue::bulk(b""),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::SetBit(key, offset, bit) => {
+            let mut storage = storage_ref.write(&key);
+            evict_if_expired(&mut storage, &key);
+            let entry = storage
+                .entry(key)
+                .or_insert_with(|| StorageEntry::string(vec![], None));
+            match entry.as_string_mut() {
+                Ok(value) => {
+                    // bit 0 is the most significant bit of the first byte
+                    let (byte, mask) = (offset / 8, 0x80 >> (offset % 8));
+                    if value.len() <= byte {
+                        value.resize(byte + 1, 0);
+                    }
+                    let previous = value[byte] & mask != 0;
+                    if bit {
+                        value[byte] |= mask;
+                    } else {
+                        value[byte] &= !mask;
+                    }
+                    RespValue::Integer(previous as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::GetBit(key, offset) => {
+            let storage = storage_ref.read(&key);
+            match read_string(&storage, &key) {
+                Ok(value) => {
+                    let byte = value.and_then(|value| value.get(offset / 8)).copied();
+                    let bit = byte.unwrap_or(0) & (0x80 >> (offset % 8)) != 0;
+                    RespValue::Integer(bit as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::BitCount { key, range, bits } => {
+            let storage = storage_ref.read(&key);
+            let value = match read_string(&storage, &key) {
+                Ok(Some(value)) => value,
+                Ok(None) => return RespValue::Integer(0),
+                Err(error) => return RespValue::Error(error),
+            };
+            let bit_len = value.len() * 8;
+            let (start, stop) = match range {
+                None => (0, bit_len as i64 - 1),
+                Some((start, stop)) if bits => (start, stop),
+                // a byte range covers every bit of the bytes at either end
+                Some((start, stop)) => match resolve_range(start, stop, value.len()) {
+                    Some((start, stop)) => (start as i64 * 8, stop as i64 * 8 + 7),
+                    None => return RespValue::Integer(0),
+                },
+            };
+            match resolve_range(start, stop, bit_len) {
+                Some((start, stop)) => RespValue::Integer(count_bits(value, start, stop) as i64),
+                None => RespValue::Integer(0),
+            }
+        }
+
+        Command::Strlen(key) => {
+            let storage = storage_ref.read(&key);
+            // values are raw bytes, so this is the byte length rather than a
+            // count of characters
+            match read_string(&storage, &key) {
+                Ok(value) => RespValue::Integer(value.map_or(0, |value| value.len()) as i64),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::MGet(keys) => {
+            let shards = storage_ref.read_keys(&keys);
+            let values = keys
+                .iter()
+                .map(|key| match read_string(shards.shard_ref(key), key) {
+                    Ok(Some(value)) => RespValue::bulk(value),
+                    // keys holding other types read as missing
+                    _ => RespValue::null_bulk(),
+                })
+                .collect();
+            RespValue::array(values)
+        }
+
+        Command::MSet(pairs) => {
+            let mut shards = storage_ref.write_keys(pairs.iter().map(|(key, _value)| key));
+            for (key, value) in pairs {
+                notify(state, db, '$', "set", &key);
+                shards
+                    .shard(&key)
+                    .insert(key, StorageEntry::string(value, None));
+            }
+            RespValue::ok()
+        }
+
+        Command::SetNx(key, value) => {
+            let mut storage = storage_ref.write(&key);
+            evict_if_expired(&mut storage, &key);
+            let inserted = !storage.contains_key(&key);
+            if inserted {
+                notify(state, db, '$', "set", &key);
+                storage.insert(key, StorageEntry::string(value, None));
+            }
+            RespValue::Integer(inserted as i64)
+        }
+
+        Command::Del(keys) => {
+            let mut shards = storage_ref.write_keys(&keys);
+            // a key past its expiry is dropped all the same, but doesn't count
+            let removed = keys
+                .iter()
+                .filter(|key| {
+                    shards
+                        .shard(key)
+                        .remove(*key)
+                        .is_some_and(|entry| !entry.is_expired())
+                })
+                .inspect(|key| notify(state, db, 'g', "del", key))
+                .count();
+            RespValue::Integer(removed as i64)
+        }
+
+        Command::Evict(key) => {
+            let removed = storage_ref.write(&key).remove(&key).is_some();
+            if removed {
+                notify(state, db, 'e', "evicted", &key);
+            }
+            RespValue::Integer(removed as i64)
+        }
+
+        Command::Rename(key, new_key) => rename_key(storage_ref, key, new_key, false),
+
+        Command::RenameNx(key, new_key) => rename_key(storage_ref, key, new_key, true),
+
+        Command::Copy {
+            src,
+            dst,
+            db: target,
+            replace,
+        } => {
+            let target = match target {
+                None => db,
+                Some(target) if (0..DATABASE_COUNT as i64).contains(&target) => target as usize,
+                Some(_target) => return RespValue::error("ERR DB index is out of range"),
+            };
+            if target == db && src == dst {
+                return RespValue::error("ERR source and destination objects are the same");
+            }
+            // the value is cloned whole, a collection's elements and all, and
+            // gets a fresh version as a new entry
+            let copy_of = |storage: &Storage| {
+                get_live(storage, &src)
+                    .map(|entry| StorageEntry::new(entry.value.clone(), entry.expire_timestamp))
+            };
+            if target == db {
+                let mut shards = storage_ref.write_keys([&src, &dst]);
+                let copy = copy_of(shards.shard(&src));
+                copy_into(shards.shard(&dst), dst, copy, replace)
+            } else {
+                // locked in database order, the same as MOVE
+                let target_ref = state.storage.db(target);
+                let (source, mut destination) = if db < target {
+                    let source = storage_ref.write(&src);
+                    (source, target_ref.write(&dst))
+                } else {
+                    let destination = target_ref.write(&dst);
+                    (storage_ref.write(&src), destination)
+                };
+                let copy = copy_of(&source);
+                drop(source);
+                copy_into(&mut destination, dst, copy, replace)
+            }
+        }
+
+        Command::Move(key, target) => {
+            if !(0..DATABASE_COUNT as i64).contains(&target) {
+                return RespValue::error("ERR DB index is out of range");
+            }
+            let target = target as usize;
+            if target == db {
+                return RespValue::error("ERR source and destination objects are the same");
+            }
+            // the lower numbered database is locked first, so two moves going
+            // opposite ways can't deadlock
+            let target_ref = state.storage.db(target);
+            let (mut source, mut destination) = if db < target {
+                let source = storage_ref.write(&key);
+                (source, target_ref.write(&key))
+            } else {
+                let destination = target_ref.write(&key);
+                (storage_ref.write(&key), destination)
+            };
+            evict_if_expired(&mut source, &key);
+            evict_if_expired(&mut destination, &key);
+            if destination.contains_key(&key) {
+                return RespValue::Integer(0);
+            }
+            // the entry goes over whole, expiry and all
+            match source.remove(&key) {
+                Some(entry) => {
+                    destination.insert(key, entry);
+                    RespValue::Integer(1)
+                }
+                None => RespValue::Integer(0),
+            }
+        }
+
+        Command::Exists(keys) => {
+            let shards = storage_ref.read_keys(&keys);
+            let count = keys
+                .iter()
+                .filter(|key| peek_live(shards.shard_ref(key), key).is_some())
+                .count();
+            RespValue::Integer(count as i64)
+        }
+        Command::Touch(keys) => {
+            let shards = storage_ref.read_keys(&keys);
+            let count = keys
+                .iter()
+                .filter(|key| get_live(shards.shard_ref(key), key).is_some())
+                .count();
+            RespValue::Integer(count as i64)
+        }
+
+        Command::Keys(pattern) => {
+            let mut keys = vec![];
+            for storage in storage_ref.read_all().iter() {
+                keys.extend(
+                    storage
+                        .iter()
+                        .filter(|(key, entry)| {
+                            !entry.is_expired() && glob_match(pattern.as_bytes(), key.as_bytes())
+                        })
+                        .map(|(key, _entry)| RespValue::bulk(key.as_bytes())),
+                );
+            }
+            RespValue::array(keys)
+        }
+
+        Command::Scan {
+            cursor,
+            pattern,
+            count,
+        } => {
+            let (next_cursor, keys) = storage_ref.scan(cursor, count);
+            let keys = keys
+                .into_iter()
+                .filter(|key| scan_match(&pattern, key.as_bytes()))
+                .map(|key| RespValue::bulk(key.as_bytes()))
+                .collect();
+            scan_reply(next_cursor, keys)
+        }
+
+        Command::ObjectEncoding(key) => {
+            let storage = storage_ref.read(&key);
+            match peek_live(&storage, &key) {
+                Some(entry) => RespValue::bulk(entry.value.encoding_name().as_bytes()),
+                None => RespValue::error(NO_SUCH_KEY_ERROR),
+            }
+        }
+
+        // values are never shared between keys here
+        Command::ObjectRefcount(key) => {
+            let storage = storage_ref.read(&key);
+            match peek_live(&storage, &key) {
+                Some(_entry) => RespValue::Integer(1),
+                None => RespValue::error(NO_SUCH_KEY_ERROR),
+            }
+        }
+
+        Command::MemoryUsage(key) => {
+            let storage = storage_ref.read(&key);
+            match peek_live(&storage, &key) {
+                Some(entry) => RespValue::Integer(memory::entry_usage(&key, entry) as i64),
+                None => RespValue::null_bulk(),
+            }
+        }
+
+        Command::ObjectIdletime(key) => {
+            let storage = storage_ref.read(&key);
+            match peek_live(&storage, &key) {
+                Some(entry) => RespValue::Integer(entry.idle_time().as_secs() as i64),
+                None => RespValue::error(NO_SUCH_KEY_ERROR),
+            }
+        }
+
+        Command::Type(key) => {
+            let storage = storage_ref.read(&key);
+            let type_name =
+                get_live(&storage, &key).map_or("none", |entry| entry.value.type_name());
+            RespValue::Simple(type_name.to_string())
+        }
+
+        Command::LPush(key, elements) => {
+            let mut storage = storage_ref.write(&key);
+            match get_or_create_list(&mut storage, &key) {
+                Ok(list) => {
+                    // each element goes to the front in turn, so `a b c` ends
+                    // up as `c b a`
+                    for element in elements {
+                        list.push_front(element);
+                    }
+                    let len = list.len();
+                    state.blocked.key_pushed(db, &key);
+                    RespValue::Integer(len as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::RPush(key, elements) => {
+            let mut storage = storage_ref.write(&key);
+            match get_or_create_list(&mut storage, &key) {
+                Ok(list) => {
+                    list.extend(elements);
+                    let len = list.len();
+                    state.blocked.key_pushed(db, &key);
+                    RespValue::Integer(len as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::LPop(key, count) => pop_list(storage_ref, &key, count, VecDeque::pop_front),
+
+        Command::RPop(key, count) => pop_list(storage_ref, &key, count, VecDeque::pop_back),
+
+        // only blocks through `Session`, so queued in a transaction, or
+        // coming from the master or the AOF, it returns straight away
+        Command::BLPop(keys, _timeout) => pop_first(storage_ref, &keys, VecDeque::pop_front),
+
+        Command::BRPop(keys, _timeout) => pop_first(storage_ref, &keys, VecDeque::pop_back),
+
+        Command::LMove(source, destination, from, to) => {
+            move_element(state, db, &source, &destination, from, to)
+        }
+
+        Command::LRange(key, start, stop) => {
+            let storage = storage_ref.read(&key);
+            match read_list(&storage, &key) {
+                Ok(Some(list)) => match resolve_range(start, stop, list.len()) {
+                    Some((start, stop)) => RespValue::array(
+                        list.range(start..=stop)
+                            .map(|element| RespValue::bulk(element))
+                            .collect(),
+                    ),
+                    None => RespValue::array(vec![]),
+                },
+                Ok(None) => RespValue::array(vec![]),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::LLen(key) => {
+            let storage = storage_ref.read(&key);
+            match read_list(&storage, &key) {
+                Ok(list) => RespValue::Integer(list.map_or(0, |list| list.len()) as i64),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::LIndex(key, index) => {
+            let storage = storage_ref.read(&key);
+            match read_list(&storage, &key) {
+                Ok(Some(list)) => match resolve_index(index, list.len()) {
+                    Some(index) => RespValue::bulk(&list[index]),
+                    None => RespValue::null_bulk(),
+                },
+                Ok(None) => RespValue::null_bulk(),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::LSet(key, index, element) => {
+            let mut storage = storage_ref.write(&key);
+            match get_list_mut(&mut storage, &key) {
+                Ok(Some(list)) => match resolve_index(index, list.len()) {
+                    Some(index) => {
+                        list[index] = element;
+                        RespValue::ok()
+                    }
+                    None => RespValue::error("ERR index out of range"),
+                },
+                Ok(None) => RespValue::error(NO_SUCH_KEY_ERROR),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::LRem(key, count, element) => {
+            let mut storage = storage_ref.write(&key);
+            let list = match get_list_mut(&mut storage, &key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return RespValue::Integer(0),
+                Err(error) => return RespValue::Error(error),
+            };
+            let limit = match count {
+                0 => usize::MAX,
+                count => count.unsigned_abs() as usize,
+            };
+            // a negative count removes from the tail, so the list is walked
+            // backwards by turning it around for the pass
+            if count < 0 {
+                list.make_contiguous().reverse();
+            }
+            let mut removed = 0;
+            list.retain(|item| {
+                let remove = removed < limit && *item == element;
+                removed += remove as usize;
+                !remove
+            });
+            if count < 0 {
+                list.make_contiguous().reverse();
+            }
+            remove_if_empty(&mut storage, &key);
+            RespValue::Integer(removed as i64)
+        }
+
+        Command::LPos {
+            key,
+            element,
+            rank,
+            count,
+            max_len,
+        } => {
+            let storage = storage_ref.read(&key);
+            let list = match read_list(&storage, &key) {
+                Ok(Some(list)) => list,
+                Ok(None) if count.is_some() => return RespValue::array(vec![]),
+                Ok(None) => return RespValue::null_bulk(),
+                Err(error) => return RespValue::Error(error),
+            };
+            let scanned = match max_len {
+                0 => list.len(),
+                max_len => max_len.min(list.len()),
+            };
+            let indices: Box<dyn Iterator<Item = usize>> = if rank > 0 {
+                Box::new(0..scanned)
+            } else {
+                Box::new((list.len() - scanned..list.len()).rev())
+            };
+            let mut matches = indices
+                .filter(|index| list[*index] == element)
+                .skip(rank.unsigned_abs() as usize - 1);
+            match count {
+                Some(count) => {
+                    let count = if count == 0 { usize::MAX } else { count };
+                    RespValue::array(
+                        matches
+                            .take(count)
+                            .map(|index| RespValue::Integer(index as i64))
+                            .collect(),
+                    )
+                }
+                None => match matches.next() {
+                    Some(index) => RespValue::Integer(index as i64),
+                    None => RespValue::null_bulk(),
+                },
+            }
+        }
+
+        Command::LTrim(key, start, stop) => {
+            let mut storage = storage_ref.write(&key);
+            match get_list_mut(&mut storage, &key) {
+                Ok(Some(list)) => match resolve_range(start, stop, list.len()) {
+                    Some((start, stop)) => {
+                        list.truncate(stop + 1);
+                        list.drain(..start);
+                    }
+                    None => list.clear(),
+                },
+                Ok(None) => {}
+                Err(error) => return RespValue::Error(error),
+            }
+            remove_if_empty(&mut storage, &key);
+            RespValue::ok()
+        }
+
+        Command::HSet(key, pairs) => {
+            let mut storage = storage_ref.write(&key);
+            match get_or_create_hash(&mut storage, &key) {
+                Ok(hash) => {
+                    // overwriting an existing field doesn't count as new
+                    let created = pairs
+                        .into_iter()
+                        .map(|(field, value)| hash.insert(field, value))
+                        .filter(Option::is_none)
+                        .count();
+                    RespValue::Integer(created as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::HGet(key, field) => {
+            let storage = storage_ref.read(&key);
+            match read_hash(&storage, &key) {
+                Ok(hash) => match hash.and_then(|hash| hash.get(&field)) {
+                    Some(value) => RespValue::bulk(value),
+                    None => RespValue::null_bulk(),
+                },
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::HGetAll(key) => {
+            let storage = storage_ref.read(&key);
+            match read_hash(&storage, &key) {
+                // fields come out in HashMap order, which isn't stable, but
+                // each field is always directly followed by its value
+                Ok(Some(hash)) => RespValue::Map(
+                    hash.iter()
+                        .map(|(field, value)| (RespValue::bulk(field), RespValue::bulk(value)))
+                        .collect(),
+                ),
+                Ok(None) => RespValue::Map(vec![]),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::HScan {
+            key,
+            cursor,
+            pattern,
+            count,
+        } => {
+            let storage = storage_ref.read(&key);
+            match read_hash(&storage, &key) {
+                Ok(Some(hash)) => {
+                    let (next_cursor, pairs) = scan_elements(
+                        hash.iter(),
+                        |(field, _value)| stable_hash(*field),
+                        cursor,
+                        count,
+                    );
+                    let elements = pairs
+                        .into_iter()
+                        .filter(|(field, _value)| scan_match(&pattern, field))
+                        .flat_map(|(field, value)| [RespValue::bulk(field), RespValue::bulk(value)])
+                        .collect();
+                    scan_reply(next_cursor, elements)
+                }
+                Ok(None) => scan_reply(0, vec![]),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::HDel(key, fields) => {
+            let mut storage = storage_ref.write(&key);
+            let removed = match get_hash_mut(&mut storage, &key) {
+                Ok(Some(hash)) => fields
+                    .iter()
+                    .filter(|field| hash.remove(*field).is_some())
+                    .count(),
+                Ok(None) => 0,
+                Err(error) => return RespValue::Error(error),
+            };
+            remove_if_empty(&mut storage, &key);
+            RespValue::Integer(removed as i64)
+        }
+
+        Command::HExists(key, field) => {
+            let storage = storage_ref.read(&key);
+            match read_hash(&storage, &key) {
+                Ok(hash) => {
+                    RespValue::Integer(hash.is_some_and(|hash| hash.contains_key(&field)) as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::HLen(key) => {
+            let storage = storage_ref.read(&key);
+            match read_hash(&storage, &key) {
+                Ok(hash) => RespValue::Integer(hash.map_or(0, |hash| hash.len()) as i64),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::HIncrBy(key, field, delta) => {
+            let mut storage = storage_ref.write(&key);
+            let hash = match get_or_create_hash(&mut storage, &key) {
+                Ok(hash) => hash,
+                Err(error) => return RespValue::Error(error),
+            };
+            let value = hash.entry(field).or_insert_with(|| b"0".to_vec());
+            let current = match std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+            {
+                Some(current) => current,
+                None => return RespValue::error("ERR hash value is not an integer"),
+            };
+            match current.checked_add(delta) {
+                Some(next) => {
+                    *value = next.to_string().into_bytes();
+                    RespValue::Integer(next)
+                }
+                None => RespValue::error(OVERFLOW_ERROR),
+            }
+        }
+
+        Command::SAdd(key, members) => {
+            let mut storage = storage_ref.write(&key);
+            match get_or_create_set(&mut storage, &key) {
+                Ok(set) => {
+                    let added = members
+                        .into_iter()
+                        .map(|member| set.insert(member))
+                        .filter(|added| *added)
+                        .count();
+                    RespValue::Integer(added as i64)
+                }
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::SRem(key, members) => {
+            let mut storage = storage_ref.write(&key);
+            let removed = match get_set_mut(&mut storage, &key) {
+                Ok(Some(set)) => members.iter().filter(|member| set.remove(*member)).count(),
+                Ok(None) => 0,
+                Err(error) => return RespValue::Error(error),
+            };
+            remove_if_empty(&mut storage, &key);
+            RespValue::Integer(removed as i64)
+        }
+
+        Command::SIsMember(key, member) => {
+            let storage = storage_ref.read(&key);
+            match read_set(&storage, &key) {
+                Ok(set) => RespValue::Integer(set.is_some_and(|set| set.contains(&member)) as i64),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::SMembers(key) => {
+            let storage = storage_ref.read(&key);
+            match read_set(&storage, &key) {
+                Ok(Some(set)) => {
+                    RespValue::array(set.iter().map(|member| RespValue::bulk(member)).collect())
+                }
+                Ok(None) => RespValue::array(vec![]),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::SScan {
+            key,
+            cursor,
+            pattern,
+            count,
+        } => {
+            let storage = storage_ref.read(&key);
+            match read_set(&storage, &key) {
+                Ok(Some(set)) => {
+                    let (next_cursor, members) =
+                        scan_elements(set.iter(), |member| stable_hash(*member), cursor, count);
+                    let elements = members
+                        .into_iter()
+                        .filter(|member| scan_match(&pattern, member))
+                        .map(|member| RespValue::bulk(member))
+                        .collect();
+                    scan_reply(next_cursor, elements)
+                }
+                Ok(None) => scan_reply(0, vec![]),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::ZScan {
+            key,
+            cursor,
+            pattern,
+            count,
+        } => {
+            let storage = storage_ref.read(&key);
+            match read_zset(&storage, &key) {
+                Ok(Some(zset)) => {
+                    let (next_cursor, members) = scan_elements(
+                        zset.iter(),
+                        |(member, _score)| stable_hash(*member),
+                        cursor,
+                        count,
+                    );
+                    let elements = members
+                        .into_iter()
+                        .filter(|(member, _score)| scan_match(&pattern, member))
+                        .flat_map(|(member, score)| {
+                            [
+                                RespValue::bulk(member),
+                                RespValue::bulk(score.to_string().as_bytes()),
+                            ]
+                        })
+                        .collect();
+                    scan_reply(next_cursor, elements)
+                }
+                Ok(None) => scan_reply(0, vec![]),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::SCard(key) => {
+            let storage = storage_ref.read(&key);
+            match read_set(&storage, &key) {
+                Ok(set) => RespValue::Integer(set.map_or(0, |set| set.len()) as i64),
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::SInter(keys) => combine_sets(storage_ref, &keys, SetOperation::Inter),
+
+        Command::SUnion(keys) => combine_sets(storage_ref, &keys, SetOperation::Union),
+
+        Command::SDiff(keys) => combine_sets(storage_ref, &keys, SetOperation::Diff),
+
+        Command::ZAdd(key, options, members) => {
+            let mut storage = storage_ref.write(&key);
+            let zset = match get_or_create_zset(&mut storage, &key) {
+                Ok(zset) => zset,
+                Err(error) => return RespValue::Error(error),
+            };
+            let (mut added, mut changed) = (0, 0);
+            for (score, member) in members {
+                match zset.score(&member) {
+                    None => {
+                        if options.condition != Some(SetCondition::IfPresent) {
+                            zset.insert(member, score);
+                            added += 1;
+                        }
+                    }
+                    Some(current) => {
+                        let allowed = options.condition != Some(SetCondition::IfAbsent)
+                            && options
+                                .comparison
+                                .is_none_or(|wanted| score.partial_cmp(&current) == Some(wanted));
+                        if allowed && score != current {
+                            zset.insert(member, score);
+                            changed += 1;
+                        }
+                    }
+                }
+            }
+            // XX on a missing key leaves nothing behind
+            remove_if_empty(&mut storage, &key);
+            RespValue::Integer(if options.changed {
+                added + changed
+            } else {
+                added
+            })
+        }
+
+        Command::ZRange {
+            key,
+            start,
+            stop,
+            with_scores,
+            rev,
+        } => {
+            let storage = storage_ref.read(&key);
+            let zset = match read_zset(&storage, &key) {
+                Ok(Some(zset)) => zset,
+                Ok(None) => return RespValue::array(vec![]),
+                Err(error) => return RespValue::Error(error),
+            };
+            let Some((start, stop)) = resolve_range(start, stop, zset.len()) else {
+                return RespValue::array(vec![]);
+            };
+            let members: Vec<(&[u8], f64)> = if rev {
+                zset.iter()
+                    .rev()
+                    .skip(start)
+                    .take(stop - start + 1)
+                    .collect()
+            } else {
+                zset.iter().skip(start).take(stop - start + 1).collect()
+            };
+            let mut elements = vec![];
+            for (member, score) in members {
+                elements.push(RespValue::bulk(member));
+                if with_scores {
+                    elements.push(RespValue::Double(score));
+                }
+            }
+            RespValue::array(elements)
+        }
+
+        Command::ZIncrBy(key, delta, member) => {
+            let mut storage = storage_ref.write(&key);
+            let zset = match get_or_create_zset(&mut storage, &key) {
+                Ok(zset) => zset,
+                Err(error) => return RespValue::Error(error),
+            };
+            let score = zset.score(&member).unwrap_or(0.0) + delta;
+            if score.is_nan() {
+                // only adding opposite infinities gets here
+                remove_if_empty(&mut storage, &key);
+                return RespValue::error("ERR resulting score is not a number (NaN)");
+            }
+            zset.insert(member, score);
+            RespValue::Double(score)
+        }
+
+        Command::ZRank(key, member) => rank_reply(storage_ref, &key, &member, false),
+
+        Command::ZRevRank(key, member) => rank_reply(storage_ref, &key, &member, true),
+
+        Command::ZScore(key, member) => {
+            let storage = storage_ref.read(&key);
+            match read_zset(&storage, &key) {
+                Ok(zset) => match zset.and_then(|zset| zset.score(&member)) {
+                    Some(score) => RespValue::Double(score),
+                    None => RespValue::null_bulk(),
+                },
+                Err(error) => RespValue::Error(error),
+            }
+        }
+
+        Command::Incr(key) => delta_reply(storage_ref, &key, Ok(1)),
+
+        Command::Decr(key) => delta_reply(storage_ref, &key, Ok(-1)),
+
+        Command::IncrBy(key, delta) => delta_reply(storage_ref, &key, Ok(delta)),
+
+        Command::DecrBy(key, delta) => delta_reply(
+            storage_ref,
+            &key,
+            delta
+                .checked_neg()
+                .ok_or_else(|| OVERFLOW_ERROR.to_string()),
+        ),
+
+        Command::Expire(key, seconds, condition) => set_expiry(
+            state,
+            db,
+            "expire",
+            &key,
+            Expiry::After(Duration::from_secs(seconds)),
+            condition,
+        ),
+
+        Command::Pexpire(key, millis, condition) => set_expiry(
+            state,
+            db,
+            "pexpire",
+            &key,
+            Expiry::After(Duration::from_millis(millis)),
+            condition,
+        ),
+
+        Command::ExpireAt(key, seconds, condition) => set_expiry(
+            state,
+            db,
+            "expireat",
+            &key,
+            Expiry::AtUnixMillis(seconds.saturating_mul(1000)),
+            condition,
+        ),
+
+        Command::PexpireAt(key, millis, condition) => set_expiry(
+            state,
+            db,
+            "pexpireat",
+            &key,
+            Expiry::AtUnixMillis(millis),
+            condition,
+        ),
+
+        Command::Persist(key) => {
+            let mut storage = storage_ref.write(&key);
+            evict_if_expired(&mut storage, &key);
+            let removed = match storage.get_mut(&key) {
+                Some(entry) if entry.expire_timestamp.is_some() => {
+                    entry.expire_timestamp = None;
+                    entry.mark_changed();
+                    true
+                }
+                Some(_entry) => false,
+                None => false,
+            };
+            RespValue::Integer(removed as i64)
+        }
+
+        Command::Ttl(key) => {
+            let ttl = match remaining_ttl_millis(storage_ref, &key) {
+                millis if millis < 0 => millis,
+                // round to the nearest second, like Redis does
+                millis => (millis + 500) / 1000,
+            };
+            RespValue::Integer(ttl as i64)
+        }
+
+        Command::Pttl(key) => {
+            let ttl = remaining_ttl_millis(storage_ref, &key);
+            RespValue::Integer(ttl as i64)
+        }
+
+        Command::ExpireTime(key) => {
+            let at = match unix_expiry_millis(storage_ref, &key) {
+                millis if millis < 0 => millis,
+                millis => (millis + 500) / 1000,
+            };
+            RespValue::Integer(at as i64)
+        }
+
+        Command::PexpireTime(key) => {
+            let at = unix_expiry_millis(storage_ref, &key);
+            RespValue::Integer(at as i64)
+        }
+
+        Command::Error(error) => RespValue::Error(error),
+
+        // these change the state of a connection, which only a `Session` has
+        Command::Multi
+        | Command::Exec
+        | Command::Discard
+        | Command::Watch(_)
+        | Command::Subscribe(_)
+        | Command::Unsubscribe(_)
+        | Command::PSubscribe(_)
+        | Command::PUnsubscribe(_)
+        | Command::Psync
+        | Command::Hello(_)
+        | Command::ClientSetName(_)
+        | Command::ClientGetName
+        | Command::ClientId
+        | Command::Select(_) => RespValue::error("ERR command not allowed here"),
+
+        // only reached inside a transaction, which has the keyspace to
+        // itself, so everyone waits as they would in Redis
+        Command::DebugSleep(seconds) => {
+            thread::sleep(Duration::from_secs_f64(seconds));
+            RespValue::ok()
+        }
+
+        Command::DebugObject(key) => {
+            let storage = storage_ref.read(&key);
+            let Some(entry) = peek_live(&storage, &key) else {
+                return RespValue::error(NO_SUCH_KEY_ERROR);
+            };
+            let ttl = entry
+                .expire_timestamp
+                .map_or(-1, |expiry| expiry.saturating_sub(unix_millis_now()) as i64);
+            let description = format!(
+                "type:{} encoding:{} serializedlength:{} ttl_ms:{}",
+                entry.value.type_name(),
+                entry.value.encoding_name(),
+                rdb::serialized_len(&entry.value),
+                ttl
+            );
+            RespValue::bulk(description.as_bytes())
+        }
+
+        Command::Debug(args) => {
+            let subcommand = args[0].to_lowercase();
+            if IGNORED_DEBUG_SUBCOMMANDS.contains(&subcommand.as_str()) {
+                RespValue::ok()
+            } else {
+                RespValue::Error(format!(
+                    "ERR unknown subcommand '{}' for 'debug', try SLEEP or OBJECT",
+                    args[0]
+                ))
+            }
+        }
+
+        Command::ClientList => {
+            let clients = state.clients.lock().unwrap();
+            let mut ids: Vec<&u64> = clients.keys().collect();
+            ids.sort();
+            let lines: String = ids
+                .into_iter()
+                .map(|id| {
+                    let client = &clients[id];
+                    format!(
+                        "id={} addr={} name={} age={}\n",
+                        id,
+                        client.addr,
+                        client.name.as_deref().unwrap_or_default(),
+                        client.connected_at.elapsed().as_secs()
+                    )
+                })
+                .collect();
+            RespValue::bulk(lines.as_bytes())
+        }
+
+        Command::Publish(channel, message) => {
+            let received = state.pubsub.lock().unwrap().publish(&channel, &message);
+            RespValue::Integer(received as i64)
+        }
+
+        // queued inside MULTI, where EXEC drops the watched keys anyway
+        Command::Unwatch => RespValue::ok(),
+
+        // what a replica says about itself in the handshake isn't used
+        Command::ReplConf(_args) => RespValue::ok(),
+
+        Command::Wait(_wanted, _timeout) => {
+            RespValue::Integer(state.replication.acked_replicas() as i64)
+        }
+
+        Command::Unknown(name) => RespValue::Error(format!("ERR unknown command '{}'", name)),
+
+        Command::Ping => RespValue::Simple("PONG".to_string()),
+    }
+}
+
+/// A connection's session, counted among the connected clients for as long
+/// as this lives. Dropping it takes the connection off the books, also when
+/// its task panics on the way out.
+struct Connection<'a> {
+    session: Session,
+    state: &'a SharedState,
+}
+
+impl Connection<'_> {
+    fn register<'a>(
+        state: &'a SharedState,
+        outgoing: UnboundedSender<Vec<u8>>,
+        addr: String,
+    ) -> Connection<'a> {
+        let session = Session::new(state, outgoing);
+        let client = ClientInfo {
+            addr,
+            name: None,
+            connected_at: Instant::now(),
+        };
+        state.clients.lock().unwrap().insert(session.id, client);
+        state.connected_clients.fetch_add(1, Ordering::SeqCst);
+        Connection { session, state }
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.session.close(self.state);
+        self.state.connected_clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts connections for as long as the server runs, each served by a
+/// task of its own.
+async fn serve(listener: TcpListener, state: SharedState) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _addr)) => {
+                tokio::spawn(handle_stream(stream, state.clone()));
+            }
+            Err(e) => {
+                eprintln!("can't accept a connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Reports a connection failing, unless it's only the client hanging up or
+/// stalling mid-reply, which is nothing to note.
+fn report_connection_error(addr: &str, error: &io::Error) {
+    if !matches!(
+        error.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::TimedOut
+    ) {
+        eprintln!("connection to {} failed: {}", addr, error);
+    }
+}
+
+async fn handle_stream(stream: TcpStream, state: SharedState) {
+    let addr = stream
+        .peer_addr()
+        .map_or_else(|_e| "?".to_string(), |addr| addr.to_string());
+    // a subscribed connection gets messages pushed to it while this task
+    // waits on the socket, so writing happens in a task of its own
+    let (mut reader, mut writer) = stream.into_split();
+    let (outgoing, mut incoming) = mpsc::unbounded_channel::<Vec<u8>>();
+    let config = state.config.clone();
+    let writer_addr = addr.clone();
+    let writer_task = tokio::spawn(async move {
+        while let Some(bytes) = incoming.recv().await {
+            let write_timeout = config.lock().unwrap().timeout();
+            let written = match write_timeout {
+                Some(write_timeout) => time::timeout(write_timeout, writer.write_all(&bytes))
+                    .await
+                    .unwrap_or_else(|_elapsed| Err(ErrorKind::TimedOut.into())),
+                None => writer.write_all(&bytes).await,
+            };
+            if let Err(e) = written {
+                report_connection_error(&writer_addr, &e);
+                break;
+            }
+        }
+    });
+
+    let mut connection = Connection::register(&state, outgoing, addr.clone());
+    let session = &mut connection.session;
+    let mut buffer: Vec<u8> = vec![];
+    'connection: loop {
+        // what arrived while a command waited may hold whole frames already
+        if complete_frames_len(&buffer) == 0 {
+            let idle_timeout = session.idle_timeout(&state);
+            match read_more(&session.outgoing, idle_timeout, &mut reader, &mut buffer).await {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    report_connection_error(&addr, &e);
+                    break;
+                }
+            }
+        }
+        // a single read may end part way through a frame, e.g. a large SET
+        // value, so only whole frames are taken and the rest waits for more
+        let frames_len = complete_frames_len(&buffer);
+        if frames_len == 0 {
+            continue;
+        }
+        let message: Vec<u8> = buffer.drain(..frames_len).collect();
+        let instructions = match handle_client_message(&message) {
+            Ok(instructions) => instructions,
+            Err(error) => {
+                let reply = RespValue::Error(format!("ERR Protocol error: {}", error));
+                let _ = session.outgoing.send(reply.to_bytes());
+                break;
+            }
+        };
+
+        // commands take locks, so they run where blocking doesn't hold up the
+        // tasks of other connections, each run of them in one go. The ones
+        // that wait for something (BLPOP, WAIT) wait on the runtime instead.
+        // Pipelined commands get their replies in order, written in one go
+        let mut replies = vec![];
+        let mut instructions = instructions.into_iter().peekable();
+        while instructions.peek().is_some() {
+            task::block_in_place(|| {
+                while let Some(instruction) =
+                    instructions.next_if(|instruction| !session.waits(instruction, &state))
+                {
+                    for reply in session.handle(instruction, &state) {
+                        reply.encode_as(session.protocol, &mut replies);
+                    }
+                }
+            });
+            let Some(instruction) = instructions.next() else {
+                continue;
+            };
+            match handle_waiting_while_connected(
+                session,
+                instruction,
+                &state,
+                &mut reader,
+                &mut buffer,
+            )
+            .await
+            {
+                Ok(Some(handled)) => {
+                    for reply in handled {
+                        reply.encode_as(session.protocol, &mut replies);
+                    }
+                }
+                Ok(None) => break 'connection,
+                Err(e) => {
+                    report_connection_error(&addr, &e);
+                    break 'connection;
+                }
+            }
+        }
+        // this only fails once the writer has given up on the socket
+        if session.outgoing.send(replies).is_err() {
+            break;
+        }
+    }
+
+    // the writer stops once the last sender is gone, after flushing what's queued
+    drop(connection);
+    let _ = writer_task.await;
+}
+
+/// Reads what the client sends next onto the end of `buffer`. `Ok(false)`
+/// means the connection is done with: the client hung up, the writer gave up
+/// on the socket, or the client stayed quiet past `idle_timeout`.
+async fn read_more(
+    outgoing: &UnboundedSender<Vec<u8>>,
+    idle_timeout: Option<Duration>,
+    reader: &mut OwnedReadHalf,
+    buffer: &mut Vec<u8>,
+) -> io::Result<bool> {
+    let mut chunk = [0_u8; 1024];
+    let read_bytes = tokio::select! {
+        read = reader.read(&mut chunk) => read?,
+        // the writer gave up on the socket, so nobody is left to answer
+        _ = outgoing.closed() => return Ok(false),
+        // a client that's gone quiet for too long is let go like one that left
+        _ = time::sleep(idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => return Ok(false),
+    };
+    buffer.extend_from_slice(&chunk[..read_bytes]);
+    Ok(read_bytes > 0)
+}
+
+/// Runs a command that waits, watching the connection meanwhile as if it
+/// were idle. If the connection is done with first, the command is given up
+/// on, a blocked pop taking nothing, and `Ok(None)` returned. Whatever the
+/// client sends in the meantime is kept in `buffer` for afterwards.
+async fn handle_waiting_while_connected(
+    session: &mut Session,
+    instruction: Command,
+    state: &SharedState,
+    reader: &mut OwnedReadHalf,
+    buffer: &mut Vec<u8>,
+) -> io::Result<Option<Vec<RespValue>>> {
+    let outgoing = session.outgoing.clone();
+    let idle_timeout = session.idle_timeout(state);
+    let handled = session.handle_waiting(instruction, state);
+    tokio::pin!(handled);
+    loop {
+        tokio::select! {
+            replies = &mut handled => return Ok(Some(replies)),
+            connected = read_more(&outgoing, idle_timeout, reader, buffer) => {
+                if !connected? {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// Returns how many bytes at the start of `buffer` make up whole frames, so
+/// that a command split across several reads isn't handed to the parser
+/// half-way through; the rest stays buffered for the next read. Inline
+/// commands are complete once their line is. Anything else that doesn't look
+/// like an array of bulk strings is handed over whole for the parser to
+/// reject.
+fn complete_frames_len(buffer: &[u8]) -> usize {
+    let mut frames_end = 0;
+    while let Some(len) = frame_len(&buffer[frames_end..]) {
+        frames_end += len;
+    }
+    frames_end
+}
+
+/// The length of the first frame in `buffer`, or `None` until all of it has
+/// arrived. Malformed input counts as one frame running to the end.
+fn frame_len(buffer: &[u8]) -> Option<usize> {
+    fn read_header(buffer: &[u8], index: &mut usize, kind: u8) -> Option<Option<usize>> {
+        if *index >= buffer.len() {
+            return None;
+        }
+        if buffer[*index] != kind {
+            return Some(None);
+        }
+        let line_end = buffer[*index..]
+            .windows(SEPARATOR.len())
+            .position(|window| window == SEPARATOR.as_bytes())?;
+        let header = std::str::from_utf8(&buffer[*index + 1..*index + line_end]).ok();
+        *index += line_end + SEPARATOR.len();
+        Some(header.and_then(|header| header.parse::<usize>().ok()))
+    }
+
+    if buffer.is_empty() {
+        return None;
+    }
+    if buffer[0] != b'*' {
+        return buffer
+            .iter()
+            .position(|&c| c == b'\n')
+            .map(|line_end| line_end + 1);
+    }
+    let mut index = 0;
+    let items = match read_header(buffer, &mut index, b'*')? {
+        Some(items) => items,
+        None => return Some(buffer.len()),
+    };
+    for _ in 0..items {
+        // a length past the cap is rejected rather than waited for
+        let length = match read_header(buffer, &mut index, b'$')? {
+            Some(length) if length <= MAX_BULK_LEN => length,
+            _ => return Some(buffer.len()),
+        };
+        index = index.saturating_add(length).saturating_add(SEPARATOR.len());
+        if index > buffer.len() {
+            return None;
+        }
+    }
+    Some(index)
+}
+
+/// Raised when a client sends bytes that aren't valid RESP. The connection
+/// can't tell where the next frame starts after one of these, so it replies
+/// and hangs up, like Redis does.
+#[derive(Debug, PartialEq, thiserror::Error)]
+enum ParseError {
+    #[error("expected '{expected}', got '{found}'")]
+    UnexpectedType { expected: char, found: String },
+    #[error("invalid multibulk length")]
+    InvalidArrayLength,
+    #[error("invalid bulk length")]
+    InvalidBulkLength,
+}
+
+enum State {
+    Array,
+    BulkStringLength,
+    BulkStringContent,
+}
+
+fn handle_client_message(message: &[u8]) -> Result<Vec<Command>, ParseError> {
+    let mut state = State::Array;
+    let mut roller = CharRoller::from_bytes(message);
+    let mut args: Vec<Vec<u8>> = vec![];
+    let mut instructions: Vec<Command> = vec![];
+    let mut items_left_count = 0;
+    let mut bulk_string_length = 0;
+
+    loop {
+        match state {
+            State::Array => {
+                let Some(raw_word) = roller.next_word() else {
+                    break;
+                };
+                let word = raw_word.trim();
+                if word.chars().next().and_then(get_instruction_type) != Some(MessageType::Array) {
+                    // an inline command, as typed into telnet: the line split on
+                    // whitespace. A blank line splits into nothing and is skipped
+                    let args = word
+                        .split_whitespace()
+                        .map(|arg| arg.as_bytes().to_vec())
+                        .collect();
+                    if let Some(instruction) = parse_command(args) {
+                        instructions.push(instruction);
+                    }
+                    continue;
+                }
+                let array_length = word[1..]
+                    .parse::<usize>()
+                    .map_err(|_e| ParseError::InvalidArrayLength)?;
+                // an empty array carries no command, so just move on to the next one
+                if array_length == 0 {
+                    continue;
+                }
+                items_left_count = array_length;
+                state = State::BulkStringLength;
+            }
+
+            State::BulkStringLength => {
+                let Some(raw_word) = roller.next_word() else {
+                    break;
+                };
+                let word = raw_word.trim();
+                expect_type(word, MessageType::BulkString, '$')?;
+                bulk_string_length = word[1..]
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|length| *length <= MAX_BULK_LEN)
+                    .ok_or(ParseError::InvalidBulkLength)?;
+                state = State::BulkStringContent;
+            }
+
+            State::BulkStringContent => {
+                // the length prefix tells us exactly how many bytes to take, so
+                // values may contain anything, including `\r\n`
+                let Some(content) = roller.next_bytes(bulk_string_length) else {
+                    break;
+                };
+                args.push(content);
+
+                if items_left_count != 1 {
+                    state = State::BulkStringLength;
+                    items_left_count -= 1;
+                    continue;
+                }
+
+                if let Some(instruction) = parse_command(std::mem::take(&mut args)) {
+                    instructions.push(instruction);
+                }
+                state = State::Array;
+            }
+        }
+    }
+
+    Ok(instructions)
+}
+
+/// Checks that a header line starts with the type byte the parser expects.
+fn expect_type(word: &str, expected_type: MessageType, expected: char) -> Result<(), ParseError> {
+    let found = word.chars().next();
+    if found.and_then(get_instruction_type) == Some(expected_type) {
+        Ok(())
+    } else {
+        Err(ParseError::UnexpectedType {
+            expected,
+            found: found.map(String::from).unwrap_or_default(),
+        })
+    }
+}
+
+/// Lossily turns an argument into text, for keys and option names.
+fn arg_string(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).to_string()
+}
+
+fn parse_integer(arg: &[u8]) -> Result<i64, String> {
+    arg_string(arg)
+        .parse::<i64>()
+        .map_err(|_e| NOT_AN_INTEGER_ERROR.to_string())
+}
+
+fn parse_command(args: Vec<Vec<u8>>) -> Option<Command> {
+    let (raw_name, args) = args.split_first()?;
+    let command_name = arg_string(raw_name).to_lowercase();
+    // past this check every command can count on the arguments it indexes
+    if let Some((arity, _group)) = commands::lookup(&command_name) {
+        if !commands::arity_allows(arity, args.len() + 1) {
+            return Some(Command::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                command_name
+            )));
+        }
+    }
+
+    let instruction = match command_name.as_str() {
+        "ping" => Command::Ping,
+
+        "echo" => Command::Echo(args.join(&b' ')),
+
+        "get" => Command::Get(arg_string(&args[0])),
+
+        "set" => match parse_set_options(&args[2..]) {
+            Ok(options) => Command::Set(arg_string(&args[0]), args[1].to_vec(), options),
+            Err(error) => Command::Error(error),
+        },
+
+        "getset" => Command::GetSet(arg_string(&args[0]), args[1].to_vec()),
+
+        "getdel" => Command::GetDel(arg_string(&args[0])),
+
+        "append" => Command::Append(arg_string(&args[0]), args[1].to_vec()),
+
+        "strlen" => Command::Strlen(arg_string(&args[0])),
+
+        "setrange" => match parse_integer(&args[1]) {
+            Ok(offset) if offset >= 0 => {
+                Command::SetRange(arg_string(&args[0]), offset as usize, args[2].to_vec())
+            }
+            Ok(_offset) => Command::Error("ERR offset is out of range".to_string()),
+            Err(error) => Command::Error(error),
+        },
+
+        "setbit" => match (parse_bit_offset(&args[1]), &*args[2]) {
+            (Ok(offset), b"0" | b"1") => {
+                Command::SetBit(arg_string(&args[0]), offset, &*args[2] == b"1")
+            }
+            (Ok(_offset), _bit) => {
+                Command::Error("ERR bit is not an integer or out of range".to_string())
+            }
+            (Err(error), _bit) => Command::Error(error),
+        },
+
+        "bitcount" => {
+            let key = arg_string(&args[0]);
+            let bits = match args.get(3).map(|unit| arg_string(unit).to_lowercase()) {
+                None => Ok(false),
+                Some(unit) if unit == "byte" => Ok(false),
+                Some(unit) if unit == "bit" => Ok(true),
+                Some(_unit) => Err(SYNTAX_ERROR.to_string()),
+            };
+            match args.len() {
+                1 => Command::BitCount {
+                    key,
+                    range: None,
+                    bits: false,
+                },
+                3 | 4 => match (parse_integer(&args[1]), parse_integer(&args[2]), bits) {
+                    (Ok(start), Ok(stop), Ok(bits)) => Command::BitCount {
+                        key,
+                        range: Some((start, stop)),
+                        bits,
+                    },
+                    (Err(error), _, _) | (_, Err(error), _) | (_, _, Err(error)) => {
+                        Command::Error(error)
+                    }
+                },
+                _ => Command::Error(SYNTAX_ERROR.to_string()),
+            }
+        }
+
+        "getbit" => match parse_bit_offset(&args[1]) {
+            Ok(offset) => Command::GetBit(arg_string(&args[0]), offset),
+            Err(error) => Command::Error(error),
+        },
+
+        "getrange" => {
+            let key = arg_string(&args[0]);
+            match (parse_integer(&args[1]), parse_integer(&args[2])) {
+                (Ok(start), Ok(stop)) => Command::GetRange(key, start, stop),
+                (Err(error), _) | (_, Err(error)) => Command::Error(error),
+            }
+        }
+
+        "mget" => Command::MGet(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "mset" => {
+            if args.is_empty() || args.len() % 2 != 0 {
+                Command::Error("ERR wrong number of arguments for 'mset' command".to_string())
+            } else {
+                Command::MSet(
+                    args.chunks(2)
+                        .map(|pair| (arg_string(&pair[0]), pair[1].to_vec()))
+                        .collect(),
+                )
+            }
+        }
+
+        "setnx" => Command::SetNx(arg_string(&args[0]), args[1].to_vec()),
+
+        "del" => Command::Del(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "rename" => Command::Rename(arg_string(&args[0]), arg_string(&args[1])),
+
+        "renamenx" => Command::RenameNx(arg_string(&args[0]), arg_string(&args[1])),
+
+        "copy" => {
+            let (mut db, mut replace) = (None, false);
+            let mut error = None;
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match arg_string(option).to_lowercase().as_str() {
+                    "replace" => replace = true,
+                    "db" => match options.next().map(|db| arg_string(db).parse::<i64>()) {
+                        Some(Ok(index)) => db = Some(index),
+                        Some(Err(_e)) => error = Some(NOT_AN_INTEGER_ERROR),
+                        None => error = Some(SYNTAX_ERROR),
+                    },
+                    _ => error = Some(SYNTAX_ERROR),
+                }
+            }
+            match error {
+                Some(error) => Command::Error(error.to_string()),
+                None => Command::Copy {
+                    src: arg_string(&args[0]),
+                    dst: arg_string(&args[1]),
+                    db,
+                    replace,
+                },
+            }
+        }
+
+        "move" => match arg_string(&args[1]).parse::<i64>() {
+            Ok(db) => Command::Move(arg_string(&args[0]), db),
+            Err(_e) => Command::Error(NOT_AN_INTEGER_ERROR.to_string()),
+        },
+
+        "exists" => Command::Exists(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "touch" => Command::Touch(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "keys" => Command::Keys(arg_string(&args[0])),
+
+        "scan" => match parse_scan(args) {
+            Ok((cursor, pattern, count)) => Command::Scan {
+                cursor,
+                pattern,
+                count,
+            },
+            Err(error) => Command::Error(error),
+        },
+
+        "hscan" | "sscan" | "zscan" => match parse_scan(&args[1..]) {
+            Ok((cursor, pattern, count)) => {
+                let key = arg_string(&args[0]);
+                match command_name.as_str() {
+                    "hscan" => Command::HScan {
+                        key,
+                        cursor,
+                        pattern,
+                        count,
+                    },
+                    "sscan" => Command::SScan {
+                        key,
+                        cursor,
+                        pattern,
+                        count,
+                    },
+                    _ => Command::ZScan {
+                        key,
+                        cursor,
+                        pattern,
+                        count,
+                    },
+                }
+            }
+            Err(error) => Command::Error(error),
+        },
+
+        "type" => Command::Type(arg_string(&args[0])),
+
+        "lpush" => Command::LPush(arg_string(&args[0]), args[1..].to_vec()),
+
+        "rpush" => Command::RPush(arg_string(&args[0]), args[1..].to_vec()),
+
+        "lpop" | "rpop" => {
+            let key = arg_string(&args[0]);
+            let count = match args.get(1).map(|count| arg_string(count).parse::<usize>()) {
+                Some(Ok(count)) => Some(count),
+                Some(Err(_e)) => {
+                    return Some(Command::Error(
+                        "ERR value is out of range, must be positive".to_string(),
+                    ))
+                }
+                None => None,
+            };
+            if command_name == "lpop" {
+                Command::LPop(key, count)
+            } else {
+                Command::RPop(key, count)
+            }
+        }
+
+        "blpop" | "brpop" => {
+            let Some((timeout, keys)) = args.split_last().filter(|(_, keys)| !keys.is_empty())
+            else {
+                return Some(Command::Error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    command_name
+                )));
+            };
+            let keys = keys.iter().map(|key| arg_string(key)).collect();
+            let timeout = match arg_string(timeout).parse::<f64>() {
+                Ok(timeout) if timeout < 0.0 => {
+                    return Some(Command::Error("ERR timeout is negative".to_string()))
+                }
+                Ok(0.0) => None,
+                Ok(timeout) if Duration::try_from_secs_f64(timeout).is_ok() => {
+                    Some(Duration::from_secs_f64(timeout))
+                }
+                _ => {
+                    return Some(Command::Error(
+                        "ERR timeout is not a float or out of range".to_string(),
+                    ))
+                }
+            };
+            if command_name == "blpop" {
+                Command::BLPop(keys, timeout)
+            } else {
+                Command::BRPop(keys, timeout)
+            }
+        }
+
+        "lmove" => match (ListEnd::parse(&args[2]), ListEnd::parse(&args[3])) {
+            (Some(from), Some(to)) => {
+                Command::LMove(arg_string(&args[0]), arg_string(&args[1]), from, to)
+            }
+            _ => Command::Error(SYNTAX_ERROR.to_string()),
+        },
+
+        "rpoplpush" => Command::LMove(
+            arg_string(&args[0]),
+            arg_string(&args[1]),
+            ListEnd::Right,
+            ListEnd::Left,
+        ),
+
+        "lrange" => {
+            let key = arg_string(&args[0]);
+            match (parse_integer(&args[1]), parse_integer(&args[2])) {
+                (Ok(start), Ok(stop)) => Command::LRange(key, start, stop),
+                (Err(error), _) | (_, Err(error)) => Command::Error(error),
+            }
+        }
+
+        "llen" => Command::LLen(arg_string(&args[0])),
+
+        "lindex" => match parse_integer(&args[1]) {
+            Ok(index) => Command::LIndex(arg_string(&args[0]), index),
+            Err(error) => Command::Error(error),
+        },
+
+        "lset" => match parse_integer(&args[1]) {
+            Ok(index) => Command::LSet(arg_string(&args[0]), index, args[2].to_vec()),
+            Err(error) => Command::Error(error),
+        },
+
+        "lpos" => {
+            let (key, element) = (arg_string(&args[0]), args[1].to_vec());
+            let (mut rank, mut count, mut max_len) = (1, None, 0);
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                let Some(value) = options.next() else {
+                    return Some(Command::Error(SYNTAX_ERROR.to_string()));
+                };
+                let value = match parse_integer(value) {
+                    Ok(value) => value,
+                    Err(error) => return Some(Command::Error(error)),
+                };
+                let error = match arg_string(option).to_lowercase().as_str() {
+                    "rank" if value == 0 => {
+                        "ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list"
+                    }
+                    "rank" => {
+                        rank = value;
+                        continue;
+                    }
+                    "count" if value < 0 => "ERR COUNT can't be negative",
+                    "count" => {
+                        count = Some(value as usize);
+                        continue;
+                    }
+                    "maxlen" if value < 0 => "ERR MAXLEN can't be negative",
+                    "maxlen" => {
+                        max_len = value as usize;
+                        continue;
+                    }
+                    _ => SYNTAX_ERROR,
+                };
+                return Some(Command::Error(error.to_string()));
+            }
+            Command::LPos {
+                key,
+                element,
+                rank,
+                count,
+                max_len,
+            }
+        }
+
+        "ltrim" => {
+            let key = arg_string(&args[0]);
+            match (parse_integer(&args[1]), parse_integer(&args[2])) {
+                (Ok(start), Ok(stop)) => Command::LTrim(key, start, stop),
+                (Err(error), _) | (_, Err(error)) => Command::Error(error),
+            }
+        }
+
+        "lrem" => match parse_integer(&args[1]) {
+            Ok(count) => Command::LRem(arg_string(&args[0]), count, args[2].to_vec()),
+            Err(error) => Command::Error(error),
+        },
+
+        "hset" => {
+            if args.len() < 3 || args.len() % 2 != 1 {
+                Command::Error("ERR wrong number of arguments for 'hset' command".to_string())
+            } else {
+                Command::HSet(
+                    arg_string(&args[0]),
+                    args[1..]
+                        .chunks(2)
+                        .map(|pair| (pair[0].to_vec(), pair[1].to_vec()))
+                        .collect(),
+                )
+            }
+        }
+
+        "hget" => Command::HGet(arg_string(&args[0]), args[1].to_vec()),
+
+        "hgetall" => Command::HGetAll(arg_string(&args[0])),
+
+        "hdel" => Command::HDel(arg_string(&args[0]), args[1..].to_vec()),
+
+        "hexists" => Command::HExists(arg_string(&args[0]), args[1].to_vec()),
+
+        "hlen" => Command::HLen(arg_string(&args[0])),
+
+        "hincrby" => match parse_integer(&args[2]) {
+            Ok(delta) => Command::HIncrBy(arg_string(&args[0]), args[1].to_vec(), delta),
+            Err(error) => Command::Error(error),
+        },
+
+        "sadd" => Command::SAdd(arg_string(&args[0]), args[1..].to_vec()),
+
+        "srem" => Command::SRem(arg_string(&args[0]), args[1..].to_vec()),
+
+        "sismember" => Command::SIsMember(arg_string(&args[0]), args[1].to_vec()),
+
+        "smembers" => Command::SMembers(arg_string(&args[0])),
+
+        "scard" => Command::SCard(arg_string(&args[0])),
+
+        "sinter" => Command::SInter(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "sunion" => Command::SUnion(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "sdiff" => Command::SDiff(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "zadd" => match parse_zadd(&args[1..]) {
+            Ok((options, members)) => Command::ZAdd(arg_string(&args[0]), options, members),
+            Err(error) => Command::Error(error),
+        },
+
+        "zscore" => Command::ZScore(arg_string(&args[0]), args[1].to_vec()),
+
+        "zincrby" => match parse_score(&args[1]) {
+            Ok(delta) => Command::ZIncrBy(arg_string(&args[0]), delta, args[2].to_vec()),
+            Err(error) => Command::Error(error),
+        },
+
+        "zrank" => Command::ZRank(arg_string(&args[0]), args[1].to_vec()),
+
+        "zrevrank" => Command::ZRevRank(arg_string(&args[0]), args[1].to_vec()),
+
+        "zrange" => {
+            let key = arg_string(&args[0]);
+            let (mut with_scores, mut rev) = (false, false);
+            let mut flags_error = None;
+            for flag in &args[3..] {
+                match arg_string(flag).to_lowercase().as_str() {
+                    "withscores" => with_scores = true,
+                    "rev" => rev = true,
+                    _ => flags_error = Some(SYNTAX_ERROR.to_string()),
+                }
+            }
+            match (
+                parse_integer(&args[1]),
+                parse_integer(&args[2]),
+                flags_error,
+            ) {
+                (Ok(start), Ok(stop), None) => Command::ZRange {
+                    key,
+                    start,
+                    stop,
+                    with_scores,
+                    rev,
+                },
+                (Err(error), _, _) | (_, Err(error), _) | (_, _, Some(error)) => {
+                    Command::Error(error)
+                }
+            }
+        }
+
+        "incr" => Command::Incr(arg_string(&args[0])),
+
+        "decr" => Command::Decr(arg_string(&args[0])),
+
+        "incrby" | "decrby" => {
+            let key = arg_string(&args[0]);
+            match arg_string(&args[1]).parse::<i64>() {
+                Ok(delta) if command_name == "incrby" => Command::IncrBy(key, delta),
+                Ok(delta) => Command::DecrBy(key, delta),
+                Err(_e) => Command::Error(NOT_AN_INTEGER_ERROR.to_string()),
+            }
+        }
+
+        // a timeout of zero or less, or a time gone by, deletes the key there and then
+        "expire" | "pexpire" => {
+            let key = arg_string(&args[0]);
+            match (
+                arg_string(&args[1])
+                    .parse::<i64>()
+                    .map(|timeout| timeout.max(0) as u64),
+                parse_expire_condition(&args[2..]),
+            ) {
+                (Ok(timeout), Ok(condition)) if command_name == "expire" => {
+                    Command::Expire(key, timeout, condition)
+                }
+                (Ok(timeout), Ok(condition)) => Command::Pexpire(key, timeout, condition),
+                (Err(_e), _) => Command::Error(NOT_AN_INTEGER_ERROR.to_string()),
+                (_, Err(error)) => Command::Error(error),
+            }
+        }
+
+        "expireat" | "pexpireat" => {
+            let key = arg_string(&args[0]);
+            match (
+                arg_string(&args[1])
+                    .parse::<i64>()
+                    .map(|at| at.max(0) as u64),
+                parse_expire_condition(&args[2..]),
+            ) {
+                (Ok(at), Ok(condition)) if command_name == "expireat" => {
+                    Command::ExpireAt(key, at, condition)
+                }
+                (Ok(at), Ok(condition)) => Command::PexpireAt(key, at, condition),
+                (Err(_e), _) => Command::Error(NOT_AN_INTEGER_ERROR.to_string()),
+                (_, Err(error)) => Command::Error(error),
+            }
+        }
+
+        "persist" => Command::Persist(arg_string(&args[0])),
+
+        "ttl" => Command::Ttl(arg_string(&args[0])),
+
+        "pttl" => Command::Pttl(arg_string(&args[0])),
+
+        "expiretime" => Command::ExpireTime(arg_string(&args[0])),
+
+        "pexpiretime" => Command::PexpireTime(arg_string(&args[0])),
+
+        "command" => {
+            let subcommand = args.first().map(|arg| arg_string(arg).to_lowercase());
+            match (subcommand.as_deref(), args.len()) {
+                (None, _) => Command::ListCommands,
+                (Some("count"), 1) => Command::CountCommands,
+                (Some("docs"), _) => {
+                    Command::DescribeCommands(args[1..].iter().map(|arg| arg_string(arg)).collect())
+                }
+                (Some("count"), _) => Command::Error(
+                    "ERR wrong number of arguments for 'command|count' command".to_string(),
+                ),
+                _ => Command::Error(
+                    "ERR unknown subcommand for 'command', try COUNT or DOCS".to_string(),
+                ),
+            }
+        }
+
+        "info" => Command::Info(args.first().map(|arg| arg_string(arg))),
+
+        "dbsize" => Command::DbSize,
+
+        "randomkey" => Command::RandomKey,
+
+        "select" => match arg_string(&args[0]).parse::<i64>() {
+            Ok(index) => Command::Select(index),
+            Err(_e) => Command::Error(NOT_AN_INTEGER_ERROR.to_string()),
+        },
+
+        "multi" => Command::Multi,
+
+        "exec" => Command::Exec,
+
+        "discard" => Command::Discard,
+
+        "watch" => Command::Watch(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "unwatch" => Command::Unwatch,
+
+        "subscribe" => Command::Subscribe(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "unsubscribe" => Command::Unsubscribe(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "psubscribe" => Command::PSubscribe(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "punsubscribe" => Command::PUnsubscribe(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "replconf" => Command::ReplConf(args.iter().map(|arg| arg_string(arg)).collect()),
+
+        "psync" => Command::Psync,
+
+        "hello" => match args
+            .first()
+            .map(|version| arg_string(version).parse::<u8>())
+        {
+            None => Command::Hello(None),
+            Some(Ok(version)) => Command::Hello(Some(version)),
+            Some(Err(_e)) => {
+                Command::Error("ERR Protocol version is not an integer or out of range".to_string())
+            }
+        },
+
+        "wait" => match (
+            arg_string(&args[0]).parse::<usize>(),
+            arg_string(&args[1]).parse::<u64>(),
+        ) {
+            (Ok(wanted), Ok(timeout)) => Command::Wait(wanted, timeout),
+            _ => Command::Error(NOT_AN_INTEGER_ERROR.to_string()),
+        },
+
+        "publish" => Command::Publish(arg_string(&args[0]), args[1].to_vec()),
+
+        "save" => Command::Save,
+
+        "bgsave" => Command::BgSave,
+
+        "flushall" => Command::FlushAll,
+
+        "flushdb" => Command::FlushDb,
+
+        "object" => {
+            let subcommand = args.first().map(|arg| arg_string(arg).to_lowercase());
+            match (subcommand.as_deref(), args.len()) {
+                (Some("encoding"), 2) => Command::ObjectEncoding(arg_string(&args[1])),
+                (Some("refcount"), 2) => Command::ObjectRefcount(arg_string(&args[1])),
+                (Some("idletime"), 2) => Command::ObjectIdletime(arg_string(&args[1])),
+                (Some("encoding" | "refcount" | "idletime"), _) => Command::Error(format!(
+                    "ERR wrong number of arguments for 'object|{}' command",
+                    subcommand.unwrap()
+                )),
+                _ => Command::Error(
+                    "ERR unknown subcommand for 'object', try ENCODING, REFCOUNT or IDLETIME"
+                        .to_string(),
+                ),
+            }
+        }
+
+        "memory" => {
+            let subcommand = args.first().map(|arg| arg_string(arg).to_lowercase());
+            match (subcommand.as_deref(), args.len()) {
+                (Some("usage"), 2) => Command::MemoryUsage(arg_string(&args[1])),
+                // every element is counted, so there's nothing to sample
+                (Some("usage"), 4) if arg_string(&args[2]).eq_ignore_ascii_case("samples") => {
+                    match parse_integer(&args[3]) {
+                        Ok(_samples) => Command::MemoryUsage(arg_string(&args[1])),
+                        Err(error) => Command::Error(error),
+                    }
+                }
+                (Some("usage"), 1) => Command::Error(
+                    "ERR wrong number of arguments for 'memory|usage' command".to_string(),
+                ),
+                (Some("usage"), _) => Command::Error(SYNTAX_ERROR.to_string()),
+                _ => Command::Error("ERR unknown subcommand for 'memory', try USAGE".to_string()),
+            }
+        }
+
+        "debug" => {
+            let subcommand = args.first().map(|arg| arg_string(arg).to_lowercase());
+            match (subcommand.as_deref(), args.len()) {
+                (Some("sleep"), 2) => match arg_string(&args[1]).parse::<f64>() {
+                    Ok(seconds) if Duration::try_from_secs_f64(seconds).is_ok() => {
+                        Command::DebugSleep(seconds)
+                    }
+                    _ => Command::Error(NOT_A_FLOAT_ERROR.to_string()),
+                },
+                (Some("object"), 2) => Command::DebugObject(arg_string(&args[1])),
+                (Some("sleep" | "object"), _) => Command::Error(format!(
+                    "ERR wrong number of arguments for 'debug|{}' command",
+                    subcommand.unwrap()
+                )),
+                _ => Command::Debug(args.iter().map(|arg| arg_string(arg)).collect()),
+            }
+        }
+
+        "client" => {
+            let subcommand = args.first().map(|arg| arg_string(arg).to_lowercase());
+            match (subcommand.as_deref(), args.len()) {
+                (Some("setname"), 2) => {
+                    let name = arg_string(&args[1]);
+                    // names go in CLIENT LIST, which separates fields by spaces
+                    if name.bytes().all(|byte| byte.is_ascii_graphic()) {
+                        Command::ClientSetName(name)
+                    } else {
+                        Command::Error(
+                            "ERR Client names cannot contain spaces, newlines or special characters."
+                                .to_string(),
+                        )
+                    }
+                }
+                (Some("getname"), 1) => Command::ClientGetName,
+                (Some("list"), 1) => Command::ClientList,
+                (Some("id"), 1) => Command::ClientId,
+                (Some("setname" | "getname" | "list" | "id"), _) => Command::Error(format!(
+                    "ERR wrong number of arguments for 'client|{}' command",
+                    subcommand.unwrap()
+                )),
+                _ => Command::Error(
+                    "ERR unknown subcommand for 'client', try SETNAME, GETNAME, LIST or ID"
+                        .to_string(),
+                ),
+            }
+        }
+
+        "config" => {
+            let subcommand = args.first().map(|arg| arg_string(arg).to_lowercase());
+            match (subcommand.as_deref(), args.len()) {
+                (Some("get"), 2) => Command::ConfigGet(arg_string(&args[1])),
+                (Some("set"), 3) => Command::ConfigSet(arg_string(&args[1]), arg_string(&args[2])),
+                (Some("get"), _) | (Some("set"), _) => Command::Error(format!(
+                    "ERR wrong number of arguments for 'config|{}' command",
+                    subcommand.unwrap()
+                )),
+                _ => Command::Error(
+                    "ERR unknown subcommand for 'config', try GET or SET".to_string(),
+                ),
+            }
+        }
+
+        _ => Command::Unknown(arg_string(raw_name)),
+    };
+
+    Some(instruction)
+}
+
+fn get_instruction_type(c: char) -> Option<MessageType> {
+    match c {
+        '+' => Some(MessageType::SimpleString),
+        '-' => Some(MessageType::Error),
+        ':' => Some(MessageType::Integer),
+        '$' => Some(MessageType::BulkString),
+        '*' => Some(MessageType::Array),
+        _ => None,
+    }
+}
+
+pub struct CharRoller {
+    chars: Vec<u8>,
+    index: usize,
+}
+
+impl CharRoller {
+    pub fn from_bytes(phrase: &[u8]) -> CharRoller {
+        CharRoller {
+            chars: phrase.to_vec(),
+            index: 0,
+        }
+    }
+
+    /// Reads up to the next `\n`, for the textual header lines of a frame.
+    /// A blank line comes back empty, and `None` means the input ran out.
+    pub fn next_word(&mut self) -> Option<String> {
+        let mut word = Vec::new();
+        if self.index == self.chars.len() {
+            return None;
+        }
+        while self.index < self.chars.len() {
+            let c = self.chars[self.index];
+            if c == b'\r' {
+                self.index += 1;
+                continue;
+            }
+            if c == b'\n' {
+                self.index += 1;
+                break;
+            }
+            word.push(c);
+            self.index += 1;
+        }
+        Some(arg_string(&word))
+    }
+
+    /// Reads exactly `length` bytes followed by the separator.
+    pub fn next_bytes(&mut self, length: usize) -> Option<Vec<u8>> {
+        let end = self.index.checked_add(length)?;
+        if end > self.chars.len() {
+            return None;
+        }
+        let bytes = self.chars[self.index..end].to_vec();
+        self.index = end;
+        if self.chars[self.index..].starts_with(SEPARATOR.as_bytes()) {
+            self.index += SEPARATOR.len();
+        }
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::OnceLock,
+    };
+    use storage::Value;
+    use tokio::runtime::{self, Runtime};
+
+    #[test]
+    fn test_char_roller() {
+        let mut roller = CharRoller::from_bytes(b"hello\r\n\r\nworld\r\n");
+        let word = roller.next_word();
+        assert_eq!(word, Some("hello".to_string()));
+        let word = roller.next_word();
+        assert_eq!(word, Some(String::new()));
+        let word = roller.next_word();
+        assert_eq!(word, Some("world".to_string()));
+        let word = roller.next_word();
+        assert_eq!(word, None);
+    }
+
+    #[test]
+    fn test_increment() {
+        let mut storage = Storage::new();
+        assert_eq!(apply_delta(&mut storage, "counter", 1), Ok(1));
+        assert_eq!(apply_delta(&mut storage, "counter", 1), Ok(2));
+        assert_eq!(
+            storage.get("counter").unwrap().value,
+            Value::Str(b"2".to_vec())
+        );
+
+        storage.insert(
+            "name".to_string(),
+            StorageEntry::string(b"bob".to_vec(), None),
+        );
+        assert!(apply_delta(&mut storage, "name", 1).is_err());
+    }
+
+    #[test]
+    fn test_apply_delta_overflow_keeps_ttl() {
+        let mut storage = Storage::new();
+        let expiry = Some(unix_millis_now() + 60_000);
+        storage.insert(
+            "counter".to_string(),
+            StorageEntry::string(i64::MAX.to_string().into_bytes(), expiry),
+        );
+        assert_eq!(
+            apply_delta(&mut storage, "counter", 1),
+            Err(OVERFLOW_ERROR.to_string())
+        );
+        assert_eq!(apply_delta(&mut storage, "counter", -10), Ok(i64::MAX - 10));
+        assert_eq!(storage.get("counter").unwrap().expire_timestamp, expiry);
+    }
+
+    fn spawn_test_server() -> TcpStream {
+        spawn_test_server_with_address().0
+    }
+
+    /// For the commands tests run in a session that wait. It has more than
+    /// one thread, as the commands run with `block_in_place`.
+    fn test_runtime() -> &'static Runtime {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+        RUNTIME.get_or_init(|| {
+            runtime::Builder::new_multi_thread()
+                .worker_threads(2)
+                .enable_all()
+                .build()
+                .unwrap()
+        })
+    }
+
+    /// Starts a server sharing one state across every connection, returning a
+    /// first connection and the address for opening more.
+    fn spawn_test_server_with_address() -> (TcpStream, std::net::SocketAddr) {
+        let mut runtime = runtime::Builder::new_multi_thread();
+        runtime.worker_threads(2);
+        spawn_test_server_on(runtime)
+    }
+
+    /// Starts a server like `spawn_test_server_with_address` does, on a
+    /// runtime built by `runtime`.
+    fn spawn_test_server_on(mut runtime: runtime::Builder) -> (TcpStream, std::net::SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let state = SharedState::default();
+        thread::spawn(move || {
+            let runtime = runtime.enable_all().build().unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                serve(listener, state).await;
+            });
+        });
+        (TcpStream::connect(address).unwrap(), address)
+    }
+
+    fn encode_command(args: &[&str]) -> Vec<u8> {
+        let mut message = format!("*{}\r\n", args.len());
+        for arg in args {
+            message.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        message.into_bytes()
+    }
+
+    /// Parses and runs a single command, returning the encoded reply.
+    fn run_command(state: &SharedState, args: &[&str]) -> Vec<u8> {
+        let mut replies = vec![];
+        for instruction in handle_client_message(&encode_command(args)).unwrap() {
+            execute_command(instruction, &mut 0, state).encode(&mut replies);
+        }
+        replies
+    }
+
+    fn read_reply(stream: &mut TcpStream, length: usize) -> Vec<u8> {
+        let mut reply = vec![0_u8; length];
+        stream.read_exact(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn test_complete_frames_len() {
+        assert_eq!(complete_frames_len(b"*1\r\n$4\r\nPING\r\n"), 14);
+        assert_eq!(complete_frames_len(b"*2\r\n$3\r\nGET\r\n"), 0);
+        assert_eq!(complete_frames_len(b"*2\r\n$3\r\nGET\r\n$3\r\nfo"), 0);
+        assert_eq!(complete_frames_len(b"*1\r\n$4\r"), 0);
+        assert_eq!(complete_frames_len(b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r"), 14);
+        assert_eq!(complete_frames_len(b"*1\r\n$x\r\n*1"), 10);
+        assert_eq!(complete_frames_len(b"*1\r\n$9999999999\r\n"), 17);
+    }
+
+    #[test]
+    fn test_malformed_input_is_an_error() {
+        assert_eq!(
+            handle_client_message(b"*x\r\n").err(),
+            Some(ParseError::InvalidArrayLength)
+        );
+        assert_eq!(
+            handle_client_message(b"*1\r\n:4\r\n").err(),
+            Some(ParseError::UnexpectedType {
+                expected: '$',
+                found: ":".to_string()
+            })
+        );
+        // too long to ever be buffered
+        assert_eq!(
+            handle_client_message(b"*1\r\n$18446744073709551615\r\nPING\r\n").err(),
+            Some(ParseError::InvalidBulkLength)
+        );
+        assert_eq!(
+            handle_client_message(b"*1\r\n$9999999999\r\n").err(),
+            Some(ParseError::InvalidBulkLength)
+        );
+    }
+
+    #[test]
+    fn test_inline_commands() {
+        let state = SharedState::default();
+        let mut replies = vec![];
+        let message = b"PING\r\nSET foo  bar\r\n*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\nGET foo\n";
+        for instruction in handle_client_message(message).unwrap() {
+            execute_command(instruction, &mut 0, &state).encode(&mut replies);
+        }
+        assert_eq!(replies, b"+PONG\r\n+OK\r\n$3\r\nbar\r\n$3\r\nbar\r\n");
+
+        // blank lines between inline commands are skipped, not the end of input
+        let instructions = handle_client_message(b"PING\r\n\r\nPING\r\n").unwrap();
+        assert_eq!(instructions.len(), 2);
+
+        assert_eq!(complete_frames_len(b"SET foo ba"), 0);
+        assert_eq!(
+            complete_frames_len(b"SET foo bar\r\n*1\r\n$4\r\nPING\r\n"),
+            27
+        );
+        assert_eq!(complete_frames_len(b"PING\r\n*1\r\n$4\r\n"), 6);
+    }
+
+    #[test]
+    fn test_random_input_never_panics() {
+        // xorshift, so the test is repeatable without pulling in a rand crate
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let valid = encode_command(&["ECHO", "hi"]);
+
+        for _ in 0..5000 {
+            let input: Vec<u8> = if next() % 2 == 0 {
+                let length = (next() % 64) as usize;
+                (0..length).map(|_| next() as u8).collect()
+            } else {
+                // corrupting a real frame gets much further into the parser
+                let mut input = valid.clone();
+                for _ in 0..=next() % 3 {
+                    let index = (next() as usize) % input.len();
+                    input[index] = next() as u8;
+                }
+                input
+            };
+            let _ = complete_frames_len(&input);
+            let _ = handle_client_message(&input);
+        }
+    }
+
+    #[test]
+    fn test_arity() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["get"]),
+            b"-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["SET", "k"]),
+            b"-ERR wrong number of arguments for 'set' command\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["get", "a", "b"]),
+            b"-ERR wrong number of arguments for 'get' command\r\n"
+        );
+
+        // no command panics on any number of arguments
+        for (name, arity, _group) in COMMANDS {
+            for argc in 0..6 {
+                let mut args = vec![name.as_bytes().to_vec()];
+                args.extend((0..argc).map(|_| b"1".to_vec()));
+                let parsed = parse_command(args);
+                if !commands::arity_allows(*arity, argc + 1) {
+                    assert!(
+                        matches!(parsed, Some(Command::Error(_))),
+                        "{} {}",
+                        name,
+                        argc
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_protocol_error_reply() {
+        let mut stream = spawn_test_server();
+        stream.write_all(b"*1\r\n$x\r\n").unwrap();
+        let mut reply = vec![];
+        stream.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"-ERR Protocol error: invalid bulk length\r\n");
+    }
+
+    #[test]
+    fn test_pipelined_commands() {
+        let mut stream = spawn_test_server();
+        let ping = encode_command(&["PING"]);
+        stream.write_all(&ping.repeat(3)).unwrap();
+        assert_eq!(read_reply(&mut stream, 21), b"+PONG\r\n".repeat(3));
+
+        // a frame cut off at the end of one write is finished by the next
+        let mut pipeline = encode_command(&["SET", "k", "v"]);
+        pipeline.extend(encode_command(&["GET", "k"]));
+        let (first, second) = pipeline.split_at(pipeline.len() - 4);
+        stream.write_all(first).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(read_reply(&mut stream, 5), b"+OK\r\n");
+        stream.write_all(second).unwrap();
+        assert_eq!(read_reply(&mut stream, 7), b"$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn test_large_value_round_trip() {
+        let mut stream = spawn_test_server();
+        let value = "x".repeat(5000);
+
+        let set = encode_command(&["SET", "blob", &value]);
+        // write in pieces so the server has to stitch several reads together
+        for piece in set.chunks(700) {
+            stream.write_all(piece).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(read_reply(&mut stream, 5), b"+OK\r\n");
+
+        stream.write_all(&encode_command(&["GET", "blob"])).unwrap();
+        let expected = format!("$5000\r\n{}\r\n", value);
+        assert_eq!(read_reply(&mut stream, expected.len()), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_binary_value_round_trip() {
+        let mut stream = spawn_test_server();
+        let value: Vec<u8> = vec![0xff, 0xfe, b'\r', b'\n', 0x00, 0x80, 0x00, 0x00];
+
+        let mut set = b"*3\r\n$3\r\nSET\r\n$3\r\nbin\r\n$8\r\n".to_vec();
+        set.extend_from_slice(&value);
+        set.extend_from_slice(b"\r\n");
+        stream.write_all(&set).unwrap();
+        assert_eq!(read_reply(&mut stream, 5), b"+OK\r\n");
+
+        stream.write_all(&encode_command(&["GET", "bin"])).unwrap();
+        let mut expected = b"$8\r\n".to_vec();
+        expected.extend_from_slice(&value);
+        expected.extend_from_slice(b"\r\n");
+        assert_eq!(read_reply(&mut stream, expected.len()), expected);
+    }
+
+    #[test]
+    fn test_negative_expiry_deletes() {
+        let mut client = spawn_test_server();
+        let mut run = |args: &[&str], reply_len: usize| {
+            client.write_all(&encode_command(args)).unwrap();
+            read_reply(&mut client, reply_len)
+        };
+        for command in ["expire", "pexpire", "expireat", "pexpireat"] {
+            run(&["set", "k", "1"], 5);
+            assert_eq!(run(&[command, "k", "-1"], 4), b":1\r\n");
+            assert_eq!(run(&["exists", "k"], 4), b":0\r\n");
+            assert_eq!(run(&[command, "k", "-1"], 4), b":0\r\n");
+        }
+        run(&["set", "k", "1"], 5);
+        assert_eq!(run(&["expire", "k", "0"], 4), b":1\r\n");
+        assert_eq!(run(&["exists", "k"], 4), b":0\r\n");
+    }
+
+    #[test]
+    fn test_persist() {
+        let mut client = spawn_test_server();
+        let mut run = |args: &[&str], reply_len: usize| {
+            client.write_all(&encode_command(args)).unwrap();
+            read_reply(&mut client, reply_len)
+        };
+        run(&["set", "k", "v", "ex", "100"], 5);
+        assert_eq!(run(&["persist", "k"], 4), b":1\r\n");
+        assert_eq!(run(&["ttl", "k"], 5), b":-1\r\n");
+        assert_eq!(run(&["persist", "k"], 4), b":0\r\n");
+        assert_eq!(run(&["persist", "missing"], 4), b":0\r\n");
+    }
+
+    #[test]
+    fn test_remaining_ttl_millis() {
+        let state = SharedState::default();
+        assert_eq!(remaining_ttl_millis(state.storage.db(0), "missing"), -2);
+
+        let options = SetOptions::default();
+        execute_command(
+            Command::Set("plain".to_string(), b"1".to_vec(), options),
+            &mut 0,
+            &state,
+        );
+        assert_eq!(remaining_ttl_millis(state.storage.db(0), "plain"), -1);
+
+        let options = SetOptions {
+            expiry: Some(Expiry::After(Duration::from_secs(10))),
+            ..Default::default()
+        };
+        execute_command(
+            Command::Set("temp".to_string(), b"1".to_vec(), options),
+            &mut 0,
+            &state,
+        );
+        let ttl = remaining_ttl_millis(state.storage.db(0), "temp");
+        assert!(ttl > 9_000 && ttl <= 10_000);
+        assert_eq!(
+            execute_command(Command::Ttl("temp".to_string()), &mut 0, &state),
+            RespValue::Integer(10)
+        );
+    }
+
+    #[test]
+    fn test_expiretime() {
+        let state = SharedState::default();
+        assert_eq!(run_command(&state, &["expiretime", "missing"]), b":-2\r\n");
+        run_command(&state, &["set", "plain", "1"]);
+        assert_eq!(run_command(&state, &["pexpiretime", "plain"]), b":-1\r\n");
+
+        let at = unix_millis_now() + 100_000;
+        run_command(&state, &["set", "temp", "1", "pxat", &at.to_string()]);
+        assert_eq!(
+            run_command(&state, &["pexpiretime", "temp"]),
+            format!(":{}\r\n", at).as_bytes()
+        );
+        assert_eq!(
+            run_command(&state, &["expiretime", "temp"]),
+            format!(":{}\r\n", (at + 500) / 1000).as_bytes()
+        );
+        // an absolute expiry is kept as given, not turned into a timeout
+        run_command(&state, &["set", "later", "1", "exat", "4102444800"]);
+        assert_eq!(
+            run_command(&state, &["pexpiretime", "later"]),
+            b":4102444800000\r\n"
+        );
+    }
+
+    fn set_options(args: &[&str]) -> Result<SetOptions, String> {
+        let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        parse_set_options(&args)
+    }
+
+    #[test]
+    fn test_parse_set_options() {
+        assert_eq!(set_options(&[]), Ok(SetOptions::default()));
+        assert_eq!(
+            set_options(&["EX", "10", "nx"]),
+            Ok(SetOptions {
+                expiry: Some(Expiry::After(Duration::from_secs(10))),
+                condition: Some(SetCondition::IfAbsent),
+                keep_ttl: false,
+            })
+        );
+        assert_eq!(
+            set_options(&["pxat", "1700000000000"]).unwrap().expiry,
+            Some(Expiry::AtUnixMillis(1_700_000_000_000))
+        );
+        assert_eq!(
+            set_options(&["xx", "keepttl"]),
+            Ok(SetOptions {
+                expiry: None,
+                condition: Some(SetCondition::IfPresent),
+                keep_ttl: true,
+            })
+        );
+        assert!(set_options(&["nx", "xx"]).is_err());
+        assert!(set_options(&["ex", "10", "keepttl"]).is_err());
+        assert!(set_options(&["ex"]).is_err());
+        assert!(set_options(&["ex", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_set_conditions() {
+        let state = SharedState::default();
+        let set = |options: &[&str]| {
+            let options = set_options(options).unwrap();
+            execute_command(
+                Command::Set("key".to_string(), b"v".to_vec(), options),
+                &mut 0,
+                &state,
+            )
+        };
+
+        assert_eq!(set(&["xx"]), RespValue::null_bulk());
+        assert_eq!(set(&["nx", "px", "10000"]), RespValue::ok());
+        assert_eq!(set(&["nx"]), RespValue::null_bulk());
+        assert_eq!(set(&["xx", "keepttl"]), RespValue::ok());
+        assert!(remaining_ttl_millis(state.storage.db(0), "key") > 0);
+        assert_eq!(set(&["xx"]), RespValue::ok());
+        assert_eq!(remaining_ttl_millis(state.storage.db(0), "key"), -1);
+    }
+
+    #[test]
+    fn test_set_expiry_keyword_is_checked() {
+        let state = SharedState::default();
+        let run = |args: &[&str]| run_command(&state, args);
+
+        assert_eq!(run(&["set", "foo", "bar", "ex", "100"]), b"+OK\r\n");
+        assert_eq!(run(&["ttl", "foo"]), b":100\r\n");
+        assert_eq!(run(&["set", "foo", "bar", "PX", "100"]), b"+OK\r\n");
+        assert_eq!(run(&["ttl", "foo"]), b":0\r\n");
+        assert_eq!(
+            run(&["set", "foo", "bar", "ms", "100"]),
+            b"-ERR syntax error\r\n"
+        );
+    }
+
+    #[test]
+    fn test_expiry_out_of_range() {
+        let state = SharedState::default();
+        let run = |args: &[&str]| run_command(&state, args);
+
+        // too big to parse at all
+        assert_eq!(
+            run(&["set", "k", "v", "px", "18446744073709551615"]),
+            b"-ERR value is not an integer or out of range\r\n"
+        );
+        // parses, but the deadline it makes doesn't fit
+        assert_eq!(
+            run(&["set", "k", "v", "px", "9223372036854775807"]),
+            b"-ERR invalid expire time in 'set' command\r\n"
+        );
+        assert_eq!(run(&["exists", "k"]), b":0\r\n");
+
+        run(&["set", "k", "v"]);
+        assert_eq!(
+            run(&["expire", "k", "9223372036854775807"]),
+            b"-ERR invalid expire time in 'expire' command\r\n"
+        );
+        assert_eq!(
+            run(&["pexpire", "k", "9223372036854775807"]),
+            b"-ERR invalid expire time in 'pexpire' command\r\n"
+        );
+        // still there, and still without a TTL
+        assert_eq!(run(&["ttl", "k"]), b":-1\r\n");
+    }
+
+    #[test]
+    fn test_append_keeps_ttl() {
+        let state = SharedState::default();
+        assert_eq!(run_command(&state, &["append", "log", "ab"]), b":2\r\n");
+        run_command(&state, &["expire", "log", "100"]);
+        assert_eq!(run_command(&state, &["append", "log", "cde"]), b":5\r\n");
+        assert_eq!(run_command(&state, &["get", "log"]), b"$5\r\nabcde\r\n");
+        assert_eq!(run_command(&state, &["ttl", "log"]), b":100\r\n");
+    }
+
+    #[test]
+    fn test_getset() {
+        let state = SharedState::default();
+        let run = |args: &[&str]| run_command(&state, args);
+        assert_eq!(run(&["getset", "k", "a"]), b"$-1\r\n");
+        run(&["expire", "k", "100"]);
+        assert_eq!(run(&["getset", "k", "b"]), b"$1\r\na\r\n");
+        assert_eq!(run(&["get", "k"]), b"$1\r\nb\r\n");
+        // the new value doesn't keep the old expiry
+        assert_eq!(run(&["ttl", "k"]), b":-1\r\n");
+        run(&["rpush", "l", "x"]);
+        assert_eq!(
+            run(&["getset", "l", "a"]),
+            RespValue::error(storage::WRONGTYPE_ERROR).to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_getdel() {
+        let state = SharedState::default();
+        let run = |args: &[&str]| run_command(&state, args);
+        run(&["set", "k", "v"]);
+        assert_eq!(run(&["getdel", "k"]), b"$1\r\nv\r\n");
+        assert_eq!(run(&["exists", "k"]), b":0\r\n");
+        assert_eq!(run(&["getdel", "k"]), b"$-1\r\n");
+    }
+
+    #[test]
+    fn test_del_skips_expired_keys() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "a", "1"]);
+        let expired = unix_millis_now() - 1000;
+        state.storage.db(0).write("gone").insert(
+            "gone".to_string(),
+            StorageEntry::string(b"x".to_vec(), Some(expired)),
+        );
+        assert_eq!(
+            run_command(&state, &["del", "a", "gone", "missing"]),
+            b":1\r\n"
+        );
+        assert!(!state.storage.db(0).read("gone").contains_key("gone"));
+    }
+
+    #[test]
+    fn test_setnx() {
+        let state = SharedState::default();
+        let run = |args: &[&str]| run_command(&state, args);
+        assert_eq!(run(&["setnx", "k", "first"]), b":1\r\n");
+        assert_eq!(run(&["setnx", "k", "second"]), b":0\r\n");
+        assert_eq!(run(&["get", "k"]), b"$5\r\nfirst\r\n");
+    }
+
+    #[test]
+    fn test_mset() {
+        let state = SharedState::default();
+        let run = |args: &[&str]| run_command(&state, args);
+        run(&["set", "a", "old", "ex", "100"]);
+        assert_eq!(run(&["mset", "a", "1", "b", "2"]), b"+OK\r\n");
+        assert_eq!(run(&["mget", "a", "b"]), b"*2\r\n$1\r\n1\r\n$1\r\n2\r\n");
+        assert_eq!(run(&["ttl", "a"]), b":-1\r\n");
+        assert_eq!(
+            run(&["mset", "a", "1", "b"]),
+            b"-ERR wrong number of arguments for 'mset' command\r\n"
+        );
+        assert_eq!(run(&["get", "a"]), b"$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn test_string_commands_reject_other_types() {
+        let state = SharedState::default();
+        state.storage.db(0).write("list").insert(
+            "list".to_string(),
+            StorageEntry::new(Value::List(vec![b"a".to_vec()].into()), None),
+        );
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["get", "list"]), wrongtype);
+        assert_eq!(run_command(&state, &["append", "list", "x"]), wrongtype);
+        assert_eq!(run_command(&state, &["incr", "list"]), wrongtype);
+        assert_eq!(run_command(&state, &["type", "list"]), b"+list\r\n");
+        assert_eq!(run_command(&state, &["mget", "list"]), b"*1\r\n$-1\r\n");
+        assert_eq!(run_command(&state, &["set", "list", "v"]), b"+OK\r\n");
+        assert_eq!(run_command(&state, &["get", "list"]), b"$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn test_push_order() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["lpush", "l", "a", "b", "c"]),
+            b":3\r\n"
+        );
+        assert_eq!(run_command(&state, &["rpush", "l", "x", "y"]), b":5\r\n");
+        let list = match &state.storage.db(0).write("l").get("l").unwrap().value {
+            Value::List(list) => list.clone(),
+            _ => panic!("expected a list"),
+        };
+        assert_eq!(list, vec![b"c", b"b", b"a", b"x", b"y"]);
+        assert_eq!(run_command(&state, &["type", "l"]), b"+list\r\n");
+    }
+
+    #[test]
+    fn test_pop() {
+        let state = SharedState::default();
+        run_command(&state, &["rpush", "l", "a", "b", "c", "d"]);
+        assert_eq!(run_command(&state, &["lpop", "l"]), b"$1\r\na\r\n");
+        assert_eq!(run_command(&state, &["rpop", "l"]), b"$1\r\nd\r\n");
+        assert_eq!(
+            run_command(&state, &["lpop", "l", "5"]),
+            b"*2\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(run_command(&state, &["exists", "l"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["lpop", "l"]), b"$-1\r\n");
+        assert_eq!(run_command(&state, &["rpop", "l", "2"]), b"*-1\r\n");
+    }
+
+    #[test]
+    fn test_lrange() {
+        let state = SharedState::default();
+        run_command(&state, &["rpush", "l", "a", "b", "c", "d", "e"]);
+        assert_eq!(
+            run_command(&state, &["lrange", "l", "0", "-1"]),
+            b"*5\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n$1\r\ne\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lrange", "l", "-3", "-1"]),
+            b"*3\r\n$1\r\nc\r\n$1\r\nd\r\n$1\r\ne\r\n"
+        );
+        assert_eq!(run_command(&state, &["lrange", "l", "7", "9"]), b"*0\r\n");
+        assert_eq!(
+            run_command(&state, &["lrange", "none", "0", "-1"]),
+            b"*0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_hset_counts_new_fields() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["hset", "user", "name", "ann", "age", "30"]),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["hset", "user", "age", "31", "city", "oslo"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["hget", "user", "age"]),
+            b"$2\r\n31\r\n"
+        );
+        assert_eq!(run_command(&state, &["hget", "user", "zip"]), b"$-1\r\n");
+        assert_eq!(run_command(&state, &["hget", "nobody", "age"]), b"$-1\r\n");
+        assert_eq!(run_command(&state, &["type", "user"]), b"+hash\r\n");
+    }
+
+    #[test]
+    fn test_hincrby() {
+        let state = SharedState::default();
+        let run = |args: &[&str]| run_command(&state, args);
+        assert_eq!(run(&["hincrby", "h", "n", "3"]), b":3\r\n");
+        assert_eq!(run(&["hincrby", "h", "n", "-5"]), b":-2\r\n");
+        assert_eq!(run(&["hget", "h", "n"]), b"$2\r\n-2\r\n");
+        assert_eq!(
+            run(&["hincrby", "h", "n", "x"]),
+            RespValue::error(NOT_AN_INTEGER_ERROR).to_bytes()
+        );
+
+        run(&["hset", "h", "name", "ann", "max", &i64::MAX.to_string()]);
+        assert_eq!(
+            run(&["hincrby", "h", "name", "1"]),
+            b"-ERR hash value is not an integer\r\n"
+        );
+        assert_eq!(
+            run(&["hincrby", "h", "max", "1"]),
+            RespValue::error(OVERFLOW_ERROR).to_bytes()
+        );
+        assert_eq!(
+            run(&["hget", "h", "max"]),
+            format!("$19\r\n{}\r\n", i64::MAX).as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_hdel_removes_empty_hash() {
+        let state = SharedState::default();
+        run_command(&state, &["hset", "h", "a", "1", "b", "2"]);
+        assert_eq!(run_command(&state, &["hexists", "h", "a"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["hdel", "h", "a", "zz"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["hexists", "h", "a"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["hlen", "h"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["hdel", "h", "b"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["exists", "h"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["hlen", "h"]), b":0\r\n");
+    }
+
+    #[test]
+    fn test_set_membership() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["sadd", "tags", "a", "b", "a"]),
+            b":2\r\n"
+        );
+        assert_eq!(run_command(&state, &["sadd", "tags", "b", "c"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["sismember", "tags", "c"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["sismember", "tags", "z"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["srem", "tags", "a", "z"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["srem", "tags", "b", "c"]), b":2\r\n");
+        assert_eq!(run_command(&state, &["exists", "tags"]), b":0\r\n");
+    }
+
+    #[test]
+    fn test_config_get_and_set() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["CONFIG", "GET", "maxmemory"]),
+            b"*2\r\n$9\r\nmaxmemory\r\n$1\r\n0\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["config", "set", "maxmemory", "100"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["config", "get", "maxmemory"]),
+            b"*2\r\n$9\r\nmaxmemory\r\n$3\r\n100\r\n"
+        );
+        // clients probe parameters we don't have, and expect an empty reply
+        assert_eq!(run_command(&state, &["config", "get", "bogus"]), b"*0\r\n");
+    }
+
+    #[test]
+    fn test_info() {
+        let state = SharedState::default();
+        let text = info(&state, None);
+        assert!(text.starts_with("# Server\r\nredis_version:"));
+        assert!(text.contains("# Clients\r\nconnected_clients:0\r\n"));
+        assert!(text.ends_with("# Keyspace\r\n"));
+
+        run_command(&state, &["set", "a", "1"]);
+        run_command(&state, &["set", "b", "2", "px", "60000"]);
+        let expired = unix_millis_now() - 1000;
+        state.storage.db(0).write("gone").insert(
+            "gone".to_string(),
+            StorageEntry::string(b"x".to_vec(), Some(expired)),
+        );
+        assert_eq!(
+            info(&state, Some("KEYSPACE")),
+            "# Keyspace\r\ndb0:keys=2,expires=1,avg_ttl=0\r\n"
+        );
+        assert_eq!(info(&state, Some("nonsense")), "");
+    }
+
+    #[test]
+    fn test_dbsize_skips_expired_keys() {
+        let state = SharedState::default();
+        assert_eq!(run_command(&state, &["dbsize"]), b":0\r\n");
+        run_command(&state, &["set", "a", "1"]);
+        run_command(&state, &["rpush", "l", "x"]);
+        let expired = unix_millis_now() - 1000;
+        state.storage.db(0).write("gone").insert(
+            "gone".to_string(),
+            StorageEntry::string(b"x".to_vec(), Some(expired)),
+        );
+        assert_eq!(run_command(&state, &["dbsize"]), b":2\r\n");
+    }
+
+    #[test]
+    fn test_rename() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "a", "1", "ex", "100"]);
+        run_command(&state, &["rpush", "b", "x"]);
+        assert_eq!(run_command(&state, &["rename", "a", "c"]), b"+OK\r\n");
+        assert_eq!(run_command(&state, &["exists", "a"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["get", "c"]), b"$1\r\n1\r\n");
+        let ttl = remaining_ttl_millis(state.storage.db(0), "c");
+        assert!(ttl > 90_000 && ttl <= 100_000);
+
+        // RENAME overwrites, RENAMENX doesn't
+        assert_eq!(run_command(&state, &["renamenx", "c", "b"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["type", "b"]), b"+list\r\n");
+        assert_eq!(run_command(&state, &["rename", "b", "c"]), b"+OK\r\n");
+        assert_eq!(run_command(&state, &["type", "c"]), b"+list\r\n");
+        assert_eq!(remaining_ttl_millis(state.storage.db(0), "c"), -1);
+        assert_eq!(run_command(&state, &["renamenx", "c", "d"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["rename", "d", "d"]), b"+OK\r\n");
+        assert_eq!(run_command(&state, &["llen", "d"]), b":1\r\n");
+
+        assert_eq!(
+            run_command(&state, &["rename", "missing", "x"]),
+            b"-ERR no such key\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["renamenx", "missing", "x"]),
+            b"-ERR no such key\r\n"
+        );
+    }
+
+    #[test]
+    fn test_copy() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        let mut run = |args: &[&str]| run_in_session(&mut session, &state, args);
+        run(&["rpush", "list", "a", "b"]);
+        run(&["pexpire", "list", "100000"]);
+        assert_eq!(run(&["copy", "list", "copied"]), b":1\r\n");
+        // the copy is a separate value, with the same expiry
+        run(&["rpush", "copied", "c"]);
+        assert_eq!(run(&["llen", "list"]), b":2\r\n");
+        assert_eq!(run(&["llen", "copied"]), b":3\r\n");
+        let ttl = remaining_ttl_millis(state.storage.db(0), "copied");
+        assert!(ttl > 90_000 && ttl <= 100_000);
+
+        run(&["set", "s", "v"]);
+        assert_eq!(run(&["copy", "s", "copied"]), b":0\r\n");
+        assert_eq!(run(&["copy", "s", "copied", "replace"]), b":1\r\n");
+        assert_eq!(run(&["get", "copied"]), b"$1\r\nv\r\n");
+        assert_eq!(run(&["copy", "missing", "x"]), b":0\r\n");
+        assert_eq!(
+            run(&["copy", "s", "s"]),
+            b"-ERR source and destination objects are the same\r\n"
+        );
+
+        assert_eq!(run(&["copy", "s", "s", "db", "4"]), b":1\r\n");
+        assert_eq!(
+            run(&["copy", "s", "s", "db", "16"]),
+            b"-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(run(&["copy", "s", "x", "db"]), b"-ERR syntax error\r\n");
+        run(&["select", "4"]);
+        assert_eq!(run(&["get", "s"]), b"$1\r\nv\r\n");
+        assert_eq!(run(&["copy", "s", "list", "db", "0"]), b":0\r\n");
+    }
+
+    #[test]
+    fn test_touch() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "a", "1"]);
+        run_command(&state, &["rpush", "l", "x"]);
+        let expired = unix_millis_now() - 1000;
+        state.storage.db(0).write("gone").insert(
+            "gone".to_string(),
+            StorageEntry::string(b"x".to_vec(), Some(expired)),
+        );
+        assert_eq!(
+            run_command(&state, &["touch", "a", "l", "gone", "missing"]),
+            b":2\r\n"
+        );
+        assert_eq!(run_command(&state, &["touch", "missing"]), b":0\r\n");
+    }
+
+    #[test]
+    fn test_setrange_and_getrange() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "s", "Hello World", "ex", "100"]);
+        assert_eq!(
+            run_command(&state, &["setrange", "s", "6", "Redis"]),
+            b":11\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["get", "s"]),
+            b"$11\r\nHello Redis\r\n"
+        );
+        assert!(remaining_ttl_millis(state.storage.db(0), "s") > 0);
+        assert_eq!(
+            run_command(&state, &["setrange", "s", "9", "!!!"]),
+            b":12\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["get", "s"]),
+            b"$12\r\nHello Red!!!\r\n"
+        );
+
+        // past the end the gap is zero bytes
+        assert_eq!(
+            run_command(&state, &["setrange", "new", "3", "\x01"]),
+            b":4\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["get", "new"]),
+            b"$4\r\n\0\0\0\x01\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["setrange", "none", "5", ""]),
+            b":0\r\n"
+        );
+        assert_eq!(run_command(&state, &["exists", "none"]), b":0\r\n");
+        assert_eq!(
+            run_command(&state, &["setrange", "s", "-1", "x"]),
+            b"-ERR offset is out of range\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["setrange", "s", "536870912", "x"]),
+            b"-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n"
+        );
+
+        assert_eq!(
+            run_command(&state, &["getrange", "s", "0", "4"]),
+            b"$5\r\nHello\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["getrange", "s", "-3", "-1"]),
+            b"$3\r\n!!!\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["getrange", "s", "10", "100"]),
+            b"$2\r\n!!\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["getrange", "s", "5", "2"]),
+            b"$0\r\n\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["getrange", "missing", "0", "-1"]),
+            b"$0\r\n\r\n"
+        );
+        run_command(&state, &["rpush", "l", "x"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["getrange", "l", "0", "1"]), wrongtype);
+        assert_eq!(run_command(&state, &["setrange", "l", "0", "x"]), wrongtype);
+    }
+
+    #[test]
+    fn test_setbit_and_getbit() {
+        let state = SharedState::default();
+        assert_eq!(run_command(&state, &["setbit", "b", "7", "1"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["get", "b"]), b"$1\r\n\x01\r\n");
+        assert_eq!(run_command(&state, &["setbit", "b", "7", "1"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["setbit", "b", "0", "1"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["setbit", "b", "20", "1"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["get", "b"]), b"$3\r\n\x81\0\x08\r\n");
+        assert_eq!(run_command(&state, &["setbit", "b", "7", "0"]), b":1\r\n");
+
+        assert_eq!(run_command(&state, &["getbit", "b", "0"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["getbit", "b", "7"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["getbit", "b", "20"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["getbit", "b", "1000"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["getbit", "missing", "3"]), b":0\r\n");
+
+        assert_eq!(
+            run_command(&state, &["setbit", "b", "1", "2"]),
+            b"-ERR bit is not an integer or out of range\r\n"
+        );
+        for offset in ["-1", "x", "4294967296"] {
+            assert_eq!(
+                run_command(&state, &["getbit", "b", offset]),
+                b"-ERR bit offset is not an integer or out of range\r\n"
+            );
+        }
+        run_command(&state, &["rpush", "l", "x"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["setbit", "l", "0", "1"]), wrongtype);
+        assert_eq!(run_command(&state, &["getbit", "l", "0"]), wrongtype);
+    }
+
+    #[test]
+    fn test_bitcount() {
+        let state = SharedState::default();
+        assert_eq!(run_command(&state, &["bitcount", "missing"]), b":0\r\n");
+        run_command(&state, &["set", "b", "foobar"]);
+        assert_eq!(run_command(&state, &["bitcount", "b"]), b":26\r\n");
+        assert_eq!(run_command(&state, &["bitcount", "b", "0", "0"]), b":4\r\n");
+        assert_eq!(run_command(&state, &["bitcount", "b", "1", "1"]), b":6\r\n");
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "1", "1", "byte"]),
+            b":6\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "-2", "-1"]),
+            b":7\r\n"
+        );
+        assert_eq!(run_command(&state, &["bitcount", "b", "3", "1"]), b":0\r\n");
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "10", "20"]),
+            b":0\r\n"
+        );
+
+        // "f" is 01100110 and "o" is 01101111
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "5", "30", "bit"]),
+            b":17\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "1", "2", "bit"]),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "3", "3", "BIT"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "-8", "-1", "bit"]),
+            b":4\r\n"
+        );
+
+        let syntax_error = RespValue::error(SYNTAX_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["bitcount", "b", "0"]), syntax_error);
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "0", "1", "word"]),
+            syntax_error
+        );
+        assert_eq!(
+            run_command(&state, &["bitcount", "b", "x", "1"]),
+            RespValue::error(NOT_AN_INTEGER_ERROR).to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_randomkey() {
+        let state = SharedState::default();
+        assert_eq!(run_command(&state, &["randomkey"]), b"$-1\r\n");
+        run_command(&state, &["set", "a", "1"]);
+        let expired = unix_millis_now() - 1000;
+        state.storage.db(0).write("gone").insert(
+            "gone".to_string(),
+            StorageEntry::string(b"x".to_vec(), Some(expired)),
+        );
+        for _ in 0..10 {
+            assert_eq!(run_command(&state, &["randomkey"]), b"$1\r\na\r\n");
+        }
+    }
+
+    #[test]
+    fn test_save_and_bgsave() {
+        let dir = std::env::temp_dir().join(format!("save-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = SharedState::default();
+        run_command(&state, &["config", "set", "dir", dir.to_str().unwrap()]);
+        run_command(&state, &["set", "a", "1"]);
+        assert_eq!(run_command(&state, &["save"]), b"+OK\r\n");
+
+        let restarted = SharedState::default();
+        let dump_path = dir.join("dump.rdb");
+        assert_eq!(
+            rdb::load_from_disk(&restarted.storage, &dump_path).unwrap(),
+            1
+        );
+        assert_eq!(run_command(&restarted, &["get", "a"]), b"$1\r\n1\r\n");
+
+        run_command(&state, &["set", "b", "2"]);
+        assert_eq!(
+            run_command(&state, &["bgsave"]),
+            b"+Background saving started\r\n"
+        );
+        while state.saving.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let restarted = SharedState::default();
+        assert_eq!(
+            rdb::load_from_disk(&restarted.storage, &dump_path).unwrap(),
+            2
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_aof_replay() {
+        let path = std::env::temp_dir().join(format!("replay-test-{}.aof", std::process::id()));
+        let mut state = SharedState::default();
+        let aof = Aof::open(&path, FsyncPolicy::No).unwrap();
+        state.aof = Some(Arc::new(Mutex::new(aof)));
+        run_command(&state, &["set", "a", "1", "ex", "100"]);
+        run_command(&state, &["rpush", "l", "x", "y"]);
+        run_command(&state, &["incr", "a"]);
+        // reads and failed writes stay out of the file
+        run_command(&state, &["get", "a"]);
+        run_command(&state, &["lpush", "a", "x"]);
+        run_command(&state, &["pexpire", "l", "100000"]);
+        drop(state);
+
+        // the first write is preceded by a SELECT
+        let restarted = SharedState::default();
+        assert_eq!(replay_aof(&restarted, &path).unwrap(), 5);
+        assert_eq!(run_command(&restarted, &["get", "a"]), b"$1\r\n2\r\n");
+        assert_eq!(
+            run_command(&restarted, &["lrange", "l", "0", "-1"]),
+            b"*2\r\n$1\r\nx\r\n$1\r\ny\r\n"
+        );
+        // expiries were logged as deadlines, not restarted by the replay
+        let ttl = remaining_ttl_millis(restarted.storage.db(0), "l");
+        assert!(ttl > 90_000 && ttl <= 100_000);
+
+        // a command cut off part way is left out
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"*2\r\n$4\r\nINCR").unwrap();
+        let restarted = SharedState::default();
+        assert_eq!(replay_aof(&restarted, &path).unwrap(), 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_aof_skips_no_ops() {
+        let path = std::env::temp_dir().join(format!("no-op-test-{}.aof", std::process::id()));
+        let mut state = SharedState::default();
+        let aof = Aof::open(&path, FsyncPolicy::No).unwrap();
+        state.aof = Some(Arc::new(Mutex::new(aof)));
+        run_command(&state, &["set", "a", "1"]);
+        // writes that change nothing aren't logged
+        run_command(&state, &["del", "missing"]);
+        run_command(&state, &["set", "a", "2", "nx"]);
+        run_command(&state, &["expire", "missing", "100"]);
+        run_command(&state, &["lpop", "missing"]);
+        run_command(&state, &["flushdb"]);
+        run_command(&state, &["flushdb"]);
+        drop(state);
+
+        let logged = std::fs::read(&path).unwrap();
+        let mut expected = encode_command(&["SELECT", "0"]);
+        expected.extend(encode_command(&["SET", "a", "1"]));
+        expected.extend(encode_command(&["FLUSHDB"]));
+        assert_eq!(logged, expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replica_syncs_from_master() {
+        let master = TcpListener::bind("127.0.0.1:0").unwrap();
+        let master_port = master.local_addr().unwrap().port();
+        let state = SharedState {
+            replication: Arc::new(
+                Replication::from_config(&format!("127.0.0.1 {}", master_port)).unwrap(),
+            ),
+            ..SharedState::default()
+        };
+        run_command(&state, &["set", "stale", "x"]);
+        let state_for_replica = state.clone();
+        thread::spawn(move || run_replica(state_for_replica));
+
+        let (mut link, _) = master.accept().unwrap();
+        let mut expect = |request: &[&str], reply: &[u8]| {
+            let request = encode_command(request);
+            assert_eq!(read_reply(&mut link, request.len()), request);
+            link.write_all(reply).unwrap();
+        };
+        expect(&["PING"], b"+PONG\r\n");
+        expect(&["REPLCONF", "listening-port", "6379"], b"+OK\r\n");
+        expect(&["REPLCONF", "capa", "psync2"], b"+OK\r\n");
+        expect(
+            &["PSYNC", "?", "-1"],
+            b"+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0\r\n",
+        );
+        let snapshot =
+            b"REDIS0011\xFE\x00\x00\x04from\x06master\xFF\x00\x00\x00\x00\x00\x00\x00\x00";
+        link.write_all(format!("\n${}\r\n", snapshot.len()).as_bytes())
+            .unwrap();
+        link.write_all(snapshot).unwrap();
+        link.write_all(&encode_command(&["SET", "foo", "bar"]))
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while run_command(&state, &["get", "foo"]) != b"$3\r\nbar\r\n" {
+            assert!(Instant::now() < deadline, "replica never applied the write");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(run_command(&state, &["get", "from"]), b"$6\r\nmaster\r\n");
+        assert_eq!(run_command(&state, &["get", "stale"]), b"$-1\r\n");
+        assert!(info(&state, Some("replication")).contains("master_link_status:up"));
+
+        // clients can read from a replica but not write to it
+        let (mut session, _incoming) = test_session(&state);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "foo"]),
+            b"$3\r\nbar\r\n"
+        );
+        assert!(
+            run_in_session(&mut session, &state, &["set", "foo", "baz"]).starts_with(b"-READONLY")
+        );
+    }
 
     #[test]
-    fn test_char_roller() {
-        let mut roller = CharRoller::from_string("hello\r\nworld\r\n".to_string());
-        let word = roller.next_word();
-        assert_eq!(word, Some("hello".to_string()));
-        let word = roller.next_word();
-        assert_eq!(word, Some("world".to_string()));
-        let word = roller.next_word();
-        assert_eq!(word, None);
+    fn test_master_streams_writes_to_replica() {
+        let (mut client, address) = spawn_test_server_with_address();
+        client
+            .write_all(&encode_command(&["SET", "before", "1"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut client, 5), b"+OK\r\n");
+
+        let replica = SharedState {
+            replication: Arc::new(
+                Replication::from_config(&format!("127.0.0.1 {}", address.port())).unwrap(),
+            ),
+            ..SharedState::default()
+        };
+        let replica_for_thread = replica.clone();
+        thread::spawn(move || run_replica(replica_for_thread));
+        let wait_for = |args: &[&str], expected: &[u8]| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while run_command(&replica, args) != expected {
+                assert!(Instant::now() < deadline, "replica never caught up");
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        wait_for(&["get", "before"], b"$1\r\n1\r\n");
+
+        client
+            .write_all(&encode_command(&["RPUSH", "list", "a", "b"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut client, 4), b":2\r\n");
+        client
+            .write_all(&encode_command(&["INCR", "before"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut client, 4), b":2\r\n");
+        wait_for(&["get", "before"], b"$1\r\n2\r\n");
+        assert_eq!(run_command(&replica, &["llen", "list"]), b":2\r\n");
+
+        client.write_all(&encode_command(&["SELECT", "2"])).unwrap();
+        assert_eq!(read_reply(&mut client, 5), b"+OK\r\n");
+        client
+            .write_all(&encode_command(&["SET", "before", "db2"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut client, 5), b"+OK\r\n");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while replica.storage.db(2).read("before").get("before").is_none() {
+            assert!(Instant::now() < deadline, "replica never caught up");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(run_command(&replica, &["get", "before"]), b"$1\r\n2\r\n");
+
+        let info = |client: &mut TcpStream| {
+            client
+                .write_all(&encode_command(&["INFO", "replication"]))
+                .unwrap();
+            let mut reply = vec![0_u8; 512];
+            let read_bytes = client.read(&mut reply).unwrap();
+            String::from_utf8_lossy(&reply[..read_bytes]).to_string()
+        };
+        let reply = info(&mut client);
+        assert!(reply.contains("role:master\r\nconnected_slaves:1\r\n"));
+        assert!(!reply.contains("master_repl_offset:0\r\n"));
+
+        // a write that changes nothing isn't sent on
+        client
+            .write_all(&encode_command(&["DEL", "missing"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut client, 4), b":0\r\n");
+        assert_eq!(info(&mut client), reply);
+    }
+
+    #[test]
+    fn test_wait() {
+        let (mut client, address) = spawn_test_server_with_address();
+        let mut command = |args: &[&str], reply_len: usize| {
+            client.write_all(&encode_command(args)).unwrap();
+            read_reply(&mut client, reply_len)
+        };
+        assert_eq!(command(&["WAIT", "0", "100"], 4), b":0\r\n");
+        assert_eq!(command(&["SET", "a", "1"], 5), b"+OK\r\n");
+
+        let mut replicas = vec![];
+        for _ in 0..2 {
+            let replica = SharedState {
+                replication: Arc::new(
+                    Replication::from_config(&format!("127.0.0.1 {}", address.port())).unwrap(),
+                ),
+                ..SharedState::default()
+            };
+            let replica_for_thread = replica.clone();
+            thread::spawn(move || run_replica(replica_for_thread));
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while run_command(&replica, &["get", "a"]) != b"$1\r\n1\r\n" {
+                assert!(Instant::now() < deadline, "replica never synced");
+                thread::sleep(Duration::from_millis(10));
+            }
+            replicas.push(replica);
+        }
+
+        // freshly synced replicas have nothing left to acknowledge
+        assert_eq!(command(&["WAIT", "2", "0"], 4), b":2\r\n");
+        assert_eq!(command(&["INCR", "a"], 4), b":2\r\n");
+        assert_eq!(command(&["WAIT", "2", "5000"], 4), b":2\r\n");
+        for replica in &replicas {
+            assert_eq!(run_command(replica, &["get", "a"]), b"$1\r\n2\r\n");
+        }
+
+        // asking for more replicas than there are runs into the timeout
+        let started = Instant::now();
+        assert_eq!(command(&["WAIT", "3", "200"], 4), b":2\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_reads_leave_expired_keys_for_writers() {
+        let state = SharedState::default();
+        let expired = unix_millis_now() - 1000;
+        state.storage.db(0).write("gone").insert(
+            "gone".to_string(),
+            StorageEntry::string(b"x".to_vec(), Some(expired)),
+        );
+        assert_eq!(run_command(&state, &["exists", "gone"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["pttl", "gone"]), b":-2\r\n");
+        // the read lock can't remove it, so it's still there until a write
+        assert!(state.storage.db(0).read("gone").contains_key("gone"));
+
+        assert_eq!(run_command(&state, &["append", "gone", "y"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["get", "gone"]), b"$1\r\ny\r\n");
+    }
+
+    #[test]
+    fn test_expireat() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["expireat", "missing", "4102444800"]),
+            b":0\r\n"
+        );
+        run_command(&state, &["set", "a", "1"]);
+        assert_eq!(
+            run_command(&state, &["expireat", "a", "4102444800"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["pexpiretime", "a"]),
+            b":4102444800000\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["pexpireat", "a", "4102444800123"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["pexpiretime", "a"]),
+            b":4102444800123\r\n"
+        );
+
+        // a time already gone deletes the key straight away
+        assert_eq!(run_command(&state, &["expireat", "a", "1000"]), b":1\r\n");
+        assert!(!state.storage.db(0).read("a").contains_key("a"));
+        run_command(&state, &["set", "b", "1"]);
+        assert_eq!(run_command(&state, &["pexpireat", "b", "0"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["exists", "b"]), b":0\r\n");
+        assert_eq!(
+            run_command(&state, &["expireat", "b", "soon"]),
+            RespValue::error(NOT_AN_INTEGER_ERROR).to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_expire_conditions() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "s", "1"]);
+        assert_eq!(
+            run_command(&state, &["expire", "s", "100", "xx"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["expire", "s", "100", "gt"]),
+            b":0\r\n"
+        );
+        assert_eq!(run_command(&state, &["ttl", "s"]), b":-1\r\n");
+        // no expiry counts as never expiring, which anything is less than
+        assert_eq!(
+            run_command(&state, &["expire", "s", "100", "lt"]),
+            b":1\r\n"
+        );
+        assert_eq!(run_command(&state, &["expire", "s", "50", "NX"]), b":0\r\n");
+
+        assert_eq!(run_command(&state, &["expire", "s", "50", "gt"]), b":0\r\n");
+        assert_eq!(
+            run_command(&state, &["expire", "s", "200", "gt"]),
+            b":1\r\n"
+        );
+        assert_eq!(run_command(&state, &["ttl", "s"]), b":200\r\n");
+        assert_eq!(
+            run_command(&state, &["pexpire", "s", "300000", "lt"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["expire", "s", "150", "xx", "lt"]),
+            b":1\r\n"
+        );
+        assert_eq!(run_command(&state, &["ttl", "s"]), b":150\r\n");
+
+        run_command(&state, &["set", "fresh", "1"]);
+        assert_eq!(
+            run_command(&state, &["expire", "fresh", "10", "nx"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["expireat", "fresh", "4102444800", "gt"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["pexpireat", "fresh", "0", "gt"]),
+            b":0\r\n"
+        );
+        assert_eq!(run_command(&state, &["exists", "fresh"]), b":1\r\n");
+        // a deadline out of range is refused before it's compared, rather
+        // than wrapping round to one that LT lets through
+        assert_eq!(
+            run_command(&state, &["expireat", "fresh", "9223372036854775807", "lt"]),
+            b"-ERR invalid expire time in 'expireat' command\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["expire", "fresh", "9223372036854775807", "gt"]),
+            b"-ERR invalid expire time in 'expire' command\r\n"
+        );
+        assert_eq!(run_command(&state, &["exists", "fresh"]), b":1\r\n");
+
+        assert_eq!(
+            run_command(&state, &["expire", "s", "10", "nx", "gt"]),
+            b"-ERR NX and XX, GT or LT options at the same time are not compatible\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["expire", "s", "10", "gt", "lt"]),
+            b"-ERR GT and LT options at the same time are not compatible\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["expire", "s", "10", "later"]),
+            b"-ERR Unsupported option later\r\n"
+        );
+    }
+
+    #[test]
+    fn test_get_expiry() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "plain", "1"]);
+        run_command(&state, &["set", "brief", "1", "px", "100"]);
+        thread::sleep(Duration::from_secs(2));
+        // no expiry means it never expires, not that it gets a default one
+        assert_eq!(run_command(&state, &["get", "plain"]), b"$1\r\n1\r\n");
+
+        // GET takes an expired key out on its way past
+        assert!(state.storage.db(0).read("brief").contains_key("brief"));
+        assert_eq!(run_command(&state, &["get", "brief"]), b"$-1\r\n");
+        assert!(!state.storage.db(0).read("brief").contains_key("brief"));
+    }
+
+    fn test_session(state: &SharedState) -> (Session, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (outgoing, incoming) = mpsc::unbounded_channel();
+        (Session::new(state, outgoing), incoming)
+    }
+
+    fn run_in_session(session: &mut Session, state: &SharedState, args: &[&str]) -> Vec<u8> {
+        let mut replies = vec![];
+        for instruction in handle_client_message(&encode_command(args)).unwrap() {
+            let handled = if session.waits(&instruction, state) {
+                test_runtime().block_on(session.handle_waiting(instruction, state))
+            } else {
+                session.handle(instruction, state)
+            };
+            for reply in handled {
+                reply.encode_as(session.protocol, &mut replies);
+            }
+        }
+        replies
+    }
+
+    #[test]
+    fn test_select() {
+        let path = std::env::temp_dir().join(format!("select-test-{}.aof", std::process::id()));
+        let mut state = SharedState::default();
+        let aof = Aof::open(&path, FsyncPolicy::No).unwrap();
+        state.aof = Some(Arc::new(Mutex::new(aof)));
+        let (mut session, _incoming) = test_session(&state);
+        run_in_session(&mut session, &state, &["set", "k", "zero"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["select", "3"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "k"]),
+            b"$-1\r\n"
+        );
+        run_in_session(&mut session, &state, &["set", "k", "three"]);
+        assert_eq!(run_in_session(&mut session, &state, &["dbsize"]), b":1\r\n");
+        assert_eq!(
+            run_in_session(&mut session, &state, &["select", "16"]),
+            b"-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["select", "-1"]),
+            b"-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "k"]),
+            b"$5\r\nthree\r\n"
+        );
+        let info = run_command(&state, &["info", "keyspace"]);
+        assert!(
+            String::from_utf8_lossy(&info).contains("db0:keys=1,expires=0,avg_ttl=0\r\ndb3:keys=1")
+        );
+
+        // a transaction's SELECT sticks once it's run
+        run_in_session(&mut session, &state, &["multi"]);
+        run_in_session(&mut session, &state, &["select", "5"]);
+        run_in_session(&mut session, &state, &["set", "k", "five"]);
+        run_in_session(&mut session, &state, &["exec"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "k"]),
+            b"$4\r\nfive\r\n"
+        );
+        run_in_session(&mut session, &state, &["flushdb"]);
+        assert_eq!(run_in_session(&mut session, &state, &["dbsize"]), b":0\r\n");
+        run_in_session(&mut session, &state, &["select", "0"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "k"]),
+            b"$4\r\nzero\r\n"
+        );
+        drop(state);
+
+        // the AOF selects the database each write went to
+        let restarted = SharedState::default();
+        replay_aof(&restarted, &path).unwrap();
+        let value = |db: usize| {
+            restarted
+                .storage
+                .db(db)
+                .read("k")
+                .get("k")
+                .map(|entry| entry.value.clone())
+        };
+        assert_eq!(value(0), Some(Value::Str(b"zero".to_vec())));
+        assert_eq!(value(3), Some(Value::Str(b"three".to_vec())));
+        assert_eq!(value(5), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_move() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        run_in_session(&mut session, &state, &["set", "k", "v", "ex", "100"]);
+        run_in_session(&mut session, &state, &["set", "taken", "v"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["move", "k", "2"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["exists", "k"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["move", "k", "2"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["move", "k", "0"]),
+            b"-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["move", "k", "16"]),
+            b"-ERR DB index is out of range\r\n"
+        );
+
+        run_in_session(&mut session, &state, &["select", "2"]);
+        let ttl = remaining_ttl_millis(state.storage.db(2), "k");
+        assert!(ttl > 90_000 && ttl <= 100_000);
+        run_in_session(&mut session, &state, &["set", "taken", "other"]);
+        // nothing is overwritten in the destination
+        assert_eq!(
+            run_in_session(&mut session, &state, &["move", "taken", "0"]),
+            b":0\r\n"
+        );
+        assert_eq!(run_in_session(&mut session, &state, &["dbsize"]), b":2\r\n");
+        assert_eq!(
+            run_in_session(&mut session, &state, &["move", "k", "0"]),
+            b":1\r\n"
+        );
+        run_in_session(&mut session, &state, &["select", "0"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "k"]),
+            b"$1\r\nv\r\n"
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let state = SharedState::default();
+        for i in 0..30 {
+            run_command(&state, &["set", &format!("key:{}", i), "v"]);
+        }
+        run_command(&state, &["set", "other", "v"]);
+
+        let mut seen = vec![];
+        let mut cursor = "0".to_string();
+        loop {
+            let command = handle_client_message(&encode_command(&[
+                "scan", &cursor, "match", "key:*", "count", "4",
+            ]))
+            .unwrap()
+            .remove(0);
+            let RespValue::Array(Some(mut reply)) = execute_command(command, &mut 0, &state) else {
+                panic!("SCAN didn't reply with an array");
+            };
+            let RespValue::Array(Some(keys)) = reply.pop().unwrap() else {
+                panic!("no keys in the SCAN reply");
+            };
+            seen.extend(keys);
+            let RespValue::Bulk(Some(next_cursor)) = reply.pop().unwrap() else {
+                panic!("no cursor in the SCAN reply");
+            };
+            cursor = String::from_utf8(next_cursor).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 30);
+        assert!(!seen.contains(&RespValue::bulk(b"other")));
+
+        assert_eq!(
+            run_command(&state, &["scan", "x"]),
+            b"-ERR invalid cursor\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["scan", "0", "count", "0"]),
+            b"-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["scan", "0", "match"]),
+            b"-ERR syntax error\r\n"
+        );
+        run_command(&state, &["flushall"]);
+        assert_eq!(
+            run_command(&state, &["scan", "0"]),
+            b"*2\r\n$1\r\n0\r\n*0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_collection_scans() {
+        let state = SharedState::default();
+        for i in 0..20 {
+            let name = format!("f{}", i);
+            run_command(&state, &["hset", "h", &name, &i.to_string()]);
+            run_command(&state, &["sadd", "s", &name]);
+        }
+        run_command(&state, &["sadd", "s", "other"]);
+
+        let scan_all = |args: &[&str]| {
+            let mut elements = vec![];
+            let mut cursor = "0".to_string();
+            loop {
+                let mut command = args.to_vec();
+                command.insert(2, &cursor);
+                let instruction = handle_client_message(&encode_command(&command))
+                    .unwrap()
+                    .remove(0);
+                let RespValue::Array(Some(mut reply)) =
+                    execute_command(instruction, &mut 0, &state)
+                else {
+                    panic!("{} didn't reply with an array", args[0]);
+                };
+                let Some(RespValue::Array(Some(found))) = reply.pop() else {
+                    panic!("no elements in the {} reply", args[0]);
+                };
+                elements.extend(found);
+                let Some(RespValue::Bulk(Some(next_cursor))) = reply.pop() else {
+                    panic!("no cursor in the {} reply", args[0]);
+                };
+                cursor = String::from_utf8(next_cursor).unwrap();
+                if cursor == "0" {
+                    return elements;
+                }
+            }
+        };
+
+        let pairs = scan_all(&["hscan", "h", "count", "3"]);
+        assert_eq!(pairs.len(), 40);
+        let field = pairs
+            .iter()
+            .position(|element| *element == RespValue::bulk(b"f7"))
+            .unwrap();
+        // each field is followed by its value
+        assert_eq!(pairs[field + 1], RespValue::bulk(b"7"));
+        let members = scan_all(&["sscan", "s", "match", "f*", "count", "5"]);
+        assert_eq!(members.len(), 20);
+        assert!(!members.contains(&RespValue::bulk(b"other")));
+
+        assert_eq!(
+            run_command(&state, &["hscan", "missing", "0"]),
+            b"*2\r\n$1\r\n0\r\n*0\r\n"
+        );
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["sscan", "h", "0"]), wrongtype);
+        assert_eq!(run_command(&state, &["zscan", "h", "0"]), wrongtype);
+        assert_eq!(
+            run_command(&state, &["zscan", "missing", "0"]),
+            b"*2\r\n$1\r\n0\r\n*0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_zadd() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "1", "a", "2.5", "b"]),
+            b":2\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "3", "a", "4", "c"]),
+            b":1\r\n"
+        );
+        assert_eq!(run_command(&state, &["zscore", "z", "a"]), b"$1\r\n3\r\n");
+        assert_eq!(run_command(&state, &["zscore", "z", "b"]), b"$3\r\n2.5\r\n");
+        assert_eq!(run_command(&state, &["zscore", "z", "x"]), b"$-1\r\n");
+        assert_eq!(run_command(&state, &["zscore", "missing", "a"]), b"$-1\r\n");
+        assert_eq!(run_command(&state, &["type", "z"]), b"+zset\r\n");
+
+        // NX only adds, XX only updates
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "nx", "9", "a", "1", "d"]),
+            b":1\r\n"
+        );
+        assert_eq!(run_command(&state, &["zscore", "z", "a"]), b"$1\r\n3\r\n");
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "xx", "ch", "9", "a", "1", "e"]),
+            b":1\r\n"
+        );
+        assert_eq!(run_command(&state, &["zscore", "z", "e"]), b"$-1\r\n");
+        // GT and LT only move scores one way, but still add new members
+        assert_eq!(
+            run_command(
+                &state,
+                &["zadd", "z", "gt", "ch", "5", "a", "5", "b", "0", "f"]
+            ),
+            b":2\r\n"
+        );
+        assert_eq!(run_command(&state, &["zscore", "z", "a"]), b"$1\r\n9\r\n");
+        assert_eq!(run_command(&state, &["zscore", "z", "b"]), b"$1\r\n5\r\n");
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "lt", "ch", "7", "a"]),
+            b":1\r\n"
+        );
+        assert_eq!(run_command(&state, &["zadd", "z", "-inf", "g"]), b":1\r\n");
+        assert_eq!(
+            run_command(&state, &["zscore", "z", "g"]),
+            b"$4\r\n-inf\r\n"
+        );
+
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "nx", "gt", "1", "a"]),
+            b"-ERR GT, LT, and/or NX options at the same time are not compatible\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "x", "a"]),
+            b"-ERR value is not a valid float\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "nan", "a"]),
+            b"-ERR value is not a valid float\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zadd", "z", "1", "a", "2"]),
+            b"-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zadd", "none", "xx", "1", "a"]),
+            b":0\r\n"
+        );
+        assert_eq!(run_command(&state, &["exists", "none"]), b":0\r\n");
+        run_command(&state, &["set", "s", "v"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["zadd", "s", "1", "a"]), wrongtype);
+        assert_eq!(
+            run_command(
+                &state,
+                &["zscan", "z", "0", "match", "[ab]", "count", "100"]
+            ),
+            b"*2\r\n$1\r\n0\r\n*4\r\n$1\r\nb\r\n$1\r\n5\r\n$1\r\na\r\n$1\r\n7\r\n"
+        );
+    }
+
+    #[test]
+    fn test_zrange() {
+        let state = SharedState::default();
+        run_command(
+            &state,
+            &[
+                "zadd", "z", "2", "b", "1", "c", "2", "a", "-1.5", "d", "3", "e",
+            ],
+        );
+        // lowest score first, and members sharing a score in byte order
+        assert_eq!(
+            run_command(&state, &["zrange", "z", "0", "-1"]),
+            b"*5\r\n$1\r\nd\r\n$1\r\nc\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\ne\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zrange", "z", "1", "2", "withscores"]),
+            b"*4\r\n$1\r\nc\r\n$1\r\n1\r\n$1\r\na\r\n$1\r\n2\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zrange", "z", "0", "2", "rev"]),
+            b"*3\r\n$1\r\ne\r\n$1\r\nb\r\n$1\r\na\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zrange", "z", "-2", "-1", "REV", "WITHSCORES"]),
+            b"*4\r\n$1\r\nc\r\n$1\r\n1\r\n$1\r\nd\r\n$4\r\n-1.5\r\n"
+        );
+        assert_eq!(run_command(&state, &["zrange", "z", "5", "10"]), b"*0\r\n");
+        assert_eq!(
+            run_command(&state, &["zrange", "missing", "0", "-1"]),
+            b"*0\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zrange", "z", "0", "-1", "byscore"]),
+            b"-ERR syntax error\r\n"
+        );
+
+        // an updated score moves the member
+        run_command(&state, &["zadd", "z", "0", "e"]);
+        assert_eq!(
+            run_command(&state, &["zrange", "z", "0", "1"]),
+            b"*2\r\n$1\r\nd\r\n$1\r\ne\r\n"
+        );
+    }
+
+    #[test]
+    fn test_zrank() {
+        let state = SharedState::default();
+        run_command(&state, &["zadd", "z", "10", "b", "10", "a", "5", "c"]);
+        assert_eq!(run_command(&state, &["zrank", "z", "c"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["zrank", "z", "a"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["zrank", "z", "b"]), b":2\r\n");
+        assert_eq!(run_command(&state, &["zrevrank", "z", "b"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["zrevrank", "z", "c"]), b":2\r\n");
+        assert_eq!(run_command(&state, &["zrank", "z", "x"]), b"$-1\r\n");
+        assert_eq!(
+            run_command(&state, &["zrevrank", "missing", "x"]),
+            b"$-1\r\n"
+        );
+
+        // ranks agree with ZRANGE, before and after a score moves
+        for (member, rank) in [("c", 0), ("a", 1), ("b", 2)] {
+            assert_eq!(
+                run_command(
+                    &state,
+                    &["zrange", "z", &rank.to_string(), &rank.to_string()]
+                ),
+                format!("*1\r\n$1\r\n{}\r\n", member).into_bytes()
+            );
+        }
+        run_command(&state, &["zadd", "z", "20", "c"]);
+        assert_eq!(run_command(&state, &["zrank", "z", "c"]), b":2\r\n");
+        assert_eq!(run_command(&state, &["zrevrank", "z", "c"]), b":0\r\n");
+
+        run_command(&state, &["set", "s", "v"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["zrank", "s", "a"]), wrongtype);
+    }
+
+    #[test]
+    fn test_zincrby() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["zincrby", "z", "2.5", "a"]),
+            b"$3\r\n2.5\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zincrby", "z", "-1", "a"]),
+            b"$3\r\n1.5\r\n"
+        );
+        run_command(&state, &["zadd", "z", "1", "b", "2", "c"]);
+        assert_eq!(
+            run_command(&state, &["zrange", "z", "0", "-1"]),
+            b"*3\r\n$1\r\nb\r\n$1\r\na\r\n$1\r\nc\r\n"
+        );
+        // the ordering follows the new score
+        assert_eq!(
+            run_command(&state, &["zincrby", "z", "5", "b"]),
+            b"$1\r\n6\r\n"
+        );
+        assert_eq!(run_command(&state, &["zrank", "z", "b"]), b":2\r\n");
+        assert_eq!(
+            run_command(&state, &["zrange", "z", "0", "-1", "withscores"]),
+            b"*6\r\n$1\r\na\r\n$3\r\n1.5\r\n$1\r\nc\r\n$1\r\n2\r\n$1\r\nb\r\n$1\r\n6\r\n"
+        );
+
+        assert_eq!(
+            run_command(&state, &["zincrby", "z", "x", "a"]),
+            b"-ERR value is not a valid float\r\n"
+        );
+        run_command(&state, &["zadd", "inf", "+inf", "a"]);
+        assert_eq!(
+            run_command(&state, &["zincrby", "inf", "-inf", "a"]),
+            b"-ERR resulting score is not a number (NaN)\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["zincrby", "nan", "-inf", "a"]),
+            b"$4\r\n-inf\r\n"
+        );
+        run_command(&state, &["set", "s", "v"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["zincrby", "s", "1", "a"]), wrongtype);
+    }
+
+    #[test]
+    fn test_object_encoding() {
+        let state = SharedState::default();
+        let encoding = |key: &str| run_command(&state, &["object", "encoding", key]);
+        run_command(&state, &["set", "int", "-12345"]);
+        run_command(&state, &["set", "short", "hello"]);
+        run_command(&state, &["set", "long", &"x".repeat(45)]);
+        assert_eq!(encoding("int"), b"$3\r\nint\r\n");
+        assert_eq!(encoding("short"), b"$6\r\nembstr\r\n");
+        assert_eq!(encoding("long"), b"$3\r\nraw\r\n");
+
+        run_command(&state, &["rpush", "list", "a"]);
+        assert_eq!(encoding("list"), b"$8\r\nlistpack\r\n");
+        run_command(&state, &["rpush", "list", &"x".repeat(65)]);
+        assert_eq!(encoding("list"), b"$9\r\nquicklist\r\n");
+
+        run_command(&state, &["sadd", "set", "1", "2"]);
+        assert_eq!(encoding("set"), b"$6\r\nintset\r\n");
+        run_command(&state, &["sadd", "set", "a"]);
+        assert_eq!(encoding("set"), b"$8\r\nlistpack\r\n");
+
+        run_command(&state, &["hset", "hash", "f", "v"]);
+        assert_eq!(encoding("hash"), b"$8\r\nlistpack\r\n");
+        for i in 0..129 {
+            run_command(&state, &["hset", "hash", &i.to_string(), "v"]);
+        }
+        assert_eq!(encoding("hash"), b"$9\r\nhashtable\r\n");
+
+        run_command(&state, &["zadd", "zset", "1", &"x".repeat(65)]);
+        assert_eq!(encoding("zset"), b"$8\r\nskiplist\r\n");
+
+        assert_eq!(encoding("missing"), b"-ERR no such key\r\n");
+        assert_eq!(
+            run_command(&state, &["object", "refcount", "zset"]),
+            b":1\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["object", "idletime", "zset"]),
+            b":0\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["object", "idletime", "missing"]),
+            b"-ERR no such key\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["object", "freq", "int"]),
+            b"-ERR unknown subcommand for 'object', try ENCODING, REFCOUNT or IDLETIME\r\n"
+        );
+    }
+
+    #[test]
+    fn test_get_resets_idletime() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "k", "v"]);
+        run_command(&state, &["set", "t", "v"]);
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(run_command(&state, &["object", "idletime", "k"]), b":1\r\n");
+        run_command(&state, &["get", "k"]);
+        assert_eq!(run_command(&state, &["object", "idletime", "k"]), b":0\r\n");
+
+        // EXISTS only looks, TOUCH uses
+        run_command(&state, &["exists", "t"]);
+        assert_eq!(run_command(&state, &["object", "idletime", "t"]), b":1\r\n");
+        run_command(&state, &["touch", "t"]);
+        assert_eq!(run_command(&state, &["object", "idletime", "t"]), b":0\r\n");
+    }
+
+    #[test]
+    fn test_hello() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        run_in_session(&mut session, &state, &["hset", "h", "f", "v"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["hgetall", "h"]),
+            b"*2\r\n$1\r\nf\r\n$1\r\nv\r\n"
+        );
+
+        let reply = run_in_session(&mut session, &state, &["hello", "3"]);
+        assert!(reply.starts_with(b"%7\r\n$6\r\nserver\r\n$5\r\nredis\r\n"));
+        assert!(reply.ends_with(b"$7\r\nmodules\r\n*0\r\n"));
+        assert_eq!(
+            run_in_session(&mut session, &state, &["hgetall", "h"]),
+            b"%1\r\n$1\r\nf\r\n$1\r\nv\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "missing"]),
+            b"_\r\n"
+        );
+
+        assert!(run_in_session(&mut session, &state, &["hello", "4"]).starts_with(b"-NOPROTO"));
+        assert!(run_in_session(&mut session, &state, &["hello", "2"]).starts_with(b"*14\r\n"));
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "missing"]),
+            b"$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_multi_exec() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        assert_eq!(run_in_session(&mut session, &state, &["multi"]), b"+OK\r\n");
+        assert_eq!(
+            run_in_session(&mut session, &state, &["set", "a", "1"]),
+            b"+QUEUED\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["incr", "a"]),
+            b"+QUEUED\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["lpush", "a", "x"]),
+            b"+QUEUED\r\n"
+        );
+        // nothing runs until EXEC
+        assert_eq!(run_command(&state, &["get", "a"]), b"$-1\r\n");
+
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        let mut expected = b"*3\r\n+OK\r\n:2\r\n".to_vec();
+        expected.extend_from_slice(&wrongtype);
+        assert_eq!(run_in_session(&mut session, &state, &["exec"]), expected);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["exec"]),
+            b"-ERR EXEC without MULTI\r\n"
+        );
+    }
+
+    #[test]
+    fn test_discard_and_exec_abort() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        run_in_session(&mut session, &state, &["multi"]);
+        run_in_session(&mut session, &state, &["set", "a", "1"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["discard"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(run_command(&state, &["get", "a"]), b"$-1\r\n");
+
+        run_in_session(&mut session, &state, &["multi"]);
+        run_in_session(&mut session, &state, &["set", "a", "1"]);
+        assert!(
+            run_in_session(&mut session, &state, &["set", "a", "1", "bogus"]).starts_with(b"-")
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["exec"]),
+            b"-EXECABORT Transaction discarded because of previous errors.\r\n"
+        );
+        assert_eq!(run_command(&state, &["get", "a"]), b"$-1\r\n");
+    }
+
+    #[test]
+    fn test_watch() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        run_command(&state, &["set", "balance", "10"]);
+
+        // untouched keys let the transaction through
+        run_in_session(&mut session, &state, &["watch", "balance", "missing"]);
+        run_in_session(&mut session, &state, &["multi"]);
+        run_in_session(&mut session, &state, &["incrby", "balance", "5"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["exec"]),
+            b"*1\r\n:15\r\n"
+        );
+
+        // a write from another connection in between aborts it
+        run_in_session(&mut session, &state, &["watch", "balance"]);
+        run_command(&state, &["set", "balance", "0"]);
+        run_in_session(&mut session, &state, &["multi"]);
+        run_in_session(&mut session, &state, &["incrby", "balance", "5"]);
+        assert_eq!(run_in_session(&mut session, &state, &["exec"]), b"*-1\r\n");
+        assert_eq!(run_command(&state, &["get", "balance"]), b"$1\r\n0\r\n");
+
+        // so does creating a key that was missing when watched
+        run_in_session(&mut session, &state, &["watch", "fresh"]);
+        run_command(&state, &["set", "fresh", "1"]);
+        run_in_session(&mut session, &state, &["multi"]);
+        assert_eq!(run_in_session(&mut session, &state, &["exec"]), b"*-1\r\n");
+
+        // writes that don't change anything leave it be
+        run_command(&state, &["set", "balance", "10"]);
+        run_command(&state, &["set", "gone", "1", "px", "1"]);
+        thread::sleep(Duration::from_millis(5));
+        run_in_session(&mut session, &state, &["watch", "balance", "gone"]);
+        run_command(&state, &["setnx", "balance", "20"]);
+        run_command(&state, &["lpush", "balance", "x"]);
+        run_command(&state, &["persist", "balance"]);
+        run_command(&state, &["get", "gone"]);
+        run_in_session(&mut session, &state, &["multi"]);
+        run_in_session(&mut session, &state, &["get", "balance"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["exec"]),
+            b"*1\r\n$2\r\n10\r\n"
+        );
+
+        // and UNWATCH forgets about earlier writes
+        run_in_session(&mut session, &state, &["watch", "balance"]);
+        run_command(&state, &["del", "balance"]);
+        run_in_session(&mut session, &state, &["unwatch"]);
+        run_in_session(&mut session, &state, &["multi"]);
+        assert_eq!(run_in_session(&mut session, &state, &["exec"]), b"*0\r\n");
+    }
+
+    #[test]
+    fn test_subscriber_mode() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["subscribe", "a", "b"]),
+            b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["ping"]),
+            b"*2\r\n$4\r\npong\r\n$0\r\n\r\n"
+        );
+        assert!(run_in_session(&mut session, &state, &["get", "a"]).starts_with(b"-ERR only"));
+
+        assert_eq!(
+            run_in_session(&mut session, &state, &["unsubscribe"]),
+            b"*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:0\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "a"]),
+            b"$-1\r\n"
+        );
+
+        // patterns count towards the same total
+        run_in_session(&mut session, &state, &["subscribe", "a"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["psubscribe", "news.*"]),
+            b"*3\r\n$10\r\npsubscribe\r\n$6\r\nnews.*\r\n:2\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["punsubscribe"]),
+            b"*3\r\n$12\r\npunsubscribe\r\n$6\r\nnews.*\r\n:1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_resp3_subscriber() {
+        let state = SharedState::default();
+        let (mut session, mut incoming) = test_session(&state);
+        run_in_session(&mut session, &state, &["subscribe", "a"]);
+        run_in_session(&mut session, &state, &["hello", "3"]);
+        // switching protocols carries over to what's already subscribed
+        run_command(&state, &["publish", "a", "hi"]);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b">3\r\n$7\r\nmessage\r\n$1\r\na\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["subscribe", "b"]),
+            b">3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n"
+        );
+        // pushes can't be mistaken for replies, so other commands work
+        assert_eq!(
+            run_in_session(&mut session, &state, &["ping"]),
+            b"+PONG\r\n"
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["get", "a"]),
+            b"_\r\n"
+        );
+    }
+
+    #[test]
+    fn test_publish_reaches_blocked_subscriber() {
+        let (mut subscriber, address) = spawn_test_server_with_address();
+        subscriber
+            .write_all(&encode_command(&["SUBSCRIBE", "news"]))
+            .unwrap();
+        let confirmation = b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n";
+        assert_eq!(
+            read_reply(&mut subscriber, confirmation.len()),
+            confirmation
+        );
+
+        // the subscriber's thread is blocked reading its socket the whole time
+        let mut publisher = TcpStream::connect(address).unwrap();
+        publisher
+            .write_all(&encode_command(&["PUBLISH", "news", "hello"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut publisher, 4), b":1\r\n");
+
+        let message = b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+        assert_eq!(read_reply(&mut subscriber, message.len()), message);
+    }
+
+    #[test]
+    fn test_client_name() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        let mut run = |args: &[&str]| run_in_session(&mut session, &state, args);
+        assert_eq!(run(&["client", "getname"]), b"$0\r\n\r\n");
+        assert_eq!(run(&["client", "setname", "worker-1"]), b"+OK\r\n");
+        assert_eq!(run(&["CLIENT", "GETNAME"]), b"$8\r\nworker-1\r\n");
+        assert_eq!(
+            run(&["client", "setname", "two words"]),
+            b"-ERR Client names cannot contain spaces, newlines or special characters.\r\n"
+        );
+        assert_eq!(run(&["client", "getname"]), b"$8\r\nworker-1\r\n");
+        assert_eq!(run(&["client", "setname", ""]), b"+OK\r\n");
+        assert_eq!(run(&["client", "getname"]), b"$0\r\n\r\n");
+        assert_eq!(
+            run(&["client", "setname"]),
+            b"-ERR wrong number of arguments for 'client|setname' command\r\n"
+        );
+
+        assert_eq!(
+            run(&["client", "id"]),
+            format!(":{}\r\n", session.id).as_bytes()
+        );
+        // the first connection is client 1
+        assert_eq!(session.id, 1);
+
+        // each connection has a name of its own
+        let (mut other, _incoming) = test_session(&state);
+        run_in_session(&mut other, &state, &["client", "setname", "other"]);
+        assert_eq!(
+            run_in_session(&mut other, &state, &["client", "id"]),
+            format!(":{}\r\n", session.id + 1).as_bytes()
+        );
+        assert_eq!(
+            run_in_session(&mut session, &state, &["client", "getname"]),
+            b"$0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_debug_sleep() {
+        let (mut sleeper, address) = spawn_test_server_with_address();
+        let started = Instant::now();
+        sleeper
+            .write_all(&encode_command(&["DEBUG", "SLEEP", "0.2"]))
+            .unwrap();
+
+        // another connection isn't held up by it
+        let mut other = TcpStream::connect(address).unwrap();
+        other.write_all(&encode_command(&["PING"])).unwrap();
+        assert_eq!(read_reply(&mut other, 7), b"+PONG\r\n");
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        assert_eq!(read_reply(&mut sleeper, 5), b"+OK\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let state = SharedState::default();
+        assert_eq!(run_command(&state, &["debug", "sleep", "0"]), b"+OK\r\n");
+        let not_a_float = RespValue::error(NOT_A_FLOAT_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["debug", "sleep", "-1"]), not_a_float);
+        assert_eq!(
+            run_command(&state, &["debug", "sleep", "soon"]),
+            not_a_float
+        );
+    }
+
+    #[test]
+    fn test_command() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["command", "count"]),
+            format!(":{}\r\n", COMMANDS.len()).as_bytes()
+        );
+        let listing = String::from_utf8(run_command(&state, &["command"])).unwrap();
+        assert!(listing.starts_with(&format!("*{}\r\n", COMMANDS.len())));
+        assert!(listing.contains("*2\r\n$3\r\nget\r\n:2\r\n"));
+        assert_eq!(
+            run_command(&state, &["command", "docs", "GET", "nope", "zadd"]),
+            b"*4\r\n$3\r\nget\r\n*4\r\n$5\r\ngroup\r\n$6\r\nstring\r\n$5\r\narity\r\n:2\r\n\
+              $4\r\nzadd\r\n*4\r\n$5\r\ngroup\r\n$10\r\nsorted-set\r\n$5\r\narity\r\n:-4\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["command", "nope"]),
+            RespValue::error("ERR unknown subcommand for 'command', try COUNT or DOCS").to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "s", "hello"]);
+        // 64 bytes of overhead per key, and 16 per element of a collection
+        assert_eq!(run_command(&state, &["memory", "usage", "s"]), b":70\r\n");
+        run_command(&state, &["rpush", "l", "a", "bc"]);
+        run_command(&state, &["sadd", "set", "abc"]);
+        let usage = |key: &str| run_command(&state, &["MEMORY", "USAGE", key, "SAMPLES", "5"]);
+        assert_eq!(usage("l"), b":100\r\n");
+        assert_eq!(usage("set"), b":86\r\n");
+        assert_eq!(usage("missing"), b"$-1\r\n");
+        assert_eq!(
+            run_command(&state, &["memory", "usage", "s", "samples", "x"]),
+            RespValue::error(NOT_AN_INTEGER_ERROR).to_bytes()
+        );
+        assert_eq!(
+            run_command(&state, &["memory", "doctor"]),
+            RespValue::error("ERR unknown subcommand for 'memory', try USAGE").to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_keyspace_notifications() {
+        let state = SharedState::default();
+        let (mut subscriber, mut incoming) = test_session(&state);
+        run_in_session(&mut subscriber, &state, &["subscribe", "__keyspace@0__:k"]);
+        run_in_session(
+            &mut subscriber,
+            &state,
+            &["subscribe", "__keyevent@0__:del"],
+        );
+        // off by default
+        run_command(&state, &["set", "k", "v"]);
+        assert!(incoming.try_recv().is_err());
+
+        run_command(&state, &["config", "set", "notify-keyspace-events", "KEg$"]);
+        run_command(&state, &["set", "k", "v"]);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$16\r\n__keyspace@0__:k\r\n$3\r\nset\r\n"
+        );
+        run_command(&state, &["del", "k", "missing"]);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$16\r\n__keyspace@0__:k\r\n$3\r\ndel\r\n"
+        );
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$18\r\n__keyevent@0__:del\r\n$1\r\nk\r\n"
+        );
+        assert!(incoming.try_recv().is_err());
+
+        // a key is announced as expired when a command finds it so, before
+        // whatever the command then does to it
+        run_in_session(
+            &mut subscriber,
+            &state,
+            &["subscribe", "__keyevent@0__:expired"],
+        );
+        run_in_session(
+            &mut subscriber,
+            &state,
+            &["subscribe", "__keyevent@0__:set"],
+        );
+        run_command(&state, &["config", "set", "notify-keyspace-events", "Ex$"]);
+        run_command(&state, &["set", "a", "1", "px", "20"]);
+        run_command(&state, &["set", "b", "1", "px", "20"]);
+        while incoming.try_recv().is_ok() {}
+        thread::sleep(Duration::from_millis(40));
+        run_command(&state, &["get", "a"]);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$22\r\n__keyevent@0__:expired\r\n$1\r\na\r\n"
+        );
+        assert!(incoming.try_recv().is_err());
+        run_command(&state, &["set", "b", "2"]);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$22\r\n__keyevent@0__:expired\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$18\r\n__keyevent@0__:set\r\n$1\r\nb\r\n"
+        );
+        assert!(incoming.try_recv().is_err());
+
+        // and once the active cycle drops it, if nothing looked at it first
+        run_command(&state, &["config", "set", "notify-keyspace-events", "Ex"]);
+        run_command(&state, &["set", "short", "1", "px", "20"]);
+        let state_for_expiry = state.clone();
+        thread::spawn(move || run_active_expiry(state_for_expiry));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$22\r\n__keyevent@0__:expired\r\n$5\r\nshort\r\n"
+        );
+    }
+
+    #[test]
+    fn test_debug_object() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "s", "hello"]);
+        assert_eq!(
+            run_command(&state, &["debug", "object", "s"]),
+            b"$56\r\ntype:string encoding:embstr serializedlength:6 ttl_ms:-1\r\n"
+        );
+        run_command(&state, &["rpush", "l", "a", "b"]);
+        run_command(&state, &["pexpire", "l", "100000"]);
+        let reply = run_command(&state, &["debug", "object", "l"]);
+        let reply = String::from_utf8(reply).unwrap();
+        assert!(reply.contains("type:list encoding:listpack serializedlength:5 ttl_ms:"));
+        let ttl: u64 = reply
+            .trim_end()
+            .rsplit(':')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(ttl > 99_000 && ttl <= 100_000);
+        assert_eq!(
+            run_command(&state, &["debug", "object", "missing"]),
+            RespValue::error(NO_SUCH_KEY_ERROR).to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_debug_ignored_subcommands() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["debug", "quicklist-packed-threshold", "1K"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["DEBUG", "STRINGMATCH-LEN", "x", "y"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["debug", "segfault"]),
+            RespValue::error("ERR unknown subcommand 'segfault' for 'debug', try SLEEP or OBJECT")
+                .to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_client_list() {
+        let (mut first, address) = spawn_test_server_with_address();
+        let run = |stream: &mut TcpStream, args: &[&str]| {
+            stream.write_all(&encode_command(args)).unwrap();
+            let mut reply = vec![0_u8; 1024];
+            let read_bytes = stream.read(&mut reply).unwrap();
+            reply.truncate(read_bytes);
+            String::from_utf8(reply).unwrap()
+        };
+        assert_eq!(run(&mut first, &["CLIENT", "SETNAME", "first"]), "+OK\r\n");
+        let mut second = TcpStream::connect(address).unwrap();
+        let list = run(&mut second, &["CLIENT", "LIST"]);
+        let lines: Vec<&str> = list.split("\r\n").nth(1).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2);
+        let first_addr = first.local_addr().unwrap();
+        assert!(lines[0].starts_with(&format!("id=1 addr={} name=first ", first_addr)));
+        let second_addr = second.local_addr().unwrap();
+        assert!(lines[1].starts_with(&format!("id=2 addr={} name= ", second_addr)));
+
+        // a connection that's gone is taken off
+        drop(first);
+        thread::sleep(Duration::from_millis(50));
+        let list = run(&mut second, &["CLIENT", "LIST"]);
+        assert_eq!(list.matches("id=").count(), 1);
+    }
+
+    #[test]
+    fn test_client_hanging_up_mid_reply() {
+        let (mut first, address) = spawn_test_server_with_address();
+        let value = "x".repeat(1 << 20);
+        first
+            .write_all(&encode_command(&["SET", "big", &value]))
+            .unwrap();
+        let mut reply = [0_u8; 5];
+        first.read_exact(&mut reply).unwrap();
+
+        // far more than the socket buffers hold, so writing fails part way
+        let mut second = TcpStream::connect(address).unwrap();
+        second
+            .write_all(&encode_command(&["GET", "big"]).repeat(20))
+            .unwrap();
+        drop(second);
+        thread::sleep(Duration::from_millis(100));
+
+        first
+            .write_all(&encode_command(&["CLIENT", "LIST"]))
+            .unwrap();
+        let mut reply = vec![0_u8; 1024];
+        let read_bytes = first.read(&mut reply).unwrap();
+        let list = String::from_utf8_lossy(&reply[..read_bytes]).to_string();
+        assert_eq!(list.matches("id=").count(), 1);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let (mut idle, address) = spawn_test_server_with_address();
+        let mut subscriber = TcpStream::connect(address).unwrap();
+        subscriber
+            .write_all(&encode_command(&["SUBSCRIBE", "news"]))
+            .unwrap();
+        let mut reply = [0_u8; 33];
+        subscriber.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+        idle.write_all(&encode_command(&["CONFIG", "SET", "timeout", "1"]))
+            .unwrap();
+        let mut reply = [0_u8; 5];
+        idle.read_exact(&mut reply).unwrap();
+        // waiting on a list with nothing else to say is being idle too
+        let mut blocked = TcpStream::connect(address).unwrap();
+        blocked
+            .write_all(&encode_command(&["BLPOP", "queue", "0"]))
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(1500));
+        let mut rest = vec![];
+        assert_eq!(idle.read_to_end(&mut rest).unwrap(), 0);
+        assert_eq!(blocked.read_to_end(&mut rest).unwrap(), 0);
+        // subscribers wait for messages however long it takes
+        subscriber.write_all(&encode_command(&["PING"])).unwrap();
+        let mut reply = vec![0_u8; 1024];
+        let read_bytes = subscriber.read(&mut reply).unwrap();
+        assert_eq!(&reply[..read_bytes], b"*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_used_memory() {
+        let state = SharedState::default();
+        let used = || {
+            let info = String::from_utf8(run_command(&state, &["info", "memory"])).unwrap();
+            let line = info
+                .lines()
+                .find_map(|line| line.strip_prefix("used_memory:"))
+                .unwrap()
+                .to_string();
+            line.parse::<usize>().unwrap()
+        };
+        assert_eq!(used(), 0);
+        run_command(&state, &["set", "s", "hello"]);
+        // 64 bytes of overhead per key, as in MEMORY USAGE
+        assert_eq!(used(), 70);
+        run_command(&state, &["set", "s", "world"]);
+        assert_eq!(used(), 70);
+        run_command(&state, &["rpush", "s", "x"]);
+        assert_eq!(used(), 70);
+        run_command(&state, &["rpush", "l", "a", "bc"]);
+        assert_eq!(used(), 170);
+        run_command(&state, &["del", "s"]);
+        assert_eq!(used(), 100);
+        run_command(&state, &["flushall"]);
+        assert_eq!(used(), 0);
+    }
+
+    #[test]
+    fn test_eviction_is_announced() {
+        let state = SharedState::default();
+        let (mut subscriber, mut incoming) = test_session(&state);
+        run_in_session(&mut subscriber, &state, &["psubscribe", "__keyevent@0__:*"]);
+        run_command(&state, &["set", "old", "v"]);
+        run_command(&state, &["config", "set", "notify-keyspace-events", "EAg"]);
+        // room for one key and the next SET, but not both keys and the SET
+        run_command(&state, &["config", "set", "maxmemory", "150"]);
+        run_command(
+            &state,
+            &["config", "set", "maxmemory-policy", "allkeys-lru"],
+        );
+        let (mut session, _incoming) = test_session(&state);
+        run_in_session(&mut session, &state, &["set", "new", "v"]);
+        let message = incoming.try_recv().unwrap();
+        assert!(message.ends_with(b"$22\r\n__keyevent@0__:evicted\r\n$3\r\nold\r\n"));
+        // followed by the SET itself, and nothing about a DEL
+        assert!(incoming.try_recv().unwrap().ends_with(b"$3\r\nnew\r\n"));
+        assert!(incoming.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_maxmemory() {
+        let state = SharedState::default();
+        let (mut session, _incoming) = test_session(&state);
+        let mut run = |args: &[&str]| run_in_session(&mut session, &state, args);
+        let value = "x".repeat(100);
+        run(&["config", "set", "maxmemory", "1000"]);
+        let mut stored = 0;
+        while run(&["set", &stored.to_string(), &value]) == b"+OK\r\n" {
+            stored += 1;
+        }
+        assert!((5..10).contains(&stored));
+        assert_eq!(
+            run(&["rpush", "list", &value]),
+            RespValue::error(OOM_ERROR).to_bytes()
+        );
+        // what doesn't add anything still works
+        assert_eq!(
+            run(&["get", "0"]),
+            format!("$100\r\n{}\r\n", value).as_bytes()
+        );
+        assert_eq!(run(&["del", "1", "2"]), b":2\r\n");
+        assert_eq!(run(&["set", "1", &value]), b"+OK\r\n");
+
+        // with eviction on, the keys that sat idle make way instead
+        run(&["config", "set", "maxmemory-policy", "allkeys-lru"]);
+        for i in 0..50 {
+            assert_eq!(run(&["set", &format!("new{}", i), &value]), b"+OK\r\n");
+        }
+        // keys are sampled at random, so only the one just written is sure to stay
+        assert_eq!(run(&["exists", "new49"]), b":1\r\n");
+        // 1000 bytes hold 6 keys of 165
+        let keys = String::from_utf8(run(&["dbsize"])).unwrap();
+        assert!((1..=6).contains(&keys[1..].trim_end().parse::<i64>().unwrap()));
+    }
+
+    #[test]
+    fn test_blpop() {
+        let state = SharedState::default();
+        run_command(&state, &["rpush", "b", "1", "2"]);
+        let pop = |args: &[&str]| {
+            let (mut session, _incoming) = test_session(&state);
+            run_in_session(&mut session, &state, args)
+        };
+        assert_eq!(
+            pop(&["blpop", "a", "b", "1"]),
+            b"*2\r\n$1\r\nb\r\n$1\r\n1\r\n"
+        );
+        assert_eq!(pop(&["brpop", "b", "0.1"]), b"*2\r\n$1\r\nb\r\n$1\r\n2\r\n");
+        let started = Instant::now();
+        assert_eq!(pop(&["blpop", "b", "0.05"]), b"*-1\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        run_command(&state, &["set", "s", "x"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(pop(&["blpop", "s", "0"]), wrongtype);
+        assert_eq!(pop(&["blpop", "b", "-1"]), b"-ERR timeout is negative\r\n");
+        assert_eq!(
+            pop(&["blpop", "b", "soon"]),
+            b"-ERR timeout is not a float or out of range\r\n"
+        );
+        assert_eq!(
+            pop(&["blpop", "1"]),
+            b"-ERR wrong number of arguments for 'blpop' command\r\n"
+        );
+
+        // queued in a transaction it doesn't wait
+        let (mut session, _incoming) = test_session(&state);
+        run_in_session(&mut session, &state, &["multi"]);
+        run_in_session(&mut session, &state, &["blpop", "b", "0"]);
+        assert_eq!(
+            run_in_session(&mut session, &state, &["exec"]),
+            b"*1\r\n*-1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_blpop_waits_for_push() {
+        let (mut pusher, address) = spawn_test_server_with_address();
+        let mut first = TcpStream::connect(address).unwrap();
+        first
+            .write_all(&encode_command(&["BLPOP", "queue", "0"]))
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let mut second = TcpStream::connect(address).unwrap();
+        second
+            .write_all(&encode_command(&["BRPOP", "other", "queue", "5"]))
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        // both are blocked, and the one that blocked first is served first
+        pusher
+            .write_all(&encode_command(&["RPUSH", "queue", "job1"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut pusher, 4), b":1\r\n");
+        let job1 = b"*2\r\n$5\r\nqueue\r\n$4\r\njob1\r\n";
+        assert_eq!(read_reply(&mut first, job1.len()), job1);
+        pusher
+            .write_all(&encode_command(&["LPUSH", "queue", "job2"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut pusher, 4), b":1\r\n");
+        let job2 = b"*2\r\n$5\r\nqueue\r\n$4\r\njob2\r\n";
+        assert_eq!(read_reply(&mut second, job2.len()), job2);
+
+        pusher
+            .write_all(&encode_command(&["LLEN", "queue"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut pusher, 4), b":0\r\n");
+    }
+
+    #[test]
+    fn test_blpop_client_hangs_up() {
+        let (mut pusher, address) = spawn_test_server_with_address();
+        let block = || {
+            let mut client = TcpStream::connect(address).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            client
+                .write_all(&encode_command(&["BLPOP", "queue", "5"]))
+                .unwrap();
+            thread::sleep(Duration::from_millis(50));
+            client
+        };
+        // the one in front leaving lets the next in line have the push
+        let gone = block();
+        let mut waiting = block();
+        drop(gone);
+        thread::sleep(Duration::from_millis(50));
+        pusher
+            .write_all(&encode_command(&["RPUSH", "queue", "job1"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut pusher, 4), b":1\r\n");
+        let job1 = b"*2\r\n$5\r\nqueue\r\n$4\r\njob1\r\n";
+        assert_eq!(read_reply(&mut waiting, job1.len()), job1);
+
+        // and with nobody else waiting the push stays in the list
+        drop(block());
+        thread::sleep(Duration::from_millis(50));
+        pusher
+            .write_all(&encode_command(&["RPUSH", "queue", "job2"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut pusher, 4), b":1\r\n");
+        pusher
+            .write_all(&encode_command(&["LLEN", "queue"]))
+            .unwrap();
+        assert_eq!(read_reply(&mut pusher, 4), b":1\r\n");
+    }
+
+    #[test]
+    fn test_many_blocked_clients() {
+        // blocked clients wait on the runtime, so a handful of threads
+        // serves any number of them
+        let mut runtime = runtime::Builder::new_multi_thread();
+        runtime.worker_threads(2).max_blocking_threads(2);
+        let (mut pusher, address) = spawn_test_server_on(runtime);
+        pusher
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut blocked: Vec<TcpStream> = (0..50)
+            .map(|_| {
+                let mut client = TcpStream::connect(address).unwrap();
+                client
+                    .write_all(&encode_command(&["BLPOP", "queue", "5"]))
+                    .unwrap();
+                client
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(100));
+
+        let mut push = vec!["RPUSH", "queue"];
+        push.extend(["job"; 50]);
+        pusher.write_all(&encode_command(&push)).unwrap();
+        assert_eq!(read_reply(&mut pusher, 5), b":50\r\n");
+        let job = b"*2\r\n$5\r\nqueue\r\n$3\r\njob\r\n";
+        for client in &mut blocked {
+            client
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            assert_eq!(read_reply(client, job.len()), job);
+        }
+    }
+
+    #[test]
+    fn test_lrem() {
+        let state = SharedState::default();
+        let list = |state: &SharedState| run_command(state, &["lrange", "l", "0", "-1"]);
+        run_command(&state, &["rpush", "l", "a", "b", "a", "c", "a", "b"]);
+        assert_eq!(run_command(&state, &["lrem", "l", "2", "a"]), b":2\r\n");
+        assert_eq!(
+            list(&state),
+            b"*4\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(run_command(&state, &["lrem", "l", "-1", "b"]), b":1\r\n");
+        assert_eq!(list(&state), b"*3\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\na\r\n");
+        assert_eq!(run_command(&state, &["lrem", "l", "5", "z"]), b":0\r\n");
+        run_command(&state, &["rpush", "l", "c", "c"]);
+        assert_eq!(run_command(&state, &["lrem", "l", "0", "c"]), b":3\r\n");
+        assert_eq!(list(&state), b"*2\r\n$1\r\nb\r\n$1\r\na\r\n");
+
+        // taking the last elements takes the key
+        assert_eq!(run_command(&state, &["lrem", "l", "-5", "a"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["lrem", "l", "0", "b"]), b":1\r\n");
+        assert_eq!(run_command(&state, &["exists", "l"]), b":0\r\n");
+        assert_eq!(run_command(&state, &["lrem", "l", "0", "b"]), b":0\r\n");
+
+        run_command(&state, &["set", "s", "x"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["lrem", "s", "0", "x"]), wrongtype);
+    }
+
+    #[test]
+    fn test_lpos() {
+        let state = SharedState::default();
+        run_command(
+            &state,
+            &["rpush", "l", "a", "b", "c", "1", "2", "3", "c", "c"],
+        );
+        assert_eq!(run_command(&state, &["lpos", "l", "c"]), b":2\r\n");
+        assert_eq!(run_command(&state, &["lpos", "l", "z"]), b"$-1\r\n");
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "rank", "2"]),
+            b":6\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "RANK", "-1"]),
+            b":7\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "rank", "4"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "count", "2"]),
+            b"*2\r\n:2\r\n:6\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "count", "0"]),
+            b"*3\r\n:2\r\n:6\r\n:7\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "rank", "-2", "count", "0"]),
+            b"*2\r\n:6\r\n:2\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "count", "0", "maxlen", "7"]),
+            b"*2\r\n:2\r\n:6\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "a", "rank", "-1", "maxlen", "3"]),
+            b"$-1\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "missing", "a", "count", "1"]),
+            b"*0\r\n"
+        );
+        assert_eq!(run_command(&state, &["lpos", "missing", "a"]), b"$-1\r\n");
+
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "count", "-1"]),
+            b"-ERR COUNT can't be negative\r\n"
+        );
+        assert!(run_command(&state, &["lpos", "l", "c", "rank", "0"])
+            .starts_with(b"-ERR RANK can't be zero"));
+        let syntax_error = RespValue::error(SYNTAX_ERROR).to_bytes();
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "rank"]),
+            syntax_error
+        );
+        assert_eq!(
+            run_command(&state, &["lpos", "l", "c", "first", "1"]),
+            syntax_error
+        );
+    }
+
+    #[test]
+    fn test_ltrim() {
+        let state = SharedState::default();
+        let list = |state: &SharedState| run_command(state, &["lrange", "l", "0", "-1"]);
+        run_command(&state, &["rpush", "l", "a", "b", "c", "d", "e"]);
+        assert_eq!(run_command(&state, &["ltrim", "l", "1", "-2"]), b"+OK\r\n");
+        assert_eq!(list(&state), b"*3\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n");
+        assert_eq!(
+            run_command(&state, &["ltrim", "l", "-100", "999"]),
+            b"+OK\r\n"
+        );
+        assert_eq!(run_command(&state, &["llen", "l"]), b":3\r\n");
+        assert_eq!(run_command(&state, &["ltrim", "l", "-2", "-1"]), b"+OK\r\n");
+        assert_eq!(list(&state), b"*2\r\n$1\r\nc\r\n$1\r\nd\r\n");
+
+        // a range holding nothing takes the key
+        assert_eq!(run_command(&state, &["ltrim", "l", "5", "10"]), b"+OK\r\n");
+        assert_eq!(run_command(&state, &["exists", "l"]), b":0\r\n");
+        run_command(&state, &["rpush", "l", "a"]);
+        assert_eq!(run_command(&state, &["ltrim", "l", "1", "0"]), b"+OK\r\n");
+        assert_eq!(run_command(&state, &["exists", "l"]), b":0\r\n");
+        assert_eq!(
+            run_command(&state, &["ltrim", "missing", "0", "1"]),
+            b"+OK\r\n"
+        );
+
+        run_command(&state, &["set", "s", "x"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["ltrim", "s", "0", "1"]), wrongtype);
+    }
+
+    #[test]
+    fn test_lmove() {
+        let state = SharedState::default();
+        run_command(&state, &["rpush", "src", "a", "b", "c"]);
+        assert_eq!(
+            run_command(&state, &["lmove", "src", "dst", "left", "right"]),
+            b"$1\r\na\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lmove", "src", "dst", "RIGHT", "LEFT"]),
+            b"$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lrange", "dst", "0", "-1"]),
+            b"*2\r\n$1\r\nc\r\n$1\r\na\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["rpoplpush", "src", "dst"]),
+            b"$1\r\nb\r\n"
+        );
+        assert_eq!(run_command(&state, &["exists", "src"]), b":0\r\n");
+        assert_eq!(
+            run_command(&state, &["rpoplpush", "src", "dst"]),
+            b"$-1\r\n"
+        );
+
+        // moving onto itself rotates the list
+        assert_eq!(
+            run_command(&state, &["rpoplpush", "dst", "dst"]),
+            b"$1\r\na\r\n"
+        );
+        assert_eq!(
+            run_command(&state, &["lrange", "dst", "0", "-1"]),
+            b"*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        run_command(&state, &["rpush", "one", "x"]);
+        assert_eq!(
+            run_command(&state, &["lmove", "one", "one", "left", "left"]),
+            b"$1\r\nx\r\n"
+        );
+        assert_eq!(run_command(&state, &["llen", "one"]), b":1\r\n");
+
+        // a destination of the wrong type leaves the source alone
+        run_command(&state, &["set", "s", "x"]);
+        let wrongtype = RespValue::error(storage::WRONGTYPE_ERROR).to_bytes();
+        assert_eq!(run_command(&state, &["rpoplpush", "dst", "s"]), wrongtype);
+        assert_eq!(run_command(&state, &["llen", "dst"]), b":3\r\n");
+        assert_eq!(run_command(&state, &["rpoplpush", "s", "dst"]), wrongtype);
+        assert_eq!(
+            run_command(&state, &["lmove", "dst", "x", "up", "left"]),
+            RespValue::error(SYNTAX_ERROR).to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_active_expiry() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "short", "1", "px", "50"]);
+        run_command(&state, &["set", "kept", "1"]);
+        let state_for_expiry = state.clone();
+        thread::spawn(move || run_active_expiry(state_for_expiry));
+
+        thread::sleep(Duration::from_millis(300));
+        // nothing read the key, so only the background cycle could have removed it
+        let stored: usize = state
+            .storage
+            .db(0)
+            .write_all()
+            .iter_mut()
+            .map(|storage| storage.len())
+            .sum();
+        assert_eq!(stored, 1);
+        assert_eq!(run_command(&state, &["dbsize"]), b":1\r\n");
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let state = SharedState::default();
+        assert_eq!(
+            run_command(&state, &["FROBNICATE", "x"]),
+            b"-ERR unknown command 'FROBNICATE'\r\n"
+        );
+    }
+
+    fn sorted_members(state: &SharedState, args: &[&str]) -> Vec<Vec<u8>> {
+        let keys = args[1..].iter().map(|key| key.to_string()).collect();
+        let command = match args[0] {
+            "sinter" => Command::SInter(keys),
+            "sunion" => Command::SUnion(keys),
+            _ => Command::SDiff(keys),
+        };
+        let RespValue::Array(Some(items)) = execute_command(command, &mut 0, state) else {
+            panic!("expected an array");
+        };
+        let mut members: Vec<Vec<u8>> = items
+            .into_iter()
+            .map(|item| match item {
+                RespValue::Bulk(Some(member)) => member,
+                other => panic!("unexpected item {:?}", other),
+            })
+            .collect();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn test_set_algebra() {
+        let state = SharedState::default();
+        run_command(&state, &["sadd", "a", "1", "2", "3"]);
+        run_command(&state, &["sadd", "b", "2", "3", "4"]);
+        run_command(&state, &["sadd", "c", "3"]);
+
+        assert_eq!(
+            sorted_members(&state, &["sinter", "a", "b", "c"]),
+            vec![b"3"]
+        );
+        assert_eq!(
+            sorted_members(&state, &["sunion", "a", "b", "missing"]),
+            vec![b"1", b"2", b"3", b"4"]
+        );
+        assert!(sorted_members(&state, &["sinter", "a", "missing"]).is_empty());
+
+        // the first set is the one the others are subtracted from
+        assert_eq!(sorted_members(&state, &["sdiff", "a", "b"]), vec![b"1"]);
+        assert_eq!(sorted_members(&state, &["sdiff", "b", "a"]), vec![b"4"]);
+        assert_eq!(
+            sorted_members(&state, &["sdiff", "b", "c"]),
+            vec![b"2", b"4"]
+        );
+    }
+
+    #[test]
+    fn test_mget() {
+        let state = SharedState::default();
+        run_command(&state, &["set", "a", "1"]);
+        run_command(&state, &["set", "c", "33"]);
+        assert_eq!(
+            run_command(&state, &["mget", "a", "b", "c"]),
+            b"*3\r\n$1\r\n1\r\n$-1\r\n$2\r\n33\r\n"
+        );
     }
 }
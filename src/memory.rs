@@ -0,0 +1,147 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use crate::storage::{Databases, StorageEntry, Value, SHARD_COUNT};
+
+/// What a key costs beyond its bytes: its slot in the map and the entry.
+const ENTRY_OVERHEAD: usize = 64;
+/// What each element of a collection costs beyond its bytes.
+const ELEMENT_OVERHEAD: usize = 16;
+
+/// How many keys eviction looks at to pick one, as `maxmemory-samples` does
+/// in Redis.
+const EVICTION_SAMPLES: usize = 5;
+
+/// An approximation of the bytes `key` and its entry take up.
+pub fn entry_usage(key: &str, entry: &StorageEntry) -> usize {
+    ENTRY_OVERHEAD + key.len() + value_usage(&entry.value)
+}
+
+/// The most a write command with these arguments can add: one new entry
+/// holding every argument as an element.
+pub fn write_usage(args: &[Vec<u8>]) -> usize {
+    ENTRY_OVERHEAD
+        + args
+            .iter()
+            .map(|arg| arg.len() + ELEMENT_OVERHEAD)
+            .sum::<usize>()
+}
+
+fn value_usage(value: &Value) -> usize {
+    match value {
+        Value::Str(value) => value.len(),
+        Value::List(list) => list
+            .iter()
+            .map(|element| element.len() + ELEMENT_OVERHEAD)
+            .sum(),
+        Value::Hash(hash) => hash
+            .iter()
+            .map(|(field, value)| field.len() + value.len() + ELEMENT_OVERHEAD)
+            .sum(),
+        Value::Set(set) => set
+            .iter()
+            .map(|member| member.len() + ELEMENT_OVERHEAD)
+            .sum(),
+        // each member comes with an 8 byte score
+        Value::ZSet(zset) => zset
+            .iter()
+            .map(|(member, _score)| member.len() + 8 + ELEMENT_OVERHEAD)
+            .sum(),
+    }
+}
+
+fn random() -> usize {
+    // a fresh `RandomState` is keyed differently every time
+    RandomState::new().build_hasher().finish() as usize
+}
+
+/// The key eviction should drop next, from a sample of keys across every
+/// database: the one that has gone unused the longest, like Redis's
+/// approximated LRU. When `volatile` is set the sample is drawn from the keys
+/// with an expiry alone, however few of them there are. Returns the key's
+/// database and name.
+pub fn pick_lru(databases: &Databases, volatile: bool) -> Option<(usize, String)> {
+    let candidate = |entry: &StorageEntry| !volatile || entry.expire_timestamp.is_some();
+    let mut occupied = vec![];
+    for (db, storage_ref) in databases.iter().enumerate() {
+        for index in 0..SHARD_COUNT {
+            if storage_ref.read_shard(index).values().any(candidate) {
+                occupied.push((db, index));
+            }
+        }
+    }
+    if occupied.is_empty() {
+        return None;
+    }
+    let mut picked: Option<(Duration, usize, String)> = None;
+    for _ in 0..EVICTION_SAMPLES {
+        let (db, index) = occupied[random() % occupied.len()];
+        let storage = databases.db(db).read_shard(index);
+        let mut candidates = storage.iter().filter(|(_key, entry)| candidate(entry));
+        let count = candidates.clone().count();
+        if count == 0 {
+            continue;
+        }
+        let Some((key, entry)) = candidates.nth(random() % count) else {
+            continue;
+        };
+        let idle = entry.idle_time();
+        if picked
+            .as_ref()
+            .is_none_or(|(most_idle, ..)| idle > *most_idle)
+        {
+            picked = Some((idle, db, key.clone()));
+        }
+    }
+    picked.map(|(_idle, db, key)| (db, key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_entry_usage() {
+        let entry = StorageEntry::string(b"hello".to_vec(), None);
+        assert_eq!(entry_usage("key", &entry), ENTRY_OVERHEAD + 3 + 5);
+        let list = Value::List([b"a".to_vec(), b"bc".to_vec()].into());
+        let entry = StorageEntry::new(list, None);
+        assert_eq!(
+            entry_usage("l", &entry),
+            ENTRY_OVERHEAD + 1 + 3 + 2 * ELEMENT_OVERHEAD
+        );
+        // RPUSH l a bc
+        let args = [
+            b"RPUSH".to_vec(),
+            b"l".to_vec(),
+            b"a".to_vec(),
+            b"bc".to_vec(),
+        ];
+        assert!(write_usage(&args) >= entry_usage("l", &entry));
+    }
+
+    #[test]
+    fn test_pick_lru() {
+        let databases = Databases::default();
+        assert_eq!(pick_lru(&databases, false), None);
+        let entry = StorageEntry::string(b"v".to_vec(), None);
+        databases.db(1).write("k").insert("k".to_string(), entry);
+        assert_eq!(pick_lru(&databases, false), Some((1, "k".to_string())));
+        assert_eq!(pick_lru(&databases, true), None);
+
+        // however outnumbered, a key with an expiry is always found
+        for i in 0..100 {
+            let key = i.to_string();
+            let entry = StorageEntry::string(b"v".to_vec(), None);
+            databases.db(0).write(&key).insert(key.clone(), entry);
+        }
+        let entry = StorageEntry::string(b"v".to_vec(), Some(u64::MAX));
+        databases.db(2).write("t").insert("t".to_string(), entry);
+        for _ in 0..20 {
+            assert_eq!(pick_lru(&databases, true), Some((2, "t".to_string())));
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    glob::glob_match,
+    resp::{Protocol, RespValue},
+};
+
+/// Where a connection's pushed messages go: its id, so it can be found again
+/// to unsubscribe, the queue its writer task drains to the socket, and the
+/// protocol to encode them in.
+#[derive(Clone)]
+pub struct Subscriber {
+    pub id: u64,
+    pub outgoing: UnboundedSender<Vec<u8>>,
+    pub protocol: Protocol,
+}
+
+/// Whether a subscription names one exact channel or a glob pattern of them.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SubscriptionKind {
+    Channel,
+    Pattern,
+}
+
+/// The letters `notify-keyspace-events` can hold.
+pub const KEYSPACE_EVENT_FLAGS: &str = "KEAg$xe";
+
+/// A `notify-keyspace-events` setting packed into one bit per letter of
+/// `KEYSPACE_EVENT_FLAGS`, so it fits in an atomic that writes can check
+/// without taking a lock. Letters that aren't flags are left out.
+pub fn keyspace_event_bits(setting: &str) -> u32 {
+    setting
+        .chars()
+        .filter_map(|flag| KEYSPACE_EVENT_FLAGS.find(flag))
+        .fold(0, |bits, index| bits | 1 << index)
+}
+
+fn has_flag(bits: u32, flag: char) -> bool {
+    KEYSPACE_EVENT_FLAGS
+        .find(flag)
+        .is_some_and(|index| bits & 1 << index != 0)
+}
+
+/// Channel and pattern subscriptions across every connection.
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<String, Vec<Subscriber>>,
+    patterns: HashMap<String, Vec<Subscriber>>,
+}
+
+impl PubSub {
+    fn subscriptions(&mut self, kind: SubscriptionKind) -> &mut HashMap<String, Vec<Subscriber>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+        }
+    }
+
+    /// Subscribes a connection, or updates how it's reached if it already is.
+    pub fn subscribe(&mut self, kind: SubscriptionKind, name: &str, subscriber: &Subscriber) {
+        let subscribers = self
+            .subscriptions(kind)
+            .entry(name.to_string())
+            .or_default();
+        match subscribers
+            .iter_mut()
+            .find(|existing| existing.id == subscriber.id)
+        {
+            Some(existing) => *existing = subscriber.clone(),
+            None => subscribers.push(subscriber.clone()),
+        }
+    }
+
+    pub fn unsubscribe(&mut self, kind: SubscriptionKind, name: &str, id: u64) {
+        let subscriptions = self.subscriptions(kind);
+        if let Some(subscribers) = subscriptions.get_mut(name) {
+            subscribers.retain(|subscriber| subscriber.id != id);
+            if subscribers.is_empty() {
+                subscriptions.remove(name);
+            }
+        }
+    }
+
+    /// Sends `message` to everyone subscribed to `channel`, directly or
+    /// through a matching pattern, returning how many received it. A
+    /// connection matching several ways gets a copy for each. Subscribers
+    /// whose connection has gone away are dropped.
+    pub fn publish(&mut self, channel: &str, message: &[u8]) -> usize {
+        let mut received = 0;
+        if let Some(subscribers) = self.channels.get_mut(channel) {
+            let message = RespValue::Push(vec![
+                RespValue::bulk(b"message"),
+                RespValue::bulk(channel.as_bytes()),
+                RespValue::bulk(message),
+            ]);
+            received += deliver(subscribers, &message);
+        }
+
+        for (pattern, subscribers) in self.patterns.iter_mut() {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                continue;
+            }
+            let message = RespValue::Push(vec![
+                RespValue::bulk(b"pmessage"),
+                RespValue::bulk(pattern.as_bytes()),
+                RespValue::bulk(channel.as_bytes()),
+                RespValue::bulk(message),
+            ]);
+            received += deliver(subscribers, &message);
+        }
+
+        self.channels
+            .retain(|_name, subscribers| !subscribers.is_empty());
+        self.patterns
+            .retain(|_name, subscribers| !subscribers.is_empty());
+        received
+    }
+
+    /// Announces that `event` happened to `key` in database `db`, as Redis's
+    /// keyspace notifications do. `flags` is the `notify-keyspace-events`
+    /// setting, as `keyspace_event_bits` packs it: `K` publishes the event to
+    /// `__keyspace@<db>__:<key>` and `E` publishes the key to
+    /// `__keyevent@<db>__:<event>`, as long as `class` is in there too, or
+    /// `A` is. Classes are `g` for generic events like `del` and `expire`, `$`
+    /// for strings, `x` for keys expiring and `e` for keys evicted by
+    /// maxmemory.
+    pub fn notify_keyspace_event(
+        &mut self,
+        flags: u32,
+        class: char,
+        event: &str,
+        db: usize,
+        key: &str,
+    ) {
+        if !has_flag(flags, class) && !has_flag(flags, 'A') {
+            return;
+        }
+        if has_flag(flags, 'K') {
+            self.publish(&format!("__keyspace@{}__:{}", db, key), event.as_bytes());
+        }
+        if has_flag(flags, 'E') {
+            self.publish(&format!("__keyevent@{}__:{}", db, event), key.as_bytes());
+        }
+    }
+}
+
+/// Sends `message` to each subscriber, dropping the ones that are gone.
+fn deliver(subscribers: &mut Vec<Subscriber>, message: &RespValue) -> usize {
+    let resp2 = message.to_bytes();
+    let mut resp3 = vec![];
+    message.encode_as(Protocol::Resp3, &mut resp3);
+    subscribers.retain(|subscriber| {
+        let frame = match subscriber.protocol {
+            Protocol::Resp2 => &resp2,
+            Protocol::Resp3 => &resp3,
+        };
+        subscriber.outgoing.send(frame.clone()).is_ok()
+    });
+    subscribers.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_publish() {
+        let mut pubsub = PubSub::default();
+        let (outgoing, mut incoming) = mpsc::unbounded_channel();
+        let subscriber = Subscriber {
+            id: 1,
+            outgoing,
+            protocol: Protocol::Resp2,
+        };
+        pubsub.subscribe(SubscriptionKind::Channel, "news", &subscriber);
+        pubsub.subscribe(SubscriptionKind::Channel, "news", &subscriber);
+
+        assert_eq!(pubsub.publish("news", b"hi"), 1);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+        assert!(incoming.try_recv().is_err());
+        assert_eq!(pubsub.publish("other", b"hi"), 0);
+
+        pubsub.unsubscribe(SubscriptionKind::Channel, "news", 1);
+        assert_eq!(pubsub.publish("news", b"hi"), 0);
+    }
+
+    #[test]
+    fn test_publish_to_patterns() {
+        let mut pubsub = PubSub::default();
+        let (outgoing, mut incoming) = mpsc::unbounded_channel();
+        let subscriber = Subscriber {
+            id: 1,
+            outgoing,
+            protocol: Protocol::Resp2,
+        };
+        pubsub.subscribe(SubscriptionKind::Pattern, "news.*", &subscriber);
+        pubsub.subscribe(SubscriptionKind::Channel, "news.art", &subscriber);
+
+        // matched both ways, so it's delivered twice
+        assert_eq!(pubsub.publish("news.art", b"hi"), 2);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$8\r\nnews.art\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$8\r\nnews.art\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(pubsub.publish("news.tech", b"hi"), 1);
+        assert_eq!(pubsub.publish("weather", b"hi"), 0);
+
+        pubsub.unsubscribe(SubscriptionKind::Pattern, "news.*", 1);
+        assert_eq!(pubsub.publish("news.tech", b"hi"), 0);
+    }
+
+    #[test]
+    fn test_publish_resp3() {
+        let mut pubsub = PubSub::default();
+        let (outgoing, mut incoming) = mpsc::unbounded_channel();
+        let subscriber = Subscriber {
+            id: 1,
+            outgoing,
+            protocol: Protocol::Resp3,
+        };
+        pubsub.subscribe(SubscriptionKind::Channel, "news", &subscriber);
+        assert_eq!(pubsub.publish("news", b"hi"), 1);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+
+        // subscribing again switches it back to RESP2
+        let subscriber = Subscriber {
+            protocol: Protocol::Resp2,
+            ..subscriber
+        };
+        pubsub.subscribe(SubscriptionKind::Channel, "news", &subscriber);
+        assert_eq!(pubsub.publish("news", b"hi"), 1);
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+    }
+
+    #[test]
+    fn test_publish_drops_closed_connections() {
+        let mut pubsub = PubSub::default();
+        let (outgoing, incoming) = mpsc::unbounded_channel();
+        pubsub.subscribe(
+            SubscriptionKind::Channel,
+            "news",
+            &Subscriber {
+                id: 1,
+                outgoing,
+                protocol: Protocol::Resp2,
+            },
+        );
+        drop(incoming);
+        assert_eq!(pubsub.publish("news", b"hi"), 0);
+        assert!(pubsub.channels.is_empty());
+        assert!(pubsub.patterns.is_empty());
+    }
+
+    #[test]
+    fn test_notify_keyspace_event() {
+        let mut pubsub = PubSub::default();
+        let (outgoing, mut incoming) = mpsc::unbounded_channel();
+        let subscriber = Subscriber {
+            id: 1,
+            outgoing,
+            protocol: Protocol::Resp2,
+        };
+        pubsub.subscribe(SubscriptionKind::Pattern, "__key*__:*", &subscriber);
+
+        pubsub.notify_keyspace_event(keyspace_event_bits("Kg"), 'g', "del", 3, "k");
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*4\r\n$8\r\npmessage\r\n$10\r\n__key*__:*\r\n$16\r\n__keyspace@3__:k\r\n$3\r\ndel\r\n"
+        );
+        assert!(incoming.try_recv().is_err());
+        pubsub.notify_keyspace_event(keyspace_event_bits("EA"), 'x', "expired", 0, "k");
+        assert_eq!(
+            incoming.try_recv().unwrap(),
+            b"*4\r\n$8\r\npmessage\r\n$10\r\n__key*__:*\r\n$22\r\n__keyevent@0__:expired\r\n$1\r\nk\r\n"
+        );
+        // the event's class isn't turned on
+        pubsub.notify_keyspace_event(keyspace_event_bits("KE$"), 'g', "del", 0, "k");
+        assert!(incoming.try_recv().is_err());
+    }
+}
@@ -0,0 +1,602 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    storage::{
+        unix_millis_now, Databases, HashValue, SetValue, ShardedStorage, StorageEntry, Value,
+        DATABASE_COUNT,
+    },
+    zset::SortedSet,
+};
+
+/// Snapshots use the RDB layout Redis itself writes: a magic and version
+/// header, then one record per key, each optionally preceded by its expiry,
+/// then an end marker and a checksum (zero, meaning it isn't checked).
+const MAGIC: &[u8] = b"REDIS";
+const VERSION: &[u8] = b"0011";
+/// The newest format we can read, from Redis 7.4.
+const MAX_VERSION: u32 = 12;
+
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+/// The longest string a compressed one may claim to unpack to, Redis's own
+/// limit on bulk strings. The length comes from the file, so it's checked
+/// before anything is allocated for it.
+const MAX_STRING_LEN: u64 = 512 * 1024 * 1024;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+/// Sorted sets with binary scores, which is how they're written.
+const TYPE_ZSET_2: u8 = 5;
+
+/// A copy of the keyspace, taken under the locks and written out after
+/// they're released: the keys of each database in turn. Expiries are unix
+/// milliseconds, the way the file has them.
+pub type Snapshot = Vec<Vec<(String, Value, Option<u64>)>>;
+
+/// Copies every live key out of `databases`.
+pub fn snapshot(databases: &Databases) -> Snapshot {
+    databases.iter().map(snapshot_db).collect()
+}
+
+fn snapshot_db(storage: &ShardedStorage) -> Vec<(String, Value, Option<u64>)> {
+    storage
+        .read_all()
+        .iter()
+        .flat_map(|storage| storage.iter())
+        .filter(|(_, entry)| !entry.is_expired())
+        .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expire_timestamp))
+        .collect()
+}
+
+/// Writes the whole keyspace to `path`, through a temporary file so a crash
+/// halfway never leaves a truncated snapshot behind.
+pub fn save_to_disk(databases: &Databases, path: &Path) -> io::Result<()> {
+    write_snapshot(&snapshot(databases), path)
+}
+
+pub fn write_snapshot(snapshot: &Snapshot, path: &Path) -> io::Result<()> {
+    let temp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+    let mut out = BufWriter::new(File::create(&temp_path)?);
+    write_to(&mut out, snapshot)?;
+    out.into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// The snapshot in RDB form, as a master sends it to a new replica.
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let mut out = vec![];
+    write_to(&mut out, snapshot).expect("writing to a Vec can't fail");
+    out
+}
+
+fn write_to(out: &mut impl Write, snapshot: &Snapshot) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(VERSION)?;
+
+    for (db, entries) in snapshot.iter().enumerate() {
+        if entries.is_empty() {
+            continue;
+        }
+        out.write_all(&[OPCODE_SELECTDB])?;
+        write_length(out, db as u64)?;
+        for (key, value, expiry) in entries {
+            if let Some(expiry) = expiry {
+                out.write_all(&[OPCODE_EXPIRETIME_MS])?;
+                out.write_all(&expiry.to_le_bytes())?;
+            }
+            let value_type = match value {
+                Value::Str(_) => TYPE_STRING,
+                Value::List(_) => TYPE_LIST,
+                Value::Set(_) => TYPE_SET,
+                Value::Hash(_) => TYPE_HASH,
+                Value::ZSet(_) => TYPE_ZSET_2,
+            };
+            out.write_all(&[value_type])?;
+            write_string(out, key.as_bytes())?;
+            write_value(out, value)?;
+        }
+    }
+
+    out.write_all(&[OPCODE_EOF])?;
+    out.write_all(&[0; 8])
+}
+
+/// Fills `databases` from the snapshot at `path`, returning how many keys it
+/// held. Keys that expired while the server was down are skipped.
+pub fn load_from_disk(databases: &Databases, path: &Path) -> io::Result<usize> {
+    load(databases, &mut BufReader::new(File::open(path)?))
+}
+
+/// Reads a whole RDB stream. Besides the files written here this takes the
+/// ones real Redis writes, as long as every value is a string or one of the
+/// plain (unpacked) list, set and hash encodings.
+pub fn load(databases: &Databases, input: &mut impl Read) -> io::Result<usize> {
+    let header: [u8; 9] = read_bytes(input)?;
+    let (magic, version) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(invalid_data("not an RDB file"));
+    }
+    let version: u32 = std::str::from_utf8(version)
+        .ok()
+        .and_then(|version| version.parse().ok())
+        .ok_or_else(|| invalid_data("malformed RDB version"))?;
+    if version > MAX_VERSION {
+        return Err(invalid_data(&format!(
+            "unsupported RDB version {}",
+            version
+        )));
+    }
+
+    let now = unix_millis_now();
+    let mut loaded = 0;
+    let mut expiry = None;
+    let mut storage = databases.db(0);
+    loop {
+        match read_u8(input)? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => {
+                let db = read_length(input)?;
+                if db >= DATABASE_COUNT as u64 {
+                    return Err(invalid_data(&format!("unsupported database {}", db)));
+                }
+                storage = databases.db(db as usize);
+            }
+            OPCODE_RESIZEDB => {
+                // sizing hints for the hash tables, which ours don't need
+                read_length(input)?;
+                read_length(input)?;
+            }
+            OPCODE_AUX => {
+                // metadata like `redis-ver`, nothing we act on
+                read_string(input)?;
+                read_string(input)?;
+            }
+            OPCODE_EXPIRETIME => {
+                let seconds = u32::from_le_bytes(read_bytes(input)?);
+                expiry = Some(seconds as u64 * 1000);
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expiry = Some(u64::from_le_bytes(read_bytes(input)?));
+            }
+            value_type => {
+                let key = String::from_utf8_lossy(&read_string(input)?).into_owned();
+                let value = read_value(input, value_type)?;
+                match expiry.take() {
+                    Some(expiry) if expiry <= now => {}
+                    expiry => {
+                        storage
+                            .write(&key)
+                            .insert(key, StorageEntry::new(value, expiry));
+                        loaded += 1;
+                    }
+                }
+            }
+        }
+    }
+    // the checksum after EOF isn't verified
+    Ok(loaded)
+}
+
+/// How many bytes `value` takes up in a snapshot, not counting its key.
+pub fn serialized_len(value: &Value) -> usize {
+    let mut out = vec![];
+    // writing to a `Vec` can't fail
+    let _ = write_value(&mut out, value);
+    out.len()
+}
+
+fn write_value(out: &mut impl Write, value: &Value) -> io::Result<()> {
+    match value {
+        Value::Str(value) => write_string(out, value),
+        Value::List(list) => {
+            write_length(out, list.len() as u64)?;
+            list.iter()
+                .try_for_each(|element| write_string(out, element))
+        }
+        Value::Set(set) => {
+            write_length(out, set.len() as u64)?;
+            set.iter().try_for_each(|member| write_string(out, member))
+        }
+        Value::Hash(hash) => {
+            write_length(out, hash.len() as u64)?;
+            hash.iter().try_for_each(|(field, value)| {
+                write_string(out, field)?;
+                write_string(out, value)
+            })
+        }
+        Value::ZSet(zset) => {
+            write_length(out, zset.len() as u64)?;
+            zset.iter().try_for_each(|(member, score)| {
+                write_string(out, member)?;
+                out.write_all(&score.to_le_bytes())
+            })
+        }
+    }
+}
+
+fn read_value(input: &mut impl Read, value_type: u8) -> io::Result<Value> {
+    match value_type {
+        TYPE_STRING => Ok(Value::Str(read_string(input)?)),
+        TYPE_LIST => {
+            let len = read_length(input)?;
+            let list = (0..len)
+                .map(|_| read_string(input))
+                .collect::<io::Result<VecDeque<_>>>()?;
+            Ok(Value::List(list))
+        }
+        TYPE_SET => {
+            let len = read_length(input)?;
+            let set = (0..len)
+                .map(|_| read_string(input))
+                .collect::<io::Result<SetValue>>()?;
+            Ok(Value::Set(set))
+        }
+        TYPE_HASH => {
+            let len = read_length(input)?;
+            let hash = (0..len)
+                .map(|_| Ok((read_string(input)?, read_string(input)?)))
+                .collect::<io::Result<HashValue>>()?;
+            Ok(Value::Hash(hash))
+        }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = read_length(input)?;
+            let mut zset = SortedSet::default();
+            for _ in 0..len {
+                let member = read_string(input)?;
+                let score = if value_type == TYPE_ZSET_2 {
+                    f64::from_le_bytes(read_bytes(input)?)
+                } else {
+                    read_text_score(input)?
+                };
+                if score.is_nan() {
+                    return Err(invalid_data("NaN score in a sorted set"));
+                }
+                zset.insert(member, score);
+            }
+            Ok(Value::ZSet(zset))
+        }
+        other => Err(invalid_data(&format!("unsupported value type {}", other))),
+    }
+}
+
+/// The older sorted set encoding writes scores as text, after a length byte
+/// that has three values set aside for NaN and the infinities.
+fn read_text_score(input: &mut impl Read) -> io::Result<f64> {
+    match read_u8(input)? {
+        253 => Ok(f64::NAN),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        len => {
+            let text = read_raw(input, len as u64)?;
+            std::str::from_utf8(&text)
+                .ok()
+                .and_then(|text| text.parse().ok())
+                .ok_or_else(|| invalid_data("malformed sorted set score"))
+        }
+    }
+}
+
+/// RDB lengths take 1, 2, 5 or 9 bytes depending on size, with the top two
+/// bits of the first byte saying which.
+fn write_length(out: &mut impl Write, len: u64) -> io::Result<()> {
+    if len < 1 << 6 {
+        out.write_all(&[len as u8])
+    } else if len < 1 << 14 {
+        out.write_all(&[0x40 | (len >> 8) as u8, len as u8])
+    } else if len <= u32::MAX as u64 {
+        out.write_all(&[0x80])?;
+        out.write_all(&(len as u32).to_be_bytes())
+    } else {
+        out.write_all(&[0x81])?;
+        out.write_all(&len.to_be_bytes())
+    }
+}
+
+/// A length field, or in the place of a string's length, a marker for a
+/// string stored some other way (see `read_string`).
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+fn read_length_or_encoding(input: &mut impl Read) -> io::Result<Length> {
+    let first = read_u8(input)?;
+    match first >> 6 {
+        0b00 => Ok(Length::Plain((first & 0x3F) as u64)),
+        0b01 => Ok(Length::Plain(
+            (((first & 0x3F) as u64) << 8) | read_u8(input)? as u64,
+        )),
+        0b11 => Ok(Length::Encoded(first & 0x3F)),
+        _ if first == 0x80 => Ok(Length::Plain(u32::from_be_bytes(read_bytes(input)?) as u64)),
+        _ if first == 0x81 => Ok(Length::Plain(u64::from_be_bytes(read_bytes(input)?))),
+        _ => Err(invalid_data(&format!(
+            "unsupported length encoding {:#x}",
+            first
+        ))),
+    }
+}
+
+fn read_length(input: &mut impl Read) -> io::Result<u64> {
+    match read_length_or_encoding(input)? {
+        Length::Plain(len) => Ok(len),
+        Length::Encoded(_) => Err(invalid_data("expected a length")),
+    }
+}
+
+fn write_string(out: &mut impl Write, value: &[u8]) -> io::Result<()> {
+    write_length(out, value.len() as u64)?;
+    out.write_all(value)
+}
+
+/// Strings are either raw bytes with a length, an integer stored in binary
+/// (saving it as text), or LZF compressed.
+fn read_string(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    match read_length_or_encoding(input)? {
+        Length::Plain(len) => read_raw(input, len),
+        Length::Encoded(ENCODING_INT8) => Ok((i8::from_le_bytes(read_bytes(input)?))
+            .to_string()
+            .into_bytes()),
+        Length::Encoded(ENCODING_INT16) => Ok((i16::from_le_bytes(read_bytes(input)?))
+            .to_string()
+            .into_bytes()),
+        Length::Encoded(ENCODING_INT32) => Ok((i32::from_le_bytes(read_bytes(input)?))
+            .to_string()
+            .into_bytes()),
+        Length::Encoded(ENCODING_LZF) => {
+            let compressed_len = read_length(input)?;
+            let len = read_length(input)?;
+            if len > MAX_STRING_LEN {
+                return Err(invalid_data("compressed string too long"));
+            }
+            lzf_decompress(&read_raw(input, compressed_len)?, len as usize)
+        }
+        Length::Encoded(other) => Err(invalid_data(&format!(
+            "unsupported string encoding {}",
+            other
+        ))),
+    }
+}
+
+fn read_raw(input: &mut impl Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut value = vec![];
+    input.take(len).read_to_end(&mut value)?;
+    if value.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(value)
+}
+
+/// Undoes LZF compression. The input is a series of literal runs and back
+/// references into what's been decompressed so far, told apart by the top
+/// three bits of each control byte.
+fn lzf_decompress(input: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let corrupt = || invalid_data("corrupt LZF string");
+    let mut out = Vec::with_capacity(len);
+    let mut input = input.iter().copied();
+    while let Some(control) = input.next() {
+        if control < 1 << 5 {
+            let run = control as usize + 1;
+            let literal: Vec<u8> = input.by_ref().take(run).collect();
+            if literal.len() != run || out.len() + run > len {
+                return Err(corrupt());
+            }
+            out.extend(literal);
+        } else {
+            let mut run = (control >> 5) as usize;
+            if run == 7 {
+                run += input.next().ok_or_else(corrupt)? as usize;
+            }
+            let offset =
+                (((control & 0x1F) as usize) << 8) | input.next().ok_or_else(corrupt)? as usize;
+            let start = out.len().checked_sub(offset + 1).ok_or_else(corrupt)?;
+            if out.len() + run + 2 > len {
+                return Err(corrupt());
+            }
+            // the reference can overlap what it's copying, so go byte by byte
+            for index in start..start + run + 2 {
+                out.push(out[index]);
+            }
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let [byte] = read_bytes(input)?;
+    Ok(byte)
+}
+
+fn read_bytes<const N: usize>(input: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_encoding() {
+        for len in [
+            0,
+            63,
+            64,
+            16383,
+            16384,
+            u32::MAX as u64,
+            u32::MAX as u64 + 1,
+        ] {
+            let mut encoded = vec![];
+            write_length(&mut encoded, len).unwrap();
+            assert_eq!(read_length(&mut encoded.as_slice()).unwrap(), len);
+        }
+    }
+
+    #[test]
+    fn test_serialized_len() {
+        assert_eq!(serialized_len(&Value::Str(b"redis".to_vec())), 6);
+        let list = Value::List(VecDeque::from([b"a".to_vec(), b"bc".to_vec()]));
+        assert_eq!(serialized_len(&list), 6);
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // a literal "a", then a back reference repeating it nine more times
+        let compressed = [0x00, b'a', 0xE0, 0x00, 0x00];
+        assert_eq!(lzf_decompress(&compressed, 10).unwrap(), b"aaaaaaaaaa");
+        assert!(lzf_decompress(&compressed, 11).is_err());
+        assert!(lzf_decompress(&[0x20, 0x05], 2).is_err());
+        // unpacking to more than it said is corrupt too
+        assert!(lzf_decompress(&compressed, 5).is_err());
+    }
+
+    #[test]
+    fn test_compressed_string_too_long() {
+        let mut encoded = vec![0xC0 | ENCODING_LZF];
+        write_length(&mut encoded, 5).unwrap();
+        write_length(&mut encoded, u32::MAX as u64 + 1).unwrap();
+        encoded.extend([0x00, b'a', 0xE0, 0x00, 0x00]);
+        let error = read_string(&mut encoded.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_load_redis_dump() {
+        let mut dump = b"REDIS0011".to_vec();
+        dump.extend(b"\xFA\x09redis-ver\x057.2.0");
+        dump.extend(b"\xFA\x0Aredis-bits\xC0\x40");
+        dump.extend(b"\xFE\x00\xFB\x04\x01");
+        dump.extend(b"\x00\x03foo\x03bar");
+        dump.extend(b"\x00\x03int\xC1\x39\x30");
+        dump.extend(b"\x00\x04long\xC3\x05\x0A\x00a\xE0\x00\x00");
+        // an old-style sorted set, scores as text
+        dump.extend(b"\x03\x02zs\x02\x01a\x031.5\x01b\xFE");
+        let millis = unix_millis_now() + 60_000;
+        dump.push(OPCODE_EXPIRETIME_MS);
+        dump.extend(millis.to_le_bytes());
+        dump.extend(b"\x00\x05later\x01x");
+        dump.extend(b"\xFD\x01\x00\x00\x00\x00\x04past\x01x");
+        dump.extend(b"\xFF\x00\x00\x00\x00\x00\x00\x00\x00");
+
+        let databases = Databases::default();
+        assert_eq!(load(&databases, &mut dump.as_slice()).unwrap(), 5);
+        let storage = databases.db(0);
+        let value = |key: &str| storage.read(key).get(key).map(|entry| entry.value.clone());
+        assert_eq!(value("foo"), Some(Value::Str(b"bar".to_vec())));
+        assert_eq!(value("int"), Some(Value::Str(b"12345".to_vec())));
+        assert_eq!(value("long"), Some(Value::Str(b"aaaaaaaaaa".to_vec())));
+        assert_eq!(value("later"), Some(Value::Str(b"x".to_vec())));
+        assert!(storage.read("later").get("later").unwrap().expire_timestamp == Some(millis));
+        assert_eq!(value("past"), None);
+        let mut zset = SortedSet::default();
+        zset.insert(b"a".to_vec(), 1.5);
+        zset.insert(b"b".to_vec(), f64::INFINITY);
+        assert_eq!(value("zs"), Some(Value::ZSet(zset)));
+    }
+
+    #[test]
+    fn test_load_rejects_unsupported_input() {
+        let databases = Databases::default();
+        let load_bytes = |bytes: &[u8]| load(&databases, &mut &bytes[..]);
+        assert!(load_bytes(b"RDB0011\xFF").is_err());
+        assert!(load_bytes(b"REDIS0099\xFF").is_err());
+        assert!(load_bytes(b"REDIS0011\xFE\x10\xFF").is_err());
+        // type 16 is a listpack-encoded hash
+        assert!(load_bytes(b"REDIS0011\x10\x01h\x00\xFF").is_err());
+        assert!(load_bytes(b"REDIS0011\x00\x03foo").is_err());
+        assert_eq!(load_bytes(b"REDIS0011\xFF").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let databases = Databases::default();
+        let storage = databases.db(0);
+        let expiry = unix_millis_now() + 60_000;
+        storage.write("name").insert(
+            "name".to_string(),
+            StorageEntry::string(b"redis".to_vec(), Some(expiry)),
+        );
+        let list = Value::List(VecDeque::from([b"a".to_vec(), b"b".to_vec()]));
+        storage
+            .write("list")
+            .insert("list".to_string(), StorageEntry::new(list.clone(), None));
+        let hash = Value::Hash(HashValue::from([(b"field".to_vec(), b"value".to_vec())]));
+        storage
+            .write("hash")
+            .insert("hash".to_string(), StorageEntry::new(hash.clone(), None));
+        let set = Value::Set(SetValue::from([b"member".to_vec()]));
+        storage
+            .write("set")
+            .insert("set".to_string(), StorageEntry::new(set.clone(), None));
+        storage.write("gone").insert(
+            "gone".to_string(),
+            StorageEntry::string(b"old".to_vec(), Some(unix_millis_now() - 1000)),
+        );
+        let mut zset = SortedSet::default();
+        zset.insert(b"low".to_vec(), -1.5);
+        zset.insert(b"high".to_vec(), f64::INFINITY);
+        let zset = Value::ZSet(zset);
+        storage
+            .write("zset")
+            .insert("zset".to_string(), StorageEntry::new(zset.clone(), None));
+        databases.db(3).write("name").insert(
+            "name".to_string(),
+            StorageEntry::string(b"three".to_vec(), None),
+        );
+
+        let path = std::env::temp_dir().join(format!("rdb-test-{}.rdb", std::process::id()));
+        save_to_disk(&databases, &path).unwrap();
+        let loaded_databases = Databases::default();
+        assert_eq!(load_from_disk(&loaded_databases, &path).unwrap(), 6);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded_databases.db(0);
+
+        let shard = loaded.read("name");
+        let entry = shard.get("name").unwrap();
+        assert_eq!(entry.value, Value::Str(b"redis".to_vec()));
+        assert_eq!(entry.expire_timestamp, Some(expiry));
+        drop(shard);
+        assert_eq!(loaded.read("list").get("list").unwrap().value, list);
+        assert_eq!(loaded.read("hash").get("hash").unwrap().value, hash);
+        assert_eq!(loaded.read("set").get("set").unwrap().value, set);
+        assert_eq!(loaded.read("zset").get("zset").unwrap().value, zset);
+        assert!(loaded.read("gone").get("gone").is_none());
+        assert_eq!(
+            loaded_databases
+                .db(3)
+                .read("name")
+                .get("name")
+                .unwrap()
+                .value,
+            Value::Str(b"three".to_vec())
+        );
+        assert!(loaded_databases.db(1).read("name").get("name").is_none());
+    }
+}
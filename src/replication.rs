@@ -0,0 +1,338 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Notify, time};
+
+use crate::{
+    execute_command, frame_len, handle_client_message, pubsub::Subscriber, rdb, resp::RespValue,
+    Command, SharedState,
+};
+
+/// How long a replica waits before reconnecting after losing its master.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which side of replication this server is on. A replica tracks whether
+/// its link to the master is up, a master the replicas it's feeding.
+pub struct Replication {
+    /// The master's host and port, when this server is a replica.
+    pub master: Option<(String, u16)>,
+    pub link_up: AtomicBool,
+    /// Names this server's history of writes for the replicas following it.
+    pub replid: String,
+    /// How many bytes of write commands have gone to replicas so far.
+    pub offset: AtomicU64,
+    /// Links to connected replicas. Held while a write is applied and sent
+    /// on, so every replica gets writes in the order they happened. That
+    /// also means writes run one at a time, even to unrelated keys, while
+    /// there's a replica or an AOF; numbering writes as they're applied and
+    /// sending them in that order would lift it, at the cost of buffering
+    /// the ones that finish early.
+    pub replicas: Mutex<Vec<Replica>>,
+    /// The database the stream of writes last selected, `None` when the next
+    /// write has to select one whatever it is. Only taken with `replicas`.
+    pub selected_db: Mutex<Option<usize>>,
+    /// Signalled whenever a replica acknowledges an offset.
+    acks: Notify,
+}
+
+/// A connected replica, and how much of the stream it has confirmed applying.
+pub struct Replica {
+    pub link: Subscriber,
+    pub acked_offset: u64,
+}
+
+impl Default for Replication {
+    fn default() -> Replication {
+        Replication {
+            master: None,
+            link_up: AtomicBool::new(false),
+            replid: random_replid(),
+            offset: AtomicU64::new(0),
+            replicas: Mutex::new(vec![]),
+            selected_db: Mutex::new(None),
+            acks: Notify::new(),
+        }
+    }
+}
+
+/// 40 random hex digits, the shape Redis gives its replication ids.
+fn random_replid() -> String {
+    let mut replid: String = (0..3)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect();
+    replid.truncate(40);
+    replid
+}
+
+impl Replication {
+    /// Builds the replication state from the `replicaof` parameter, which
+    /// is either empty or `<host> <port>`.
+    pub fn from_config(replicaof: &str) -> Result<Replication, String> {
+        let parts: Vec<&str> = replicaof.split_whitespace().collect();
+        let master = match parts.as_slice() {
+            [] => None,
+            [host, port] => match port.parse::<u16>() {
+                Ok(port) => Some((host.to_string(), port)),
+                Err(_e) => return Err(format!("invalid master port '{}'", port)),
+            },
+            _ => return Err(format!("expected '<host> <port>', got '{}'", replicaof)),
+        };
+        Ok(Replication {
+            master,
+            ..Replication::default()
+        })
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// The body of the INFO replication section.
+    pub fn info(&self) -> String {
+        match &self.master {
+            Some((host, port)) => format!(
+                "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\n",
+                host,
+                port,
+                if self.link_up.load(Ordering::SeqCst) {
+                    "up"
+                } else {
+                    "down"
+                }
+            ),
+            None => format!(
+                "role:master\r\nconnected_slaves:{}\r\nmaster_replid:{}\r\nmaster_repl_offset:{}\r\n",
+                self.replicas.lock().unwrap().len(),
+                self.replid,
+                self.offset.load(Ordering::SeqCst)
+            ),
+        }
+    }
+
+    /// Sends a write command (already RESP encoded) to every replica,
+    /// dropping the ones that have gone away.
+    pub fn propagate(&self, replicas: &mut Vec<Replica>, frame: &[u8]) {
+        if replicas.is_empty() {
+            return;
+        }
+        replicas.retain(|replica| replica.link.outgoing.send(frame.to_vec()).is_ok());
+        self.offset.fetch_add(frame.len() as u64, Ordering::SeqCst);
+    }
+
+    /// Records a replica's `REPLCONF ACK`, waking anyone in WAIT.
+    pub fn record_ack(&self, id: u64, offset: u64) {
+        let mut replicas = self.replicas.lock().unwrap();
+        if let Some(replica) = replicas.iter_mut().find(|replica| replica.link.id == id) {
+            replica.acked_offset = replica.acked_offset.max(offset);
+        }
+        self.acks.notify_waiters();
+    }
+
+    /// How many replicas have acknowledged every write sent so far.
+    pub fn acked_replicas(&self) -> usize {
+        let replicas = self.replicas.lock().unwrap();
+        self.count_acked(&replicas, self.offset.load(Ordering::SeqCst))
+    }
+
+    fn count_acked(&self, replicas: &[Replica], offset: u64) -> usize {
+        replicas
+            .iter()
+            .filter(|replica| replica.acked_offset >= offset)
+            .count()
+    }
+
+    /// Waits until `wanted` replicas have acknowledged every write sent so
+    /// far, or `timeout` runs out, and returns how many have. Replicas that
+    /// are behind are asked for an acknowledgement first.
+    pub async fn wait_for_acks(&self, wanted: usize, timeout: Option<Duration>) -> usize {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let target = {
+            let mut replicas = self.replicas.lock().unwrap();
+            let target = self.offset.load(Ordering::SeqCst);
+            if self.count_acked(&replicas, target) < wanted {
+                let getack = RespValue::array(vec![
+                    RespValue::bulk(b"REPLCONF"),
+                    RespValue::bulk(b"GETACK"),
+                    RespValue::bulk(b"*"),
+                ]);
+                self.propagate(&mut replicas, &getack.to_bytes());
+            }
+            target
+        };
+        loop {
+            // listening before counting, so an ack in between isn't missed
+            let notified = self.acks.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let acked = self.count_acked(&self.replicas.lock().unwrap(), target);
+            if acked >= wanted {
+                return acked;
+            }
+            match deadline {
+                None => notified.await,
+                Some(deadline) => {
+                    if time::timeout_at(deadline.into(), notified).await.is_err() {
+                        return acked;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Keeps this replica in sync with its master for as long as the server
+/// runs, reconnecting whenever the link drops.
+pub fn run_replica(state: SharedState) {
+    let Some((host, port)) = state.replication.master.clone() else {
+        return;
+    };
+    loop {
+        if let Err(error) = sync_with_master(&state, &host, port) {
+            eprintln!("replication from {}:{} failed: {}", host, port, error);
+        }
+        state.replication.link_up.store(false, Ordering::SeqCst);
+        thread::sleep(RECONNECT_INTERVAL);
+    }
+}
+
+/// Runs the handshake, loads the master's snapshot in place of everything we
+/// had, then applies the write commands it streams from there on.
+fn sync_with_master(state: &SharedState, host: &str, port: u16) -> io::Result<()> {
+    let mut stream = TcpStream::connect((host, port))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let listening_port = state
+        .config
+        .lock()
+        .unwrap()
+        .get("port")
+        .unwrap_or_default()
+        .to_string();
+
+    send(&mut stream, &["PING"])?;
+    expect_reply(&mut reader, "+PONG")?;
+    send(
+        &mut stream,
+        &["REPLCONF", "listening-port", &listening_port],
+    )?;
+    expect_reply(&mut reader, "+OK")?;
+    send(&mut stream, &["REPLCONF", "capa", "psync2"])?;
+    expect_reply(&mut reader, "+OK")?;
+    send(&mut stream, &["PSYNC", "?", "-1"])?;
+    let reply = read_line(&mut reader)?;
+    if !reply.starts_with("+FULLRESYNC ") {
+        return Err(unexpected_reply(&reply));
+    }
+
+    // the snapshot comes as a bulk string, but without the trailing CRLF
+    let header = read_line(&mut reader)?;
+    let len = header
+        .strip_prefix('$')
+        .and_then(|len| len.parse::<u64>().ok())
+        .ok_or_else(|| unexpected_reply(&header))?;
+    let mut snapshot = vec![];
+    (&mut reader).take(len).read_to_end(&mut snapshot)?;
+    if snapshot.len() as u64 != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    {
+        // nothing gets to see the keyspace half replaced
+        let _exclusive = state.storage.exclusive_access();
+        for storage_ref in state.storage.iter() {
+            for storage in storage_ref.write_all().iter_mut() {
+                storage.clear();
+            }
+        }
+        rdb::load(&state.storage, &mut snapshot.as_slice())?;
+    }
+    state.replication.link_up.store(true, Ordering::SeqCst);
+
+    // frames are taken one at a time, to keep count of how many bytes of the
+    // stream have been applied for when the master asks
+    let mut offset = 0;
+    let mut db = 0;
+    let mut buffer: Vec<u8> = vec![];
+    loop {
+        let Some(len) = frame_len(&buffer) else {
+            let mut chunk = [0_u8; 4096];
+            let read_bytes = reader.read(&mut chunk)?;
+            if read_bytes == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "master closed the connection",
+                ));
+            }
+            buffer.extend_from_slice(&chunk[..read_bytes]);
+            continue;
+        };
+        let frame: Vec<u8> = buffer.drain(..len).collect();
+        let instructions = handle_client_message(&frame)
+            .map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?;
+        for instruction in instructions {
+            match instruction {
+                Command::ReplConf(args) if is_subcommand(&args, "getack") => {
+                    send(&mut stream, &["REPLCONF", "ACK", &offset.to_string()])?;
+                }
+                // the master doesn't want replies to anything else
+                instruction => {
+                    execute_command(instruction, &mut db, state);
+                }
+            }
+        }
+        offset += len;
+    }
+}
+
+/// Whether REPLCONF arguments start with `name`, in any case.
+pub fn is_subcommand(args: &[String], name: &str) -> bool {
+    args.first()
+        .is_some_and(|arg| arg.eq_ignore_ascii_case(name))
+}
+
+fn send(stream: &mut TcpStream, args: &[&str]) -> io::Result<()> {
+    let command = RespValue::array(
+        args.iter()
+            .map(|arg| RespValue::bulk(arg.as_bytes()))
+            .collect(),
+    );
+    stream.write_all(&command.to_bytes())
+}
+
+/// Reads a line without its line ending. The master may send bare newlines
+/// to keep the link alive while it prepares the snapshot, which are skipped.
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if !line.is_empty() {
+            return Ok(line.to_string());
+        }
+    }
+}
+
+fn expect_reply(reader: &mut impl BufRead, expected: &str) -> io::Result<()> {
+    let reply = read_line(reader)?;
+    if reply != expected {
+        return Err(unexpected_reply(&reply));
+    }
+    Ok(())
+}
+
+fn unexpected_reply(reply: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected reply from master: {}", reply),
+    )
+}
@@ -0,0 +1,212 @@
+pub const SEPARATOR: &str = "\r\n";
+
+/// Which version of the protocol a connection speaks. Everyone starts on
+/// RESP2, and HELLO can switch to RESP3.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// A reply in the RESP wire format. Handlers build one of these and the
+/// connection encodes it once, right before writing it out, in whichever
+/// protocol it speaks. The RESP3-only types have RESP2 fallbacks.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// `None` is the null bulk string, `$-1`, or `_` in RESP3.
+    Bulk(Option<Vec<u8>>),
+    /// `None` is the null array, `*-1`, or `_` in RESP3.
+    Array(Option<Vec<RespValue>>),
+    /// A flat array of keys and values in RESP2.
+    Map(Vec<(RespValue, RespValue)>),
+    /// A bulk string in RESP2.
+    Double(f64),
+    /// `:1` or `:0` in RESP2. No command replies with one yet.
+    #[allow(dead_code)]
+    Boolean(bool),
+    /// Data the server sends of its own accord, like pub/sub messages, so
+    /// RESP3 clients can tell it apart from replies. A plain array in RESP2.
+    Push(Vec<RespValue>),
+}
+
+impl RespValue {
+    pub fn ok() -> RespValue {
+        RespValue::Simple("OK".to_string())
+    }
+
+    pub fn error(message: &str) -> RespValue {
+        RespValue::Error(message.to_string())
+    }
+
+    pub fn bulk(value: &[u8]) -> RespValue {
+        RespValue::Bulk(Some(value.to_vec()))
+    }
+
+    pub fn null_bulk() -> RespValue {
+        RespValue::Bulk(None)
+    }
+
+    pub fn array(items: Vec<RespValue>) -> RespValue {
+        RespValue::Array(Some(items))
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        self.encode_as(Protocol::Resp2, out)
+    }
+
+    pub fn encode_as(&self, protocol: Protocol, out: &mut Vec<u8>) {
+        let resp3 = protocol == Protocol::Resp3;
+        match self {
+            RespValue::Simple(value) => {
+                out.push(b'+');
+                out.extend_from_slice(value.as_bytes());
+            }
+            RespValue::Error(message) => {
+                out.push(b'-');
+                out.extend_from_slice(message.as_bytes());
+            }
+            RespValue::Integer(value) => {
+                out.extend_from_slice(format!(":{}", value).as_bytes());
+            }
+            RespValue::Bulk(None) | RespValue::Array(None) if resp3 => out.push(b'_'),
+            RespValue::Bulk(None) => out.extend_from_slice(b"$-1"),
+            RespValue::Bulk(Some(value)) => {
+                out.extend_from_slice(format!("${}{}", value.len(), SEPARATOR).as_bytes());
+                out.extend_from_slice(value);
+            }
+            RespValue::Array(None) => out.extend_from_slice(b"*-1"),
+            RespValue::Array(Some(items)) | RespValue::Push(items) => {
+                let kind = if resp3 && matches!(self, RespValue::Push(_)) {
+                    '>'
+                } else {
+                    '*'
+                };
+                out.extend_from_slice(format!("{}{}{}", kind, items.len(), SEPARATOR).as_bytes());
+                for item in items {
+                    item.encode_as(protocol, out);
+                }
+                // every element already wrote its own separator
+                return;
+            }
+            RespValue::Map(pairs) => {
+                let header = if resp3 {
+                    format!("%{}{}", pairs.len(), SEPARATOR)
+                } else {
+                    format!("*{}{}", pairs.len() * 2, SEPARATOR)
+                };
+                out.extend_from_slice(header.as_bytes());
+                for (key, value) in pairs {
+                    key.encode_as(protocol, out);
+                    value.encode_as(protocol, out);
+                }
+                return;
+            }
+            RespValue::Double(value) if resp3 => {
+                out.extend_from_slice(format!(",{}", value).as_bytes());
+            }
+            RespValue::Double(value) => {
+                return RespValue::bulk(value.to_string().as_bytes()).encode_as(protocol, out);
+            }
+            RespValue::Boolean(value) if resp3 => {
+                out.extend_from_slice(if *value { b"#t" } else { b"#f" });
+            }
+            RespValue::Boolean(value) => {
+                return RespValue::Integer(*value as i64).encode_as(protocol, out);
+            }
+        }
+        out.extend_from_slice(SEPARATOR.as_bytes());
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_integer() {
+        assert_eq!(RespValue::Integer(42).to_bytes(), b":42\r\n");
+        assert_eq!(RespValue::Integer(-2).to_bytes(), b":-2\r\n");
+    }
+
+    #[test]
+    fn test_encode_bulk() {
+        assert_eq!(RespValue::bulk(b"hey").to_bytes(), b"$3\r\nhey\r\n");
+        assert_eq!(RespValue::bulk(b"").to_bytes(), b"$0\r\n\r\n");
+        assert_eq!(RespValue::null_bulk().to_bytes(), b"$-1\r\n");
+    }
+
+    #[test]
+    fn test_encode_nested_array() {
+        let value = RespValue::array(vec![
+            RespValue::bulk(b"a"),
+            RespValue::array(vec![RespValue::Integer(1), RespValue::null_bulk()]),
+            RespValue::Array(None),
+            RespValue::array(vec![]),
+        ]);
+        assert_eq!(
+            value.to_bytes(),
+            b"*4\r\n$1\r\na\r\n*2\r\n:1\r\n$-1\r\n*-1\r\n*0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_resp3() {
+        let encode = |value: &RespValue, protocol| {
+            let mut out = vec![];
+            value.encode_as(protocol, &mut out);
+            out
+        };
+        let map = RespValue::Map(vec![(RespValue::bulk(b"a"), RespValue::Integer(1))]);
+        assert_eq!(encode(&map, Protocol::Resp3), b"%1\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(encode(&map, Protocol::Resp2), b"*2\r\n$1\r\na\r\n:1\r\n");
+
+        let double = RespValue::Double(1.5);
+        assert_eq!(encode(&double, Protocol::Resp3), b",1.5\r\n");
+        assert_eq!(encode(&double, Protocol::Resp2), b"$3\r\n1.5\r\n");
+        let infinite = RespValue::Double(f64::NEG_INFINITY);
+        assert_eq!(encode(&infinite, Protocol::Resp3), b",-inf\r\n");
+
+        assert_eq!(
+            encode(&RespValue::Boolean(true), Protocol::Resp3),
+            b"#t\r\n"
+        );
+        assert_eq!(
+            encode(&RespValue::Boolean(false), Protocol::Resp3),
+            b"#f\r\n"
+        );
+        assert_eq!(
+            encode(&RespValue::Boolean(true), Protocol::Resp2),
+            b":1\r\n"
+        );
+        assert_eq!(
+            encode(&RespValue::Boolean(false), Protocol::Resp2),
+            b":0\r\n"
+        );
+
+        let push = RespValue::Push(vec![RespValue::bulk(b"a")]);
+        assert_eq!(encode(&push, Protocol::Resp3), b">1\r\n$1\r\na\r\n");
+        assert_eq!(encode(&push, Protocol::Resp2), b"*1\r\n$1\r\na\r\n");
+
+        let nulls = RespValue::array(vec![RespValue::null_bulk(), RespValue::Array(None)]);
+        assert_eq!(encode(&nulls, Protocol::Resp3), b"*2\r\n_\r\n_\r\n");
+    }
+
+    #[test]
+    fn test_encode_simple_and_error() {
+        assert_eq!(RespValue::ok().to_bytes(), b"+OK\r\n");
+        assert_eq!(
+            RespValue::error("ERR syntax error").to_bytes(),
+            b"-ERR syntax error\r\n"
+        );
+    }
+}
@@ -0,0 +1,1123 @@
+use std::{
+    cell::Cell,
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        BTreeMap, HashMap, HashSet, VecDeque,
+    },
+    hash::{BuildHasher, Hash, Hasher},
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{memory::entry_usage, zset::SortedSet};
+
+pub const WRONGTYPE_ERROR: &str =
+    "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+pub type HashValue = HashMap<Vec<u8>, Vec<u8>>;
+pub type SetValue = HashSet<Vec<u8>>;
+
+/// The data held under a key. Strings are raw bytes, and so are the
+/// elements of every collection type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashValue),
+    Set(SetValue),
+    ZSet(SortedSet),
+}
+
+impl Value {
+    /// The name `TYPE` reports for this value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+        }
+    }
+
+    /// The name `OBJECT ENCODING` reports. Everything is stored the one way
+    /// here, so this is the encoding Redis would pick for a value this size,
+    /// going by its default thresholds.
+    pub fn encoding_name(&self) -> &'static str {
+        match self {
+            Value::Str(value) if value.len() <= 20 && is_integer(value) => "int",
+            Value::Str(value) if value.len() <= 44 => "embstr",
+            Value::Str(_) => "raw",
+            Value::List(list) if fits_listpack(list.len(), list.iter()) => "listpack",
+            Value::List(_) => "quicklist",
+            Value::Hash(hash) if fits_listpack(hash.len(), hash.keys().chain(hash.values())) => {
+                "listpack"
+            }
+            Value::Hash(_) => "hashtable",
+            Value::Set(set) if set.len() <= 512 && set.iter().all(|member| is_integer(member)) => {
+                "intset"
+            }
+            Value::Set(set) if fits_listpack(set.len(), set.iter()) => "listpack",
+            Value::Set(_) => "hashtable",
+            Value::ZSet(zset)
+                if fits_listpack(zset.len(), zset.iter().map(|(member, _)| member)) =>
+            {
+                "listpack"
+            }
+            Value::ZSet(_) => "skiplist",
+        }
+    }
+
+    /// Collections are never stored empty, so callers removing elements use
+    /// this to know when to drop the key. Empty strings are valid values.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::Str(_) => false,
+            Value::List(list) => list.is_empty(),
+            Value::Hash(hash) => hash.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+        }
+    }
+}
+
+/// Source of entry versions, shared by every shard so a key that's deleted
+/// and created again never reuses an old version.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+thread_local! {
+    static CHANGES_MADE: Cell<u64> = const { Cell::new(0) };
+}
+
+/// How many write locks this thread has let go of after changing what they
+/// guarded. A command changed the dataset if this moved while it ran.
+pub fn changes_made() -> u64 {
+    CHANGES_MADE.with(Cell::get)
+}
+
+/// Milliseconds since the server first needed the time of an access. Access
+/// times are kept in these rather than as an `Instant`, to fit in an atomic.
+fn access_clock() -> u64 {
+    static STARTED: OnceLock<Instant> = OnceLock::new();
+    STARTED.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+pub struct StorageEntry {
+    /// When the key expires, in milliseconds since the Unix epoch. Kept on
+    /// the wall clock so absolute expiries mean what the client asked for.
+    pub expire_timestamp: Option<u64>,
+    pub value: Value,
+    /// Changes every time a command takes the value to change it, or changes
+    /// the expiry, which is what WATCH compares. A command that fails, or
+    /// only looks, leaves it alone.
+    pub version: u64,
+    /// When a command last read or wrote the key, on the `access_clock`.
+    /// Reads only hold the shared lock, hence the atomic.
+    last_access: AtomicU64,
+}
+
+impl StorageEntry {
+    pub fn new(value: Value, expire_timestamp: Option<u64>) -> StorageEntry {
+        StorageEntry {
+            expire_timestamp,
+            value,
+            version: next_version(),
+            last_access: AtomicU64::new(access_clock()),
+        }
+    }
+
+    /// Gives the entry a new version, for changes made to its fields directly
+    /// rather than through one of the `as_*_mut` accessors.
+    pub fn mark_changed(&mut self) {
+        self.version = next_version();
+    }
+
+    /// Records that a command just used the key.
+    pub fn touch(&self) {
+        self.last_access.store(access_clock(), Ordering::Relaxed);
+    }
+
+    /// How long it's been since a command last used the key.
+    pub fn idle_time(&self) -> Duration {
+        let last_access = self.last_access.load(Ordering::Relaxed);
+        Duration::from_millis(access_clock().saturating_sub(last_access))
+    }
+
+    pub fn string(value: Vec<u8>, expire_timestamp: Option<u64>) -> StorageEntry {
+        StorageEntry::new(Value::Str(value), expire_timestamp)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expire_timestamp {
+            Some(expiry) => unix_millis_now() > expiry,
+            None => false,
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&Vec<u8>, String> {
+        match &self.value {
+            Value::Str(value) => Ok(value),
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> Result<&mut Vec<u8>, String> {
+        match &mut self.value {
+            Value::Str(value) => {
+                self.version = next_version();
+                Ok(value)
+            }
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&VecDeque<Vec<u8>>, String> {
+        match &self.value {
+            Value::List(list) => Ok(list),
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Result<&mut VecDeque<Vec<u8>>, String> {
+        match &mut self.value {
+            Value::List(list) => {
+                self.version = next_version();
+                Ok(list)
+            }
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_hash(&self) -> Result<&HashValue, String> {
+        match &self.value {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_hash_mut(&mut self) -> Result<&mut HashValue, String> {
+        match &mut self.value {
+            Value::Hash(hash) => {
+                self.version = next_version();
+                Ok(hash)
+            }
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_set(&self) -> Result<&SetValue, String> {
+        match &self.value {
+            Value::Set(set) => Ok(set),
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Result<&mut SetValue, String> {
+        match &mut self.value {
+            Value::Set(set) => {
+                self.version = next_version();
+                Ok(set)
+            }
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_zset(&self) -> Result<&SortedSet, String> {
+        match &self.value {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+
+    pub fn as_zset_mut(&mut self) -> Result<&mut SortedSet, String> {
+        match &mut self.value {
+            Value::ZSet(zset) => {
+                self.version = next_version();
+                Ok(zset)
+            }
+            _ => Err(WRONGTYPE_ERROR.to_string()),
+        }
+    }
+}
+
+fn is_integer(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes).is_ok_and(|text| text.parse::<i64>().is_ok())
+}
+
+/// Whether Redis would keep a collection this size packed into a listpack:
+/// at most 128 entries, none longer than 64 bytes.
+fn fits_listpack<T: AsRef<[u8]>>(len: usize, mut elements: impl Iterator<Item = T>) -> bool {
+    len <= 128 && elements.all(|element| element.as_ref().len() <= 64)
+}
+
+pub type Storage = HashMap<String, StorageEntry>;
+
+pub const SHARD_COUNT: usize = 16;
+
+/// The keyspace split over several independently locked maps, so commands
+/// on unrelated keys don't wait on each other. Each key lives in the shard
+/// its hash picks.
+///
+/// Shards are behind a `RwLock`, so commands that only read share it. An
+/// expired entry can't be removed under the read lock, so readers just treat
+/// it as missing (see `get_live`) and leave it in place for the next write to
+/// the key or the active expiry cycle to drop. GET is the exception, going
+/// back for the write lock to drop what it finds expired.
+///
+/// Taking the write lock for a key drops it if it has expired, and queues it
+/// in `expired` for the `expired` keyspace notification.
+pub struct ShardedStorage {
+    shards: Vec<RwLock<Storage>>,
+    /// What the entries of every shard take up, by `entry_usage`. Kept up to
+    /// date as write locks are let go, see `ShardWriteGuard`.
+    used: AtomicUsize,
+    expired: Mutex<Vec<String>>,
+    /// Set once `expired` has something in it, so commands can look without
+    /// taking its lock.
+    expired_pending: AtomicBool,
+}
+
+impl Default for ShardedStorage {
+    fn default() -> ShardedStorage {
+        ShardedStorage {
+            shards: (0..SHARD_COUNT)
+                .map(|_| RwLock::new(Storage::new()))
+                .collect(),
+            used: AtomicUsize::new(0),
+            expired: Mutex::new(vec![]),
+            expired_pending: AtomicBool::new(false),
+        }
+    }
+}
+
+pub const DATABASE_COUNT: usize = 16;
+
+/// The numbered databases SELECT picks between, each a keyspace of its own.
+pub struct Databases {
+    databases: Vec<ShardedStorage>,
+    /// Held shared by every command, and exclusively by a transaction while
+    /// it runs, so nothing interleaves with one.
+    gate: RwLock<()>,
+}
+
+impl Default for Databases {
+    fn default() -> Databases {
+        Databases {
+            databases: (0..DATABASE_COUNT)
+                .map(|_| ShardedStorage::default())
+                .collect(),
+            gate: RwLock::new(()),
+        }
+    }
+}
+
+impl Databases {
+    /// Taken around a single command. Any number of these can be held at once.
+    pub fn shared_access(&self) -> RwLockReadGuard<'_, ()> {
+        self.gate.read().unwrap()
+    }
+
+    /// Taken around a whole transaction, waiting out every command in flight
+    /// and holding off new ones until it's dropped.
+    pub fn exclusive_access(&self) -> RwLockWriteGuard<'_, ()> {
+        self.gate.write().unwrap()
+    }
+
+    /// Database number `index`, which has to be below `DATABASE_COUNT`.
+    pub fn db(&self, index: usize) -> &ShardedStorage {
+        &self.databases[index]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ShardedStorage> {
+        self.databases.iter()
+    }
+
+    /// What the entries of every database take up, as maxmemory counts it.
+    pub fn used_memory(&self) -> usize {
+        self.databases.iter().map(ShardedStorage::used_memory).sum()
+    }
+}
+
+/// A hash of `item` that's the same every time: the default hasher isn't
+/// randomly seeded when it's built with `new`.
+pub fn stable_hash<T: Hash + ?Sized>(item: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn shard_index(key: &str) -> usize {
+    (stable_hash(key) % SHARD_COUNT as u64) as usize
+}
+
+/// Where one step of a SCAN-style iteration stops. Elements are visited in
+/// order of their `stable_hash`, and a cursor is the hash to carry on from,
+/// so it stays valid however the maps underneath grow or shrink. Given the
+/// hashes of every element, returns the highest hash this step covers and
+/// the cursor for the next one, 0 once there's nothing left.
+///
+/// Elements sharing a hash are always covered by the same step, so a step
+/// can take a few more than `count`.
+pub fn scan_range(hashes: impl Iterator<Item = u64>, cursor: u64, count: usize) -> (u64, u64) {
+    let mut ahead: Vec<u64> = hashes.filter(|hash| *hash >= cursor).collect();
+    if ahead.len() <= count {
+        return (u64::MAX, 0);
+    }
+    let (_before, last, _after) = ahead.select_nth_unstable(count.max(1) - 1);
+    (*last, last.checked_add(1).unwrap_or(0))
+}
+
+/// One SCAN-style step over the elements of a single collection, `hash`
+/// giving what each is ordered by. Returns the cursor to continue from and
+/// the elements covered.
+pub fn scan_elements<T>(
+    elements: impl Iterator<Item = T> + Clone,
+    hash: impl Fn(&T) -> u64,
+    cursor: u64,
+    count: usize,
+) -> (u64, Vec<T>) {
+    let (last, next_cursor) = scan_range(
+        elements.clone().map(|element| hash(&element)),
+        cursor,
+        count,
+    );
+    let covered = elements
+        .filter(|element| (cursor..=last).contains(&hash(element)))
+        .collect();
+    (next_cursor, covered)
+}
+
+/// The shards holding `keys`, in ascending order and without repeats. Taking
+/// locks in this order means two multi-key commands can't deadlock.
+fn shard_indices<'a, K: AsRef<str> + 'a>(keys: impl IntoIterator<Item = &'a K>) -> Vec<usize> {
+    let mut indices: Vec<usize> = keys
+        .into_iter()
+        .map(|key| shard_index(key.as_ref()))
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
+
+impl ShardedStorage {
+    /// Takes the shared lock on the shard holding `key`.
+    pub fn read(&self, key: &str) -> RwLockReadGuard<'_, Storage> {
+        self.shards[shard_index(key)].read().unwrap()
+    }
+
+    /// Takes the exclusive lock on the shard holding `key`, which counts as a
+    /// use of the key, and drops the key if it has expired.
+    pub fn write(&self, key: &str) -> ShardWriteGuard<'_> {
+        let mut storage = self.write_shard(shard_index(key));
+        self.drop_if_expired(&mut storage, key);
+        storage.track(key);
+        touch_key(&storage, key);
+        storage
+    }
+
+    /// Runs before the key is tracked, so dropping it isn't taken for a
+    /// change the command made.
+    fn drop_if_expired(&self, storage: &mut Storage, key: &str) {
+        if storage.get(key).is_some_and(StorageEntry::is_expired) {
+            if let Some(entry) = storage.remove(key) {
+                adjust(&self.used, entry_usage(key, &entry), 0);
+            }
+            self.expired.lock().unwrap().push(key.to_string());
+            self.expired_pending.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The keys writes found expired and dropped since the last call.
+    pub fn take_expired(&self) -> Vec<String> {
+        if !self.expired_pending.swap(false, Ordering::Relaxed) {
+            return vec![];
+        }
+        std::mem::take(&mut self.expired.lock().unwrap())
+    }
+
+    pub fn read_shard(&self, index: usize) -> RwLockReadGuard<'_, Storage> {
+        self.shards[index].read().unwrap()
+    }
+
+    /// Takes the exclusive lock on shard `index`, without tracking any keys.
+    pub fn write_shard(&self, index: usize) -> ShardWriteGuard<'_> {
+        ShardWriteGuard {
+            storage: self.shards[index].write().unwrap(),
+            used: &self.used,
+            tracked: Tracked::Keys(vec![]),
+        }
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Read locks every shard holding one of `keys`, for commands reading
+    /// several keys at once.
+    pub fn read_keys<'a, K: AsRef<str> + 'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a K>,
+    ) -> ShardGuards<RwLockReadGuard<'_, Storage>> {
+        ShardGuards {
+            guards: shard_indices(keys)
+                .into_iter()
+                .map(|index| (index, self.shards[index].read().unwrap()))
+                .collect(),
+        }
+    }
+
+    /// Write locks every shard holding one of `keys`, for commands changing
+    /// several keys at once.
+    pub fn write_keys<'a, K: AsRef<str> + 'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a K>,
+    ) -> ShardGuards<ShardWriteGuard<'_>> {
+        let keys: Vec<&K> = keys.into_iter().collect();
+        let mut shards = ShardGuards {
+            guards: shard_indices(keys.iter().copied())
+                .into_iter()
+                .map(|index| (index, self.write_shard(index)))
+                .collect(),
+        };
+        for key in keys {
+            let storage = shards.guard(key.as_ref());
+            self.drop_if_expired(storage, key.as_ref());
+            storage.track(key.as_ref());
+            touch_key(storage, key.as_ref());
+        }
+        shards
+    }
+
+    /// Read locks every shard, for commands that look at the whole keyspace.
+    pub fn read_all(&self) -> ShardGuards<RwLockReadGuard<'_, Storage>> {
+        ShardGuards {
+            guards: (0..SHARD_COUNT)
+                .map(|index| (index, self.shards[index].read().unwrap()))
+                .collect(),
+        }
+    }
+
+    /// A live key picked at random, or `None` when there aren't any. Expired
+    /// keys picked along the way are dropped, then another pick is made.
+    pub fn random_key(&self) -> Option<String> {
+        loop {
+            let picked = {
+                let shards = self.read_all();
+                let total: usize = shards.iter().map(|storage| storage.len()).sum();
+                if total == 0 {
+                    return None;
+                }
+                // a fresh `RandomState` is keyed differently every time
+                let index = RandomState::new().build_hasher().finish() % total as u64;
+                let picked = shards
+                    .iter()
+                    .flat_map(|storage| storage.iter())
+                    .nth(index as usize)
+                    .map(|(key, entry)| (key.clone(), entry.is_expired()));
+                picked
+            };
+            match picked {
+                Some((key, true)) => evict_if_expired(&mut self.write(&key), &key),
+                picked => return picked.map(|(key, _expired)| key),
+            }
+        }
+    }
+
+    /// One step of SCAN: up to about `count` live keys, starting from
+    /// `cursor`, and the cursor to pass next time. A key that exists from
+    /// the first step to the last is returned exactly once; one added or
+    /// removed in between may or may not be. Each step looks at every key,
+    /// one shard at a time, to find where it ends.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<String>) {
+        let mut hashes = vec![];
+        for shard in &self.shards {
+            hashes.extend(shard.read().unwrap().keys().map(stable_hash));
+        }
+        let (last, next_cursor) = scan_range(hashes.into_iter(), cursor, count);
+        let mut keys = vec![];
+        for shard in &self.shards {
+            keys.extend(
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .filter(|(key, entry)| {
+                        let hash = stable_hash(key.as_str());
+                        !entry.is_expired() && (cursor..=last).contains(&hash)
+                    })
+                    .map(|(key, _entry)| key.clone()),
+            );
+        }
+        (next_cursor, keys)
+    }
+
+    /// Write locks every shard, for commands that change the whole keyspace.
+    pub fn write_all(&self) -> ShardGuards<ShardWriteGuard<'_>> {
+        let mut shards = ShardGuards {
+            guards: (0..SHARD_COUNT)
+                .map(|index| {
+                    let mut storage = self.write_shard(index);
+                    storage.track_all();
+                    (index, storage)
+                })
+                .collect(),
+        };
+        for entry in shards.iter_mut().flat_map(|storage| storage.values_mut()) {
+            entry.version = next_version();
+        }
+        shards
+    }
+}
+
+/// A set of locked shards, from one of the `*_keys` or `*_all` methods.
+pub struct ShardGuards<G> {
+    guards: BTreeMap<usize, G>,
+}
+
+impl<G: Deref<Target = Storage>> ShardGuards<G> {
+    /// The shard holding `key`, which has to be one of the keys locked.
+    pub fn shard_ref(&self, key: &str) -> &Storage {
+        self.guards
+            .get(&shard_index(key))
+            .expect("shard for key is not locked")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Storage> {
+        self.guards.values().map(|guard| &**guard)
+    }
+}
+
+impl<'a> ShardGuards<ShardWriteGuard<'a>> {
+    fn guard(&mut self, key: &str) -> &mut ShardWriteGuard<'a> {
+        self.guards
+            .get_mut(&shard_index(key))
+            .expect("shard for key is not locked")
+    }
+}
+
+/// Which changes a `ShardWriteGuard` counts towards memory use.
+enum Tracked {
+    /// Just those to these keys, with what each took up and its version when
+    /// tracking began.
+    Keys(Vec<(String, usize, Option<u64>)>),
+    /// Everything, with what the whole shard took up, how many keys it held
+    /// and the next version to be handed out when it was locked.
+    Shard {
+        usage: usize,
+        len: usize,
+        since: u64,
+    },
+}
+
+/// A shard locked for writing. Whatever happens to the keys it tracks,
+/// usually the ones it was locked for, is reflected in the database's memory
+/// use when it's let go, so that count stays exact without adding up the
+/// keyspace. It also counts towards `changes_made` if any of them changed.
+pub struct ShardWriteGuard<'a> {
+    storage: RwLockWriteGuard<'a, Storage>,
+    used: &'a AtomicUsize,
+    tracked: Tracked,
+}
+
+impl ShardWriteGuard<'_> {
+    /// Counts whatever happens to `key` from here on.
+    pub fn track(&mut self, key: &str) {
+        let Tracked::Keys(keys) = &mut self.tracked else {
+            return;
+        };
+        if keys.iter().any(|(tracked, ..)| tracked == key) {
+            return;
+        }
+        let entry = self.storage.get(key);
+        let usage = entry.map_or(0, |entry| entry_usage(key, entry));
+        keys.push((key.to_string(), usage, entry.map(|entry| entry.version)));
+    }
+
+    /// Counts whatever happens to the shard from here on.
+    fn track_all(&mut self) {
+        self.tracked = Tracked::Shard {
+            usage: shard_usage(&self.storage),
+            len: self.storage.len(),
+            since: NEXT_VERSION.load(Ordering::Relaxed),
+        };
+    }
+}
+
+impl Deref for ShardWriteGuard<'_> {
+    type Target = Storage;
+
+    fn deref(&self) -> &Storage {
+        &self.storage
+    }
+}
+
+impl DerefMut for ShardWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Storage {
+        &mut self.storage
+    }
+}
+
+impl Drop for ShardWriteGuard<'_> {
+    fn drop(&mut self) {
+        let changed = match &self.tracked {
+            Tracked::Keys(keys) => {
+                let mut changed = false;
+                for (key, usage, version) in keys {
+                    let entry = self.storage.get(key);
+                    adjust(
+                        self.used,
+                        *usage,
+                        entry.map_or(0, |entry| entry_usage(key, entry)),
+                    );
+                    changed |= entry.map(|entry| entry.version) != *version;
+                }
+                changed
+            }
+            Tracked::Shard { usage, len, since } => {
+                adjust(self.used, *usage, shard_usage(&self.storage));
+                self.storage.len() != *len
+                    || self.storage.values().any(|entry| entry.version >= *since)
+            }
+        };
+        if changed {
+            CHANGES_MADE.with(|changes| changes.set(changes.get() + 1));
+        }
+    }
+}
+
+fn shard_usage(storage: &Storage) -> usize {
+    storage
+        .iter()
+        .map(|(key, entry)| entry_usage(key, entry))
+        .sum()
+}
+
+/// Moves `used` on by however much something that took `before` bytes
+/// takes `after` them.
+fn adjust(used: &AtomicUsize, before: usize, after: usize) {
+    if after >= before {
+        used.fetch_add(after - before, Ordering::Relaxed);
+    } else {
+        let _ = used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            Some(used.saturating_sub(before - after))
+        });
+    }
+}
+
+impl<G: DerefMut<Target = Storage>> ShardGuards<G> {
+    pub fn shard(&mut self, key: &str) -> &mut Storage {
+        self.guards
+            .get_mut(&shard_index(key))
+            .expect("shard for key is not locked")
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Storage> {
+        self.guards.values_mut().map(|guard| &mut **guard)
+    }
+}
+
+fn touch_key(storage: &Storage, key: &str) {
+    if let Some(entry) = storage.get(key) {
+        entry.touch();
+    }
+}
+
+/// The version WATCH records for `key`, `None` when it's missing.
+pub fn live_version(storage: &Storage, key: &str) -> Option<u64> {
+    peek_live(storage, key).map(|entry| entry.version)
+}
+
+pub fn unix_millis_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as u64
+}
+
+/// Looks up a live entry without evicting anything, for the read lock paths.
+/// The entry counts as used.
+pub fn get_live<'a>(storage: &'a Storage, key: &str) -> Option<&'a StorageEntry> {
+    let entry = peek_live(storage, key)?;
+    entry.touch();
+    Some(entry)
+}
+
+/// Like `get_live`, but for looking at the entry itself without counting as
+/// a use of it, as OBJECT does.
+pub fn peek_live<'a>(storage: &'a Storage, key: &str) -> Option<&'a StorageEntry> {
+    storage.get(key).filter(|entry| !entry.is_expired())
+}
+
+/// Read-only lookups of a typed value, `Ok(None)` meaning the key is missing.
+pub fn read_string<'a>(storage: &'a Storage, key: &str) -> Result<Option<&'a Vec<u8>>, String> {
+    get_live(storage, key)
+        .map(StorageEntry::as_string)
+        .transpose()
+}
+
+pub fn read_list<'a>(
+    storage: &'a Storage,
+    key: &str,
+) -> Result<Option<&'a VecDeque<Vec<u8>>>, String> {
+    get_live(storage, key)
+        .map(StorageEntry::as_list)
+        .transpose()
+}
+
+pub fn read_hash<'a>(storage: &'a Storage, key: &str) -> Result<Option<&'a HashValue>, String> {
+    get_live(storage, key)
+        .map(StorageEntry::as_hash)
+        .transpose()
+}
+
+pub fn read_set<'a>(storage: &'a Storage, key: &str) -> Result<Option<&'a SetValue>, String> {
+    get_live(storage, key).map(StorageEntry::as_set).transpose()
+}
+
+pub fn read_zset<'a>(storage: &'a Storage, key: &str) -> Result<Option<&'a SortedSet>, String> {
+    get_live(storage, key)
+        .map(StorageEntry::as_zset)
+        .transpose()
+}
+
+/// Drops `key` if its expiry has passed, so lookups right after can treat
+/// the entry as live.
+pub fn evict_if_expired(storage: &mut Storage, key: &str) {
+    if storage.get(key).is_some_and(|entry| entry.is_expired()) {
+        storage.remove(key);
+    }
+}
+
+/// Checks up to `sample` entries, starting `cursor` entries into the map's
+/// iteration order, and drops the expired ones. Returns how many entries were
+/// checked and the keys of those that were dropped, and moves `cursor` along
+/// so repeated calls walk the whole map, wrapping around at the end.
+pub fn evict_expired_sample(
+    storage: &mut ShardWriteGuard,
+    cursor: &mut usize,
+    sample: usize,
+) -> (usize, Vec<String>) {
+    if *cursor >= storage.len() {
+        *cursor = 0;
+    }
+    let checked = sample.min(storage.len() - *cursor);
+    let expired: Vec<String> = storage
+        .iter()
+        .skip(*cursor)
+        .take(checked)
+        .filter(|(_key, entry)| entry.is_expired())
+        .map(|(key, _entry)| key.clone())
+        .collect();
+    for key in &expired {
+        storage.track(key);
+        storage.remove(key);
+    }
+    // removing entries shifts later ones back, so step over the survivors only
+    *cursor += checked - expired.len();
+    (checked, expired)
+}
+
+/// Looks up a live string value, `Ok(None)` meaning the key is missing.
+pub fn get_string<'a>(storage: &'a mut Storage, key: &str) -> Result<Option<&'a Vec<u8>>, String> {
+    evict_if_expired(storage, key);
+    storage.get(key).map(StorageEntry::as_string).transpose()
+}
+
+/// Looks up the list at `key`, creating an empty one if the key is missing.
+pub fn get_or_create_list<'a>(
+    storage: &'a mut Storage,
+    key: &str,
+) -> Result<&'a mut VecDeque<Vec<u8>>, String> {
+    evict_if_expired(storage, key);
+    storage
+        .entry(key.to_string())
+        .or_insert_with(|| StorageEntry::new(Value::List(VecDeque::new()), None))
+        .as_list_mut()
+}
+
+pub fn get_list_mut<'a>(
+    storage: &'a mut Storage,
+    key: &str,
+) -> Result<Option<&'a mut VecDeque<Vec<u8>>>, String> {
+    evict_if_expired(storage, key);
+    storage
+        .get_mut(key)
+        .map(StorageEntry::as_list_mut)
+        .transpose()
+}
+
+pub fn get_hash_mut<'a>(
+    storage: &'a mut Storage,
+    key: &str,
+) -> Result<Option<&'a mut HashValue>, String> {
+    evict_if_expired(storage, key);
+    storage
+        .get_mut(key)
+        .map(StorageEntry::as_hash_mut)
+        .transpose()
+}
+
+/// Looks up the hash at `key`, creating an empty one if the key is missing.
+pub fn get_or_create_hash<'a>(
+    storage: &'a mut Storage,
+    key: &str,
+) -> Result<&'a mut HashValue, String> {
+    evict_if_expired(storage, key);
+    storage
+        .entry(key.to_string())
+        .or_insert_with(|| StorageEntry::new(Value::Hash(HashMap::new()), None))
+        .as_hash_mut()
+}
+
+pub fn get_set_mut<'a>(
+    storage: &'a mut Storage,
+    key: &str,
+) -> Result<Option<&'a mut SetValue>, String> {
+    evict_if_expired(storage, key);
+    storage
+        .get_mut(key)
+        .map(StorageEntry::as_set_mut)
+        .transpose()
+}
+
+/// Looks up the set at `key`, creating an empty one if the key is missing.
+pub fn get_or_create_set<'a>(
+    storage: &'a mut Storage,
+    key: &str,
+) -> Result<&'a mut SetValue, String> {
+    evict_if_expired(storage, key);
+    storage
+        .entry(key.to_string())
+        .or_insert_with(|| StorageEntry::new(Value::Set(SetValue::new()), None))
+        .as_set_mut()
+}
+
+/// Looks up the sorted set at `key`, creating an empty one if the key is
+/// missing.
+pub fn get_or_create_zset<'a>(
+    storage: &'a mut Storage,
+    key: &str,
+) -> Result<&'a mut SortedSet, String> {
+    evict_if_expired(storage, key);
+    storage
+        .entry(key.to_string())
+        .or_insert_with(|| StorageEntry::new(Value::ZSet(SortedSet::default()), None))
+        .as_zset_mut()
+}
+
+/// Drops `key` once its collection has had its last element removed.
+pub fn remove_if_empty(storage: &mut Storage, key: &str) {
+    if storage
+        .get(key)
+        .is_some_and(|entry| entry.value.is_empty_collection())
+    {
+        storage.remove(key);
+    }
+}
+
+/// Resolves a single index, negative ones counting back from the end.
+pub fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    if index < 0 || index >= len as i64 {
+        None
+    } else {
+        Some(index as usize)
+    }
+}
+
+/// Resolves an inclusive `start..=stop` range as Redis takes it, where
+/// negative indices count back from the end and out of range indices are
+/// clamped. `None` means the range selects nothing.
+pub fn resolve_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_keys() {
+        let storage = ShardedStorage::default();
+        let keys: Vec<String> = (0..100).map(|i| format!("key:{}", i)).collect();
+        let mut shards = storage.write_keys(&keys);
+        for key in &keys {
+            shards
+                .shard(key)
+                .insert(key.clone(), StorageEntry::string(vec![], None));
+        }
+        drop(shards);
+
+        // every key landed in the shard a single-key lock would pick
+        for key in &keys {
+            assert!(storage.read(key).contains_key(key));
+        }
+        let total: usize = storage.read_all().iter().map(|shard| shard.len()).sum();
+        assert_eq!(total, 100);
+    }
+
+    #[test]
+    fn test_scan() {
+        let storage = ShardedStorage::default();
+        let keys: Vec<String> = (0..100).map(|i| format!("key:{}", i)).collect();
+        for key in &keys {
+            storage
+                .write(key)
+                .insert(key.clone(), StorageEntry::string(vec![], None));
+        }
+
+        let mut seen = vec![];
+        let mut cursor = 0;
+        loop {
+            let (next_cursor, keys) = storage.scan(cursor, 7);
+            assert!(keys.iter().filter(|key| key.starts_with("key:")).count() <= 7);
+            seen.extend(keys);
+            // keys added part way through don't throw the scan off
+            storage.write("late").insert(
+                format!("late:{}", cursor),
+                StorageEntry::string(vec![], None),
+            );
+            if next_cursor == 0 {
+                break;
+            }
+            cursor = next_cursor;
+        }
+        let mut seen: Vec<String> = seen
+            .into_iter()
+            .filter(|key| key.starts_with("key:"))
+            .collect();
+        seen.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+
+        assert_eq!(scan_range([5, 1, 3].into_iter(), 0, 2), (3, 4));
+        assert_eq!(scan_range([5, 1, 3].into_iter(), 4, 2), (u64::MAX, 0));
+        assert_eq!(scan_range([3, 3, 3].into_iter(), 0, 1), (3, 4));
+    }
+
+    #[test]
+    fn test_random_key() {
+        let storage = ShardedStorage::default();
+        assert_eq!(storage.random_key(), None);
+        let expired = Some(unix_millis_now() - 1000);
+        for i in 0..10 {
+            let key = format!("gone:{}", i);
+            storage
+                .write(&key)
+                .insert(key.clone(), StorageEntry::string(vec![], expired));
+        }
+        assert_eq!(storage.random_key(), None);
+        // the expired keys were dropped finding that out
+        assert_eq!(
+            storage
+                .read_all()
+                .iter()
+                .map(|shard| shard.len())
+                .sum::<usize>(),
+            0
+        );
+
+        for key in ["a", "b", "c"] {
+            storage
+                .write(key)
+                .insert(key.to_string(), StorageEntry::string(vec![], None));
+        }
+        let picked: HashSet<String> = (0..100).filter_map(|_| storage.random_key()).collect();
+        assert_eq!(picked.len(), 3);
+    }
+
+    #[test]
+    fn test_idle_time() {
+        let storage = ShardedStorage::default();
+        storage
+            .write("k")
+            .insert("k".to_string(), StorageEntry::string(vec![], None));
+        std::thread::sleep(Duration::from_millis(20));
+        let idle =
+            |storage: &ShardedStorage| peek_live(&storage.read("k"), "k").unwrap().idle_time();
+        // looking doesn't count as a use, reading and writing do
+        assert!(idle(&storage) >= Duration::from_millis(20));
+        assert!(idle(&storage) >= Duration::from_millis(20));
+        get_live(&storage.read("k"), "k");
+        assert!(idle(&storage) < Duration::from_millis(20));
+
+        std::thread::sleep(Duration::from_millis(20));
+        drop(storage.write("k"));
+        assert!(idle(&storage) < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_evict_expired_sample() {
+        let sharded = ShardedStorage::default();
+        let mut storage = sharded.write_shard(0);
+        let expired = Some(unix_millis_now() - 1000);
+        for i in 0..10 {
+            let expiry = if i % 2 == 0 { expired } else { None };
+            storage.track(&i.to_string());
+            storage.insert(i.to_string(), StorageEntry::string(vec![], expiry));
+        }
+        drop(storage);
+        let size = entry_usage("0", &StorageEntry::string(vec![], None));
+        assert_eq!(sharded.used_memory(), 10 * size);
+
+        let mut cursor = 0;
+        let mut evicted = 0;
+        let mut storage = sharded.write_shard(0);
+        while storage.len() > 5 {
+            let (checked, dropped) = evict_expired_sample(&mut storage, &mut cursor, 3);
+            assert!(checked <= 3);
+            evicted += dropped.len();
+        }
+        assert_eq!(evicted, 5);
+        assert!(storage
+            .values()
+            .all(|entry| entry.expire_timestamp.is_none()));
+        drop(storage);
+        assert_eq!(sharded.used_memory(), 5 * size);
+    }
+
+    #[test]
+    fn test_resolve_index() {
+        assert_eq!(resolve_index(0, 3), Some(0));
+        assert_eq!(resolve_index(-1, 3), Some(2));
+        assert_eq!(resolve_index(-3, 3), Some(0));
+        assert_eq!(resolve_index(-4, 3), None);
+        assert_eq!(resolve_index(3, 3), None);
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        assert_eq!(resolve_range(0, -1, 5), Some((0, 4)));
+        assert_eq!(resolve_range(-3, -1, 5), Some((2, 4)));
+        assert_eq!(resolve_range(-100, 100, 5), Some((0, 4)));
+        assert_eq!(resolve_range(1, 2, 5), Some((1, 2)));
+        assert_eq!(resolve_range(3, 1, 5), None);
+        assert_eq!(resolve_range(5, 10, 5), None);
+        assert_eq!(resolve_range(0, -6, 5), None);
+        assert_eq!(resolve_range(0, -1, 0), None);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+};
+
+/// A score as sorted sets order them. NaN never gets in, so the total order
+/// agrees with the usual one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members with a score each, kept ordered by score and then by member, the
+/// way every ranged command reads them. Scores are also indexed by member so
+/// looking one up doesn't take a walk.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    order: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets `member`'s score, returning the one it had before. `score` must
+    /// not be NaN.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        // -0 and 0 compare equal, so only one of them is ever stored
+        let score = score + 0.0;
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.order.remove(&(Score(previous), member.clone()));
+        }
+        self.order.insert((Score(score), member));
+        previous
+    }
+
+    /// How many members come before `member`, in the order `iter` gives.
+    /// The tree doesn't keep subtree sizes, so this walks up to that point.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.order.range(..(Score(score), member.to_vec())).count())
+    }
+
+    /// Every member and its score, lowest score first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u8], f64)> + Clone {
+        self.order
+            .iter()
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut zset = SortedSet::default();
+        assert_eq!(zset.insert(b"b".to_vec(), 2.0), None);
+        assert_eq!(zset.insert(b"a".to_vec(), 2.0), None);
+        assert_eq!(zset.insert(b"c".to_vec(), -1.0), None);
+        assert_eq!(zset.insert(b"c".to_vec(), 3.0), Some(-1.0));
+        assert_eq!(zset.len(), 3);
+        assert_eq!(zset.score(b"c"), Some(3.0));
+        assert_eq!(zset.score(b"d"), None);
+
+        // ties go by member
+        let members: Vec<&[u8]> = zset.iter().map(|(member, _score)| member).collect();
+        assert_eq!(members, [b"a", b"b", b"c"]);
+
+        zset.insert(b"z".to_vec(), -0.0);
+        zset.insert(b"y".to_vec(), 0.0);
+        let first: Vec<&[u8]> = zset.iter().take(2).map(|(member, _score)| member).collect();
+        assert_eq!(first, [b"y", b"z"]);
+    }
+
+    #[test]
+    fn test_rank() {
+        let mut zset = SortedSet::default();
+        zset.insert(b"b".to_vec(), 1.0);
+        zset.insert(b"a".to_vec(), 1.0);
+        zset.insert(b"c".to_vec(), 0.5);
+        assert_eq!(zset.rank(b"c"), Some(0));
+        assert_eq!(zset.rank(b"a"), Some(1));
+        assert_eq!(zset.rank(b"b"), Some(2));
+        assert_eq!(zset.rank(b"d"), None);
+        zset.insert(b"c".to_vec(), 2.0);
+        assert_eq!(zset.rank(b"c"), Some(2));
+    }
+}